@@ -1,8 +1,12 @@
 use rocket::{State, response::content::Html};
 use rocket::Route;
+use rocket::http::Status;
+use rocket::serde::json::Json;
 use std::path::PathBuf;
-use crate::auth::jwt::JWT;
+use crate::auth::{AdminUser, UserID};
 use crate::database::{SharedDatabase, UserRoll};
+use crate::users::{self, UserError};
+use rocket::response::status;
 use crate::database::DBUser;
 use std::fmt::Write as FmtWrite;
 use log::{info, warn};
@@ -10,43 +14,63 @@ use log::{info, warn};
 pub fn mount_admin() -> Vec<Route> {
     routes![
         get_admin_root,
+        get_active_sessions,
+        delete_active_session,
         get_image_cache,
-        cleanup_image_cache
+        cleanup_image_cache,
+        get_users,
+        create_user,
+        update_user,
+        reset_user_password,
+        set_user_quota,
+        delete_user
     ]
 }
 
 #[derive(Serialize)]
 struct Session {
-    token: String,
-    last_conn: std::time::SystemTime,
+    session_id: String,
     user_id: String,
+    created_at: String,
+    last_conn: String,
+    expires_at: String,
 }
 
-// TODO secure admin routes
-/*
 #[get("/admin/active_sessions")]
-fn get_active_sessions() -> Json<Vec<Session>> {
-    let mut res = Vec::new();
-
-    for (token, data) in token_storage().inner().iter() {
-        res.push(Session {
-            token: String::from_utf8_lossy(token).to_string(),
-            last_conn: data.0,
-            user_id: (data.1).0.clone(),
-        })
-    }
+fn get_active_sessions(db: &State<SharedDatabase>, _admin: AdminUser) -> Result<Json<Vec<Session>>, Status> {
+    let sessions = db.get_active_sessions().map_err(|e| {
+        warn!("Failed to load sessions: {:?}", e);
+        Status::InternalServerError
+    })?;
 
-    Json(res)
+    Ok(Json(sessions.into_iter().map(|s| Session {
+        session_id: s.id,
+        user_id: s.user.0,
+        created_at: s.created_at,
+        last_conn: s.last_seen,
+        expires_at: s.expires_at,
+    }).collect()))
 }
-*/
 
-#[get("/admin")]
-fn get_admin_root(db: &State<SharedDatabase>, jwt: JWT) -> std::io::Result<Html<String>> {
-    
-    if jwt.user_roll != UserRoll::Admin {
-        return Err(std::io::ErrorKind::PermissionDenied.into());
+/// Revokes a single session, the token of it can't be used anymore
+#[delete("/admin/active_sessions?<session_id>")]
+fn delete_active_session(db: &State<SharedDatabase>, admin: AdminUser, session_id: &str) -> Status {
+    match db.delete_session(session_id) {
+        Ok(()) => {
+            info!("Admin {} revoked session {}", admin.0, session_id);
+            Status::Ok
+        }
+        Err(e) => {
+            warn!("Failed to delete session: {:?}", e);
+            Status::InternalServerError
+        }
     }
+}
 
+const MIB: f64 = 1024.0 * 1024.0;
+
+#[get("/admin")]
+fn get_admin_root(db: &State<SharedDatabase>, _admin: AdminUser) -> std::io::Result<Html<String>> {
     let mut body = std::fs::read_to_string("./pages/admin.html")?;
     // generate user tr entries
     let users: Vec<DBUser> = db.get_all_users().map_err(|_| std::io::Error::from(std::io::ErrorKind::Other))?;
@@ -54,11 +78,20 @@ fn get_admin_root(db: &State<SharedDatabase>, jwt: JWT) -> std::io::Result<Html<
         writeln!(&mut res, r#"
             <tr>
                 <td>{}</td>
+                <td>{:?}{}</td>
+                <td>{:.1} / {} MiB</td>
                 <td class="pw-change">
-                    <input type="text"></input>
-                    <button onclick="changePassword('{}')">Change</button>
+                    <input type="password"></input>
+                    <button onclick="changePassword('{}', this)">Change</button>
                 </td>
-            </tr>"#, user.name, user.id.0).unwrap();
+            </tr>"#,
+            html_escape(&user.name),
+            user.roll,
+            if user.disabled { " (disabled)" } else { "" },
+            user.used_bytes.unwrap_or(0) as f64 / MIB,
+            user.quota_bytes.map(|q| format!("{:.1}", q as f64 / MIB)).unwrap_or_else(|| "unlimited".into()),
+            user.id.0
+        ).unwrap();
         res
     });
 
@@ -67,10 +100,162 @@ fn get_admin_root(db: &State<SharedDatabase>, jwt: JWT) -> std::io::Result<Html<
     Ok(Html(body))
 }
 
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&#39;")
+}
+
+#[derive(Serialize)]
+struct UserInfo {
+    id: String,
+    name: String,
+    roll: UserRoll,
+    disabled: bool,
+    #[serde(rename = "usedBytes")]
+    used_bytes: u64,
+    /// null is unlimited
+    #[serde(rename = "quotaBytes")]
+    quota_bytes: Option<u64>,
+}
+
+fn user_error_status(e: UserError) -> status::Custom<String> {
+    let status = match e {
+        UserError::NotFound => Status::NotFound,
+        UserError::NameTaken => Status::Conflict,
+        UserError::InvalidName => Status::BadRequest,
+        UserError::Database(_) | UserError::Hash(_) => {
+            warn!("User management failed: {}", e);
+            return status::Custom(Status::InternalServerError, String::new());
+        }
+    };
+    status::Custom(status, e.to_string())
+}
+
+#[get("/admin/users")]
+fn get_users(db: &State<SharedDatabase>, _admin: AdminUser) -> Result<Json<Vec<UserInfo>>, Status> {
+    let users = db.get_all_users().map_err(|e| {
+        warn!("Failed to load users: {:?}", e);
+        Status::InternalServerError
+    })?;
+    Ok(Json(users.into_iter().map(|u| UserInfo {
+        id: u.id.0,
+        name: u.name,
+        roll: u.roll,
+        disabled: u.disabled,
+        used_bytes: u.used_bytes.unwrap_or(0),
+        quota_bytes: u.quota_bytes,
+    }).collect()))
+}
+
+#[derive(Deserialize)]
+struct NewUser {
+    name: String,
+    #[serde(rename = "passwordBase64")]
+    password_base64: String,
+    /// defaults to User
+    roll: Option<UserRoll>,
+}
+
+#[derive(Serialize)]
+struct CreatedUser {
+    id: String,
+}
+
+#[post("/admin/users", data = "<user>")]
+fn create_user(
+    db: &State<SharedDatabase>,
+    _admin: AdminUser,
+    user: Json<NewUser>,
+) -> Result<Json<CreatedUser>, status::Custom<String>> {
+    let id = users::create_user(db, &user.name, &user.password_base64, user.roll.unwrap_or(UserRoll::User))
+        .map_err(user_error_status)?;
+    Ok(Json(CreatedUser { id: id.0 }))
+}
+
+#[derive(Deserialize)]
+struct UserChanges {
+    name: Option<String>,
+    roll: Option<UserRoll>,
+    disabled: Option<bool>,
+}
+
+/// Changes the fields that are set, admins can't change their own roll or disable themselves
+#[patch("/admin/users?<id>", data = "<changes>")]
+fn update_user(
+    db: &State<SharedDatabase>,
+    admin: AdminUser,
+    id: &str,
+    changes: Json<UserChanges>,
+) -> Result<Status, status::Custom<String>> {
+    let user_id = UserID(id.into());
+    if user_id == admin.0 && (changes.roll.is_some() || changes.disabled == Some(true)) {
+        return Err(status::Custom(Status::Conflict, "Can't change your own roll or disable yourself".into()));
+    }
+    if let Some(name) = &changes.name {
+        users::rename_user(db, &user_id, name).map_err(user_error_status)?;
+    }
+    if let Some(roll) = changes.roll {
+        users::set_roll(db, &user_id, roll).map_err(user_error_status)?;
+    }
+    if let Some(disabled) = changes.disabled {
+        users::set_disabled(db, &user_id, disabled).map_err(user_error_status)?;
+    }
+    Ok(Status::NoContent)
+}
+
+#[derive(Deserialize)]
+struct NewPassword {
+    #[serde(rename = "passwordBase64")]
+    password_base64: String,
+}
+
+/// Sets a new password and revokes all sessions of the user
+#[put("/admin/users/password?<id>", data = "<password>")]
+fn reset_user_password(
+    db: &State<SharedDatabase>,
+    _admin: AdminUser,
+    id: &str,
+    password: Json<NewPassword>,
+) -> Result<Status, status::Custom<String>> {
+    if password.password_base64.is_empty() {
+        return Err(status::Custom(Status::BadRequest, "Password can't be empty".into()));
+    }
+    users::reset_password(db, &UserID(id.into()), &password.password_base64).map_err(user_error_status)?;
+    Ok(Status::NoContent)
+}
+
+#[derive(Deserialize)]
+struct Quota {
+    /// null removes the quota
+    #[serde(rename = "quotaBytes")]
+    quota_bytes: Option<u64>,
+}
+
+#[put("/admin/users/quota?<id>", data = "<quota>")]
+fn set_user_quota(
+    db: &State<SharedDatabase>,
+    _admin: AdminUser,
+    id: &str,
+    quota: Json<Quota>,
+) -> Result<Status, status::Custom<String>> {
+    users::set_quota(db, &UserID(id.into()), quota.quota_bytes).map_err(user_error_status)?;
+    Ok(Status::NoContent)
+}
+
+/// Deletes the user with all files, admins can't delete themselves
+#[delete("/admin/users?<id>")]
+fn delete_user(db: &State<SharedDatabase>, admin: AdminUser, id: &str) -> Result<Status, status::Custom<String>> {
+    let user_id = UserID(id.into());
+    if user_id == admin.0 {
+        return Err(status::Custom(Status::Conflict, "Can't delete yourself".into()));
+    }
+    users::delete_user(db, &user_id).map_err(user_error_status)?;
+    Ok(Status::NoContent)
+}
+
 use crate::fs::previews;
 
 #[get("/admin/image_cache")]
-fn get_image_cache() -> Option<Html<String>> {
+fn get_image_cache(_admin: AdminUser) -> Option<Html<String>> {
     let mut total_size = 0;
     let mut total_count = 0;
     // [(count, size)]
@@ -119,7 +304,7 @@ fn get_image_cache() -> Option<Html<String>> {
 
 /// max_size in mb
 #[get("/admin/image_cache/cleanup?<max_size>")]
-fn cleanup_image_cache(max_size: Option<u64>) -> Option<Html<String>> {
+fn cleanup_image_cache(max_size: Option<u64>, _admin: AdminUser) -> Option<Html<String>> {
     if max_size.is_none() {
         warn!("max_size not specified, keeping max. 100mb");
     }
@@ -4,20 +4,198 @@ pub fn mount_api() -> Vec<Route> {
     routes![
         crate::auth::login,
         crate::auth::logout,
+        crate::auth::logout_all,
+        crate::auth::account::get_settings,
+        crate::auth::account::update_settings,
+        crate::auth::account::change_password,
+        crate::auth::account::upload_avatar,
+        crate::auth::account::delete_avatar,
+        crate::auth::account::get_avatar,
+        crate::fs::quota::get_usage,
         crate::fs::get_node_data_shared,
         crate::fs::get_node_data,
+        crate::fs::delete_node_data_shared,
         crate::fs::delete_node_data,
+        crate::fs::node_ops::move_node,
+        crate::fs::node_ops::copy_node,
+        crate::fs::search::search_shared,
+        crate::fs::search::search_files,
+        crate::fs::trash::get_trash,
+        crate::fs::trash::restore_trash_item,
+        crate::fs::trash::purge_trash,
+        crate::fs::versions::get_versions,
+        crate::fs::versions::download_version,
+        crate::fs::versions::restore_version,
+        crate::fs::versions::prune_file_versions,
         crate::fs::download::download_file,
         crate::fs::download::download_shared_file,
         crate::fs::previews::preview_image,
         crate::fs::previews::preview_image_shared,
         crate::fs::shared::update_folder_share,
         crate::fs::shared::get_my_shared,
+        crate::fs::shared::set_share_password,
+        crate::fs::shared::unlock_share,
+        crate::fs::shared::get_share_stats,
         crate::fs::upload::post_upload,
         crate::fs::upload::post_upload_shared,
         crate::fs::upload::post_create_folder,
+        crate::fs::user_shares::create_user_share,
+        crate::fs::user_shares::get_outgoing_user_shares,
+        crate::fs::user_shares::get_incoming_user_shares,
+        crate::fs::user_shares::delete_user_share,
+        crate::fs::tus::tus_options,
+        crate::fs::tus::tus_create,
+        crate::fs::tus::tus_head,
+        crate::fs::tus::tus_patch,
+        crate::fs::tus::tus_delete,
         crate::icons::icons_get,
         crate::auth::my_user,
         crate::auth::my_user_not_loggedin,
     ]
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{jwt, UserID};
+    use crate::database::{SharedDatabase, UserRoll};
+    use rocket::http::{Header, Method, Status};
+    use rocket::local::blocking::Client;
+
+    /// Routes anyone can use, shared routes are protected by the share itself
+    const PUBLIC_ROUTES: &[&str] = &[
+        "login",
+        "get_node_data_shared",
+        "delete_node_data_shared",
+        "search_shared",
+        "download_shared_file",
+        "preview_image_shared",
+        "post_upload_shared",
+        "unlock_share",
+        "tus_options",
+        "icons_get",
+        "my_user_not_loggedin",
+    ];
+
+    /// Lowest roll allowed to use each route and a request that reaches its guards
+    const ROUTE_ROLLS: &[(&str, Method, &str, UserRoll)] = &[
+        ("logout", Method::Get, "/api/user/logout", UserRoll::Guest),
+        ("logout_all", Method::Get, "/api/user/logout/all", UserRoll::Guest),
+        ("my_user", Method::Get, "/api/user", UserRoll::Guest),
+        ("get_settings", Method::Get, "/api/user/settings", UserRoll::Guest),
+        ("update_settings", Method::Patch, "/api/user/settings", UserRoll::User),
+        ("change_password", Method::Put, "/api/user/password", UserRoll::Guest),
+        ("upload_avatar", Method::Post, "/api/user/avatar", UserRoll::User),
+        ("delete_avatar", Method::Delete, "/api/user/avatar", UserRoll::User),
+        ("get_avatar", Method::Get, "/api/user/avatar?id=x", UserRoll::Guest),
+        ("get_usage", Method::Get, "/api/user/usage", UserRoll::Guest),
+        ("get_node_data", Method::Get, "/api/node?file_path=", UserRoll::Guest),
+        ("delete_node_data", Method::Delete, "/api/node?path=to_delete", UserRoll::User),
+        ("move_node", Method::Post, "/api/node/move?path=a&destination=b", UserRoll::User),
+        ("copy_node", Method::Post, "/api/node/copy?path=a&destination=b", UserRoll::User),
+        ("search_files", Method::Get, "/api/search?path=&q=a", UserRoll::Guest),
+        ("get_trash", Method::Get, "/api/trash", UserRoll::Guest),
+        ("restore_trash_item", Method::Post, "/api/trash/restore?id=x", UserRoll::User),
+        ("purge_trash", Method::Delete, "/api/trash?id=x", UserRoll::User),
+        ("get_versions", Method::Get, "/api/versions?path=a", UserRoll::Guest),
+        ("download_version", Method::Get, "/api/versions/download?id=x", UserRoll::Guest),
+        ("restore_version", Method::Post, "/api/versions/restore?id=x", UserRoll::User),
+        ("prune_file_versions", Method::Delete, "/api/versions?path=a&keep=1", UserRoll::User),
+        ("download_file", Method::Get, "/api/download/file?path=a", UserRoll::Guest),
+        ("preview_image", Method::Get, "/api/preview/file?path=a.png", UserRoll::Guest),
+        ("update_folder_share", Method::Patch, "/api/folder/shared?path=a&enabled=false", UserRoll::User),
+        ("get_my_shared", Method::Get, "/api/shared", UserRoll::Guest),
+        ("set_share_password", Method::Put, "/api/folder/shared/password?shared_id=x", UserRoll::User),
+        ("get_share_stats", Method::Get, "/api/shared/stats?shared_id=x", UserRoll::Guest),
+        ("post_upload", Method::Post, "/api/upload?file_path=upload.txt", UserRoll::User),
+        ("post_create_folder", Method::Post, "/api/create_folder?folder_path=new_folder", UserRoll::User),
+        ("create_user_share", Method::Post, "/api/user_shares?path=a&user=nobody&access=read", UserRoll::User),
+        ("get_outgoing_user_shares", Method::Get, "/api/user_shares", UserRoll::Guest),
+        ("get_incoming_user_shares", Method::Get, "/api/user_shares/incoming", UserRoll::Guest),
+        ("delete_user_share", Method::Delete, "/api/user_shares?id=x", UserRoll::User),
+        ("tus_create", Method::Post, "/api/tus", UserRoll::User),
+        ("tus_head", Method::Head, "/api/tus/x", UserRoll::Guest),
+        ("tus_patch", Method::Patch, "/api/tus/x", UserRoll::User),
+        ("tus_delete", Method::Delete, "/api/tus/x", UserRoll::User),
+        ("get_admin_root", Method::Get, "/api/admin", UserRoll::Admin),
+        ("get_active_sessions", Method::Get, "/api/admin/active_sessions", UserRoll::Admin),
+        ("delete_active_session", Method::Delete, "/api/admin/active_sessions?session_id=x", UserRoll::Admin),
+        ("get_image_cache", Method::Get, "/api/admin/image_cache", UserRoll::Admin),
+        ("cleanup_image_cache", Method::Get, "/api/admin/image_cache/cleanup?max_size=100000", UserRoll::Admin),
+        ("get_users", Method::Get, "/api/admin/users", UserRoll::Admin),
+        ("create_user", Method::Post, "/api/admin/users", UserRoll::Admin),
+        ("update_user", Method::Patch, "/api/admin/users?id=x", UserRoll::Admin),
+        ("reset_user_password", Method::Put, "/api/admin/users/password?id=x", UserRoll::Admin),
+        ("set_user_quota", Method::Put, "/api/admin/users/quota?id=x", UserRoll::Admin),
+        ("delete_user", Method::Delete, "/api/admin/users?id=x", UserRoll::Admin),
+    ];
+
+    fn login(db: &SharedDatabase, id: &str, roll: UserRoll) -> String {
+        let user_id = UserID(id.into());
+        let expires_at = jwt::JWT::expires_at();
+        let session_id = db.create_session(&user_id, expires_at).unwrap();
+        // exists after the first request
+        let _ = db.create_user(&user_id, id, "hash", roll);
+        std::fs::create_dir_all(crate::config::data_path().join(id)).unwrap();
+        std::fs::write(crate::config::data_path().join(id).join("to_delete"), b"x").unwrap();
+        jwt::to_jwt(
+            jwt::JWT {
+                profile_picture_url: None,
+                user_name: id.into(),
+                user_id,
+                user_roll: roll,
+                session_id: Some(session_id),
+                expires_at: None,
+            },
+            expires_at,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_route_rolls() {
+        let dir = crate::config::init_for_tests();
+
+        let mut routes = mount_api();
+        routes.extend(crate::admin::mount_admin());
+        for route in &routes {
+            let name = route.name.as_deref().unwrap_or_default();
+            assert!(
+                PUBLIC_ROUTES.contains(&name) || ROUTE_ROLLS.iter().any(|(n, ..)| *n == name),
+                "route {} has no required roll",
+                name
+            );
+        }
+
+        let db = SharedDatabase::new(&dir.join("rolls-test.sqlite"));
+        let rocket = rocket::build()
+            .manage(db)
+            .manage(crate::icons::IconsCache::empty())
+            .mount("/api/", routes);
+        let client = Client::tracked(rocket).unwrap();
+        let db = client.rocket().state::<SharedDatabase>().unwrap();
+
+        for (name, method, uri, required) in ROUTE_ROLLS {
+            for (id, roll) in [("guest001", UserRoll::Guest), ("user0001", UserRoll::User), ("admin001", UserRoll::Admin)] {
+                // new session for every request, the logout routes revoke them
+                let token = login(db, id, roll);
+                let status = client
+                    .req(*method, *uri)
+                    .header(Header::new("Authorization", format!("Bearer {}", token)))
+                    .dispatch()
+                    .status();
+                if roll < *required {
+                    assert_eq!(status, Status::Forbidden, "{} as {:?}", name, roll);
+                } else {
+                    assert_ne!(status, Status::Forbidden, "{} as {:?}", name, roll);
+                    assert_ne!(status, Status::Unauthorized, "{} as {:?}", name, roll);
+                }
+            }
+        }
+
+        let _ = std::fs::remove_file(dir.join("rolls-test.sqlite"));
+        for id in ["guest001", "user0001", "admin001"] {
+            let _ = std::fs::remove_dir_all(crate::config::data_path().join(id));
+        }
+    }
+}
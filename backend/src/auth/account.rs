@@ -0,0 +1,318 @@
+use super::jwt::JWT;
+use super::{hash_password, user_token, verify_password, PasswordCheck, UserID, WritableUser};
+use crate::database::{DBUser, GetUserQuery, SharedDatabase};
+use image::GenericImageView;
+use log::{error, info, warn};
+use rocket::data::{Data, ToByteUnit};
+use rocket::fs::NamedFile;
+use rocket::http::Status;
+use rocket::response::status;
+use rocket::serde::json::Json;
+use rocket::State;
+use std::convert::TryFrom;
+use std::path::PathBuf;
+
+const AVATAR_MAX_SIZE_MB: usize = 10;
+/// Avatars get scaled down to fit into this
+const AVATAR_RES: u32 = 1024;
+const AVATAR_THUMBNAIL_RES: u32 = 128;
+/// Larger images are rejected before decoding, a small file can decode to a huge bitmap
+const AVATAR_MAX_PIXELS: u64 = 25_000_000;
+/// Length of the serialized display settings
+const SETTINGS_MAX_LEN: usize = 4096;
+
+/// Avatars are inside the data path but outside of the users root, like versions
+fn avatar_dir(user_id: &UserID) -> PathBuf {
+    let mut dir = PathBuf::from(crate::config::data_path());
+    dir.push(".avatars");
+    dir.push(&user_id.0);
+    dir
+}
+
+fn avatar_path(user_id: &UserID, thumbnail: bool) -> PathBuf {
+    let mut path = avatar_dir(user_id);
+    path.push(if thumbnail { "thumbnail.png" } else { "avatar.png" });
+    path
+}
+
+/// Url of the avatar thumbnail, None if the user has no avatar.
+/// Changes with every upload, so it can be cached
+pub fn avatar_url(user_id: &UserID) -> Option<String> {
+    let modified = std::fs::metadata(avatar_path(user_id, true))
+        .and_then(|m| m.modified())
+        .ok()?
+        .duration_since(std::time::UNIX_EPOCH)
+        .ok()?;
+    Some(format!(
+        "/api/user/avatar?id={}&thumbnail=true&v={}",
+        user_id.0,
+        modified.as_secs()
+    ))
+}
+
+/// Removes the avatar, Ok if there is none
+pub fn remove_avatar(user_id: &UserID) -> std::io::Result<()> {
+    match std::fs::remove_dir_all(avatar_dir(user_id)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Serialize)]
+pub struct RefreshedToken {
+    /// replaces the token used for the request, contains the changed name / avatar
+    token: String,
+}
+
+type AccountResult = Result<Json<RefreshedToken>, status::Custom<&'static str>>;
+
+fn load_user(db: &SharedDatabase, user_id: &UserID) -> Result<DBUser, status::Custom<&'static str>> {
+    db.get_user(GetUserQuery::ByID(user_id)).map_err(|e| {
+        error!("Failed to load user {}: {:?}", user_id, e);
+        status::Custom(Status::InternalServerError, "Failed to load user")
+    })
+}
+
+fn refreshed_token(db: &SharedDatabase, jwt: &JWT) -> AccountResult {
+    let user = load_user(db, &jwt.user_id)?;
+    let session_id = jwt.session_id.clone().unwrap_or_default();
+    // same expiry as the replaced token, which is the one of its session
+    let token = user_token(&user, session_id, jwt.expires_at.unwrap_or_else(JWT::expires_at))
+        .map_err(|e| status::Custom(Status::InternalServerError, e))?;
+    Ok(Json(RefreshedToken { token }))
+}
+
+#[derive(Serialize)]
+pub struct AccountSettings {
+    name: String,
+    #[serde(rename = "displayName")]
+    display_name: Option<String>,
+    /// json object stored for the client
+    settings: serde_json::Value,
+    /// url of the avatar thumbnail, needs the token as query parameter
+    avatar: Option<String>,
+}
+
+#[get("/user/settings")]
+pub fn get_settings(user_id: UserID, db: &State<SharedDatabase>) -> Result<Json<AccountSettings>, status::Custom<&'static str>> {
+    let user = load_user(db, &user_id)?;
+    let settings = user
+        .settings
+        .as_deref()
+        .and_then(|s| serde_json::from_str(s).ok())
+        .unwrap_or(serde_json::Value::Null);
+    Ok(Json(AccountSettings {
+        avatar: avatar_url(&user.id),
+        name: user.name,
+        display_name: user.display_name,
+        settings,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct SettingsUpdate {
+    /// empty to use the name again
+    #[serde(rename = "displayName")]
+    display_name: Option<String>,
+    /// replaces the stored settings, needs to be an object
+    settings: Option<serde_json::Value>,
+}
+
+/// Changes the fields that are set, guests are read-only
+#[patch("/user/settings", data = "<update>")]
+pub fn update_settings(
+    _writable: WritableUser,
+    jwt: JWT,
+    update: Json<SettingsUpdate>,
+    db: &State<SharedDatabase>,
+) -> AccountResult {
+    let user = load_user(db, &jwt.user_id)?;
+
+    let display_name = match update.display_name.as_deref().map(str::trim) {
+        Some("") => None,
+        Some(name) if name.chars().count() > 64 || name.chars().any(char::is_control) => {
+            return Err(status::Custom(Status::BadRequest, "Display names can have 64 characters and no control characters"))
+        }
+        Some(name) => Some(name.to_owned()),
+        None => user.display_name,
+    };
+    let settings = match &update.settings {
+        Some(settings @ serde_json::Value::Object(_)) => {
+            let json = settings.to_string();
+            if json.len() > SETTINGS_MAX_LEN {
+                return Err(status::Custom(Status::PayloadTooLarge, "Settings are too large"));
+            }
+            Some(json)
+        }
+        Some(serde_json::Value::Null) => None,
+        Some(_) => return Err(status::Custom(Status::BadRequest, "Settings need to be an object")),
+        None => user.settings,
+    };
+
+    if let Err(e) = db.update_user_settings(&jwt.user_id, display_name.as_deref(), settings.as_deref()) {
+        error!("Failed to update settings of {}: {:?}", jwt.user_id, e);
+        return Err(status::Custom(Status::InternalServerError, "Failed to update settings"));
+    }
+    refreshed_token(db, &jwt)
+}
+
+#[derive(Deserialize)]
+pub struct PasswordChange {
+    #[serde(rename = "oldPasswordBase64")]
+    old_password_base64: String,
+    #[serde(rename = "newPasswordBase64")]
+    new_password_base64: String,
+}
+
+/// Needs the current password, all other sessions of the user get revoked.
+/// Allowed for guests as well, they have to be able to secure their account
+#[put("/user/password", data = "<change>")]
+pub fn change_password(jwt: JWT, change: Json<PasswordChange>, db: &State<SharedDatabase>) -> AccountResult {
+    let user = load_user(db, &jwt.user_id)?;
+    if verify_password(&user.hashed_pw, &change.old_password_base64) == PasswordCheck::Invalid {
+        info!("Wrong password for password change of {}", user.id);
+        return Err(status::Custom(Status::Forbidden, "Wrong password"));
+    }
+    if change.new_password_base64.is_empty() {
+        return Err(status::Custom(Status::BadRequest, "Password can't be empty"));
+    }
+
+    let stored = hash_password(&change.new_password_base64)
+        .map_err(|e| format!("{:?}", e))
+        .and_then(|hash| db.update_password_hash(&user.id, &hash).map_err(|e| format!("{:?}", e)));
+    if let Err(e) = stored {
+        error!("Failed to change password of {}: {}", user.id, e);
+        return Err(status::Custom(Status::InternalServerError, "Failed to change password"));
+    }
+    if let Some(session_id) = &jwt.session_id {
+        match db.delete_other_sessions(&user.id, session_id) {
+            Ok(n) => info!("{} changed the password, revoked {} other sessions", user.id, n),
+            Err(e) => error!("Failed to revoke sessions of {}: {:?}", user.id, e),
+        }
+    }
+    refreshed_token(db, &jwt)
+}
+
+/// Body is the image, it gets stored as png scaled down to `AVATAR_RES` with a thumbnail. Guests are read-only
+#[post("/user/avatar", data = "<data>")]
+pub async fn upload_avatar(
+    _writable: WritableUser,
+    jwt: JWT,
+    data: Data<'_>,
+    db: &State<SharedDatabase>,
+) -> AccountResult {
+    let bytes = match data.open(AVATAR_MAX_SIZE_MB.mebibytes()).into_bytes().await {
+        Ok(bytes) if bytes.is_complete() => bytes.into_inner(),
+        Ok(_) => return Err(status::Custom(Status::PayloadTooLarge, "Avatars can have 10mb")),
+        Err(e) => {
+            warn!("Failed to read avatar of {}: {:?}", jwt.user_id, e);
+            return Err(status::Custom(Status::BadRequest, "Failed to read avatar"));
+        }
+    };
+    let user_id = jwt.user_id.clone();
+    let stored = rocket::tokio::task::spawn_blocking(move || store_avatar(&user_id, &bytes)).await;
+    match stored {
+        Ok(Ok(())) => {}
+        Ok(Err(AvatarError::NotAnImage)) => {
+            return Err(status::Custom(Status::UnsupportedMediaType, "Avatar needs to be an image"))
+        }
+        Ok(Err(AvatarError::TooLarge)) => return Err(status::Custom(Status::PayloadTooLarge, "Avatar is too large")),
+        Ok(Err(AvatarError::Failed(e))) => {
+            error!("Failed to store avatar of {}: {}", jwt.user_id, e);
+            return Err(status::Custom(Status::InternalServerError, "Failed to store avatar"));
+        }
+        Err(e) => {
+            error!("Avatar task failed: {:?}", e);
+            return Err(status::Custom(Status::InternalServerError, "Failed to store avatar"));
+        }
+    }
+    info!("{} uploaded a new avatar", jwt.user_id);
+    refreshed_token(db, &jwt)
+}
+
+#[derive(Debug, PartialEq)]
+enum AvatarError {
+    NotAnImage,
+    /// more than `AVATAR_MAX_PIXELS`
+    TooLarge,
+    Failed(String),
+}
+
+/// Dimensions from the image header, without decoding it
+fn avatar_dimensions(bytes: &[u8]) -> Result<(u32, u32), AvatarError> {
+    let (width, height) = image::io::Reader::new(std::io::Cursor::new(bytes))
+        .with_guessed_format()
+        .map_err(|_| AvatarError::NotAnImage)?
+        .into_dimensions()
+        .map_err(|_| AvatarError::NotAnImage)?;
+    if width as u64 * height as u64 > AVATAR_MAX_PIXELS {
+        return Err(AvatarError::TooLarge);
+    }
+    Ok((width, height))
+}
+
+/// Decodes, scales and stores the avatar with its thumbnail, blocks while doing that
+fn store_avatar(user_id: &UserID, bytes: &[u8]) -> Result<(), AvatarError> {
+    avatar_dimensions(bytes)?;
+    let image = image::load_from_memory(bytes).map_err(|_| AvatarError::NotAnImage)?;
+
+    std::fs::create_dir_all(avatar_dir(user_id)).map_err(|e| AvatarError::Failed(format!("{:?}", e)))?;
+    let (width, height) = image.dimensions();
+    let avatar = if width > AVATAR_RES || height > AVATAR_RES {
+        image.resize(AVATAR_RES, AVATAR_RES, image::imageops::FilterType::Triangle)
+    } else {
+        image
+    };
+    avatar
+        .save(avatar_path(user_id, false))
+        .map_err(|e| AvatarError::Failed(format!("{:?}", e)))?;
+    avatar
+        .thumbnail(AVATAR_THUMBNAIL_RES, AVATAR_THUMBNAIL_RES)
+        .save(avatar_path(user_id, true))
+        .map_err(|e| AvatarError::Failed(format!("{:?}", e)))
+}
+
+#[delete("/user/avatar")]
+pub fn delete_avatar(_writable: WritableUser, jwt: JWT, db: &State<SharedDatabase>) -> AccountResult {
+    if let Err(e) = remove_avatar(&jwt.user_id) {
+        error!("Failed to delete avatar of {}: {:?}", jwt.user_id, e);
+        return Err(status::Custom(Status::InternalServerError, "Failed to delete avatar"));
+    }
+    refreshed_token(db, &jwt)
+}
+
+/// Avatars of all users can be seen by everyone who is logged in, e.g. for the owners of shared folders.
+/// token gets read by the `UserID` guard from the `token` query parameter, like `download::download_file`.
+/// 404 for ids that are no valid user id, they would end up in the path
+#[get("/user/avatar?<id>&<thumbnail>")]
+pub async fn get_avatar(id: &str, thumbnail: Option<bool>, _token: UserID) -> Option<NamedFile> {
+    let user_id = UserID::try_from(id.to_owned()).ok()?;
+    NamedFile::open(avatar_path(&user_id, thumbnail.unwrap_or(false))).await.ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Uncompressed 24 bit bmp header without pixel data
+    fn bmp_header(width: u32, height: u32) -> Vec<u8> {
+        let mut bmp = b"BM".to_vec();
+        bmp.extend_from_slice(&54u32.to_le_bytes()); // file size, not checked
+        bmp.extend_from_slice(&[0; 4]);
+        bmp.extend_from_slice(&54u32.to_le_bytes()); // pixel data offset
+        bmp.extend_from_slice(&40u32.to_le_bytes());
+        bmp.extend_from_slice(&width.to_le_bytes());
+        bmp.extend_from_slice(&height.to_le_bytes());
+        bmp.extend_from_slice(&1u16.to_le_bytes());
+        bmp.extend_from_slice(&24u16.to_le_bytes());
+        bmp.extend_from_slice(&[0; 24]);
+        bmp
+    }
+
+    #[test]
+    fn test_avatar_dimensions() {
+        assert_eq!(avatar_dimensions(&bmp_header(640, 480)), Ok((640, 480)));
+        assert_eq!(avatar_dimensions(&bmp_header(10_000, 5_000)), Err(AvatarError::TooLarge));
+        assert_eq!(avatar_dimensions(b"no image"), Err(AvatarError::NotAnImage));
+    }
+}
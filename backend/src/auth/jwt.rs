@@ -1,6 +1,13 @@
-use chrono::{Duration, Utc};
-use medallion::{Header, Payload, Token};
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use log::info;
+use medallion::{Algorithm, Header, Payload, Token};
 use rocket::{Request, request::{FromRequest, Outcome}};
+use crate::database::SharedDatabase;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// How long a token (and its session) stays valid after login
+pub const TOKEN_LIFETIME_DAYS: i64 = 7;
 
 #[derive(Serialize, Deserialize)]
 pub struct JWT {
@@ -13,8 +20,19 @@ pub struct JWT {
     pub user_id: super::UserID,
     #[serde(rename = "userRoll")]
     pub user_roll: super::database::UserRoll,
+    /// Stored as `jti` claim, not as private claim
+    #[serde(skip)]
+    pub session_id: Option<String>,
+    /// `exp` claim of a parsed token, signing uses the expiry passed to `to_jwt`
+    #[serde(skip)]
+    pub expires_at: Option<DateTime<Utc>>,
 }
 
+impl JWT {
+    pub fn expires_at() -> DateTime<Utc> {
+        Utc::now() + Duration::days(TOKEN_LIFETIME_DAYS)
+    }
+}
 
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for JWT {
@@ -22,46 +40,152 @@ impl<'r> FromRequest<'r> for JWT {
     async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
         use rocket::http::Status;
 
+        // downloads and previews can't set headers, so they pass the token as ?token=...
+        let token = match request.headers().get("Authorization").next() {
+            Some(header) if header.starts_with("Bearer ") => Some(&header[7..]),
+            _ => request.query_value::<&str>("token").and_then(Result::ok),
+        };
 
-        if let Some(token) = request.headers().get("Authorization").next() {
-            if token.starts_with("Bearer ") {
-                let jwt = &token[7..];
-
-                if let Ok(jwt) = crate::auth::jwt::validate_and_parse(jwt) {
+        if let Some(jwt) = token {
+            if let Ok(jwt) = crate::auth::jwt::validate_and_parse(jwt) {
+                let db = request.rocket().state::<SharedDatabase>();
+                let active = match (db, jwt.session_id.as_deref()) {
+                    (Some(db), Some(session_id)) => db.touch_session(session_id, &jwt.user_id),
+                    _ => false,
+                };
+                if active {
                     return Outcome::Success(jwt);
                 }
-                
+                info!("Rejected token of revoked or unknown session for {}", jwt.user_id);
             }
         }
         Outcome::Failure((Status::Unauthorized, ()))
     }
 }
 
-pub fn to_jwt(payload: JWT) -> Result<String, &'static str> {
-    // TODO !!!! production secret
+/// Custom header fields, `kid` names the key from the config the token was signed with
+#[derive(Serialize, Deserialize, Default)]
+pub struct KeyHeader {
+    kid: String,
+}
 
-    let token: Token<(), JWT> = Token::new(
-        Header::default(),
+pub fn to_jwt(payload: JWT, expires_at: DateTime<Utc>) -> Result<String, &'static str> {
+    let jti = payload.session_id.clone();
+    sign(payload, None, jti, expires_at)
+}
+
+/// Checks signature (with the key named by `kid`), algorithm, expiry and issuer
+pub fn validate_and_parse(token: &str) -> medallion::Result<JWT> {
+    let (mut jwt, jti, exp) = parse::<JWT>(token, None)?;
+    jwt.session_id = jti;
+    jwt.expires_at = Some(exp);
+    Ok(jwt)
+}
+
+/// Audience of share tokens, so they can't be used as user tokens and the other way around
+const SHARE_TOKEN_AUDIENCE: &str = "share";
+
+/// How long a share token from `shared::unlock_share` stays valid
+pub const SHARE_TOKEN_LIFETIME_HOURS: i64 = 1;
+
+/// Grants access to one password protected share
+#[derive(Serialize, Deserialize)]
+pub struct ShareToken {
+    #[serde(rename = "sharedId")]
+    pub shared_id: String,
+    /// derived from the password hash, so changing the password revokes all tokens
+    #[serde(rename = "passwordTag")]
+    pub password_tag: String,
+}
+
+impl ShareToken {
+    pub fn expires_at() -> DateTime<Utc> {
+        Utc::now() + Duration::hours(SHARE_TOKEN_LIFETIME_HOURS)
+    }
+}
+
+pub fn to_share_token(payload: ShareToken, expires_at: DateTime<Utc>) -> Result<String, &'static str> {
+    sign(payload, Some(SHARE_TOKEN_AUDIENCE), None, expires_at)
+}
+
+pub fn validate_share_token(token: &str) -> medallion::Result<ShareToken> {
+    parse::<ShareToken>(token, Some(SHARE_TOKEN_AUDIENCE)).map(|(token, ..)| token)
+}
+
+fn sign<C: Serialize + DeserializeOwned>(
+    claims: C,
+    aud: Option<&str>,
+    jti: Option<String>,
+    expires_at: DateTime<Utc>,
+) -> Result<String, &'static str> {
+    let key = crate::config::jwt_signing_key();
+
+    let token: Token<KeyHeader, C> = Token::new(
+        Header {
+            alg: Algorithm::HS256,
+            headers: Some(KeyHeader {
+                kid: key.kid.clone(),
+            }),
+            ..Default::default()
+        },
         Payload {
-            iss: Some("cloud.matthiaskind.com".into()),
-            exp: Some((Utc::now() + Duration::days(7)).timestamp() as u64),
-            claims: Some(payload),
-            aud: None,
-            iat: None,
-            jti: None,
+            iss: Some(crate::config::jwt_issuer().into()),
+            exp: Some(expires_at.timestamp() as u64),
+            claims: Some(claims),
+            aud: aud.map(String::from),
+            iat: Some(Utc::now().timestamp() as u64),
+            jti,
             nbf: None,
             sub: None,
         },
     );
 
     token
-        .sign(b"test_secret")
+        .sign(key.secret.as_bytes())
         .map_err(|_| "failed to sign token")
 }
 
-pub fn validate_and_parse(token: &str) -> medallion::Result<JWT> {
-    Token::<(), _>::parse(token)?
-        .payload
+/// Checks signature (with the key named by `kid`), algorithm, expiry, issuer and audience,
+/// returns the private claims, the `jti` claim and the expiry
+fn parse<C: Serialize + DeserializeOwned>(
+    token: &str,
+    aud: Option<&str>,
+) -> medallion::Result<(C, Option<String>, DateTime<Utc>)> {
+    let token = Token::<KeyHeader, C>::parse(token)?;
+
+    if token.header.alg != Algorithm::HS256 {
+        return Err(anyhow::Error::msg("Unexpected signature algorithm"));
+    }
+
+    let kid = token
+        .header
+        .headers
+        .as_ref()
+        .map(|h| h.kid.as_str())
+        .ok_or(anyhow::Error::msg("No kid in token header"))?;
+    let key = crate::config::jwt_keys()
+        .iter()
+        .find(|k| k.kid == kid)
+        .ok_or(anyhow::Error::msg("Unknown kid"))?;
+    if !token.verify(key.secret.as_bytes())? {
+        return Err(anyhow::Error::msg("Invalid signature"));
+    }
+
+    let payload = token.payload;
+    let exp = match payload.exp {
+        Some(exp) if exp > Utc::now().timestamp() as u64 => exp,
+        _ => return Err(anyhow::Error::msg("Token expired")),
+    };
+    if payload.iss.as_deref() != Some(crate::config::jwt_issuer()) {
+        return Err(anyhow::Error::msg("Wrong issuer"));
+    }
+    if payload.aud.as_deref() != aud {
+        return Err(anyhow::Error::msg("Wrong audience"));
+    }
+
+    let claims = payload
         .claims
-        .ok_or(anyhow::Error::msg("No private claims in token"))
+        .ok_or(anyhow::Error::msg("No private claims in token"))?;
+    let expires_at = Utc.timestamp(exp as i64, 0);
+    Ok((claims, payload.jti, expires_at))
 }
@@ -1,6 +1,8 @@
 use crate::database;
 use crate::database::SharedDatabase;
+use lazy_static::lazy_static;
 use log::{info};
+use rocket::http::Status;
 use rocket::request::{FromRequest, Outcome};
 use rocket::response::status;
 use rocket::{Request, State};
@@ -8,6 +10,7 @@ use rocket::serde::json::Json;
 use serde_json::json;
 use sha3::Digest;
 
+pub mod account;
 pub mod jwt;
 
 #[derive(Deserialize)]
@@ -39,16 +42,39 @@ impl<'r> FromRequest<'r> for UserID {
     }
 }
 
-use rocket::form::{FromFormField, ValueField};
+/// Guard for routes that change data, guests are read-only and get 403
+pub struct WritableUser(pub UserID);
 
-#[rocket::async_trait]
-impl<'v> FromFormField<'v> for UserID {
-    fn from_value(token: ValueField<'v>) -> rocket::form::Result<Self> {
-        crate::auth::jwt::validate_and_parse(token.value).map(|jwt| jwt.user_id).map_err(|_| rocket::form::Errors::new())
+/// Guard for admin routes, everyone else gets 403
+pub struct AdminUser(pub UserID);
+
+async fn user_with_roll(request: &Request<'_>, required: database::UserRoll) -> Outcome<UserID, ()> {
+    match jwt::JWT::from_request(request).await {
+        Outcome::Success(jwt) if jwt.user_roll >= required => Outcome::Success(jwt.user_id),
+        Outcome::Success(jwt) => {
+            info!("{} ({:?}) needs to be {:?} for {}", jwt.user_id, jwt.user_roll, required, request.uri());
+            Outcome::Failure((Status::Forbidden, ()))
+        }
+        Outcome::Forward(e) => Outcome::Forward(e),
+        Outcome::Failure(e) => Outcome::Failure(e),
     }
 }
 
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for WritableUser {
+    type Error = ();
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        user_with_roll(request, database::UserRoll::User).await.map(WritableUser)
+    }
+}
 
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminUser {
+    type Error = ();
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        user_with_roll(request, database::UserRoll::Admin).await.map(AdminUser)
+    }
+}
 
 #[inline]
 fn quad_to_char(b: u8) -> char {
@@ -71,48 +97,158 @@ pub fn hash_str_to_hex(strng: &str) -> String {
     res
 }
 
+/// Hashes the (base64 encoded) password with argon2id and a random salt, returns a PHC string
+pub fn hash_password(password_base64: &str) -> Result<String, argon2::password_hash::Error> {
+    use argon2::password_hash::{rand_core::OsRng, PasswordHasher, SaltString};
+
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(argon2::Argon2::default()
+        .hash_password(password_base64.as_bytes(), &salt)?
+        .to_string())
+}
+
+lazy_static! {
+    /// Checked for unknown and disabled users, so their logins take as long as the ones with a wrong password
+    static ref DUMMY_HASH: String = hash_password("").unwrap_or_default();
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PasswordCheck {
+    Valid,
+    /// Valid, but stored as unsalted sha3 hash and should get rehashed
+    ValidLegacy,
+    Invalid,
+}
+
+/// Compares the entered password against a stored PHC string or legacy sha3 hex hash
+pub fn verify_password(stored_hash: &str, password_base64: &str) -> PasswordCheck {
+    use argon2::password_hash::{PasswordHash, PasswordVerifier};
+
+    if stored_hash.starts_with('$') {
+        return match PasswordHash::new(stored_hash) {
+            Ok(parsed) => {
+                if argon2::Argon2::default()
+                    .verify_password(password_base64.as_bytes(), &parsed)
+                    .is_ok()
+                {
+                    PasswordCheck::Valid
+                } else {
+                    PasswordCheck::Invalid
+                }
+            }
+            Err(e) => {
+                error!("Stored password hash is no valid PHC string: {:?}", e);
+                PasswordCheck::Invalid
+            }
+        };
+    }
+
+    if constant_time_eq(stored_hash.as_bytes(), hash_str_to_hex(password_base64).as_bytes()) {
+        PasswordCheck::ValidLegacy
+    } else {
+        PasswordCheck::Invalid
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Token with the current name, roll and avatar of the user
+pub fn user_token(
+    user: &database::DBUser,
+    session_id: String,
+    expires_at: chrono::DateTime<chrono::Utc>,
+) -> Result<String, &'static str> {
+    jwt::to_jwt(
+        jwt::JWT {
+            profile_picture_url: account::avatar_url(&user.id),
+            user_id: user.id.clone(),
+            user_name: user.shown_name().to_owned(),
+            user_roll: user.roll,
+            session_id: Some(session_id),
+            expires_at: None,
+        },
+        expires_at,
+    )
+}
+
 /// Sends token on success, else error
 #[post("/user/login", data = "<login_data>")]
 pub fn login(
-    mut login_data: Json<UserLogin>,
+    login_data: Json<UserLogin>,
     db: &State<SharedDatabase>,
 ) -> Result<String, status::Unauthorized<&'static str>> {
-    let hashed_pw = hash_str_to_hex(login_data.password_base64.as_str());
-    //println!("{}", hashed_pw);
     match db.get_user(database::GetUserQuery::ByName(&login_data.name)) {
+        Ok(user) if user.disabled => {
+            verify_password(&DUMMY_HASH, &login_data.password_base64);
+            info!("Login of disabled user {}", user.id)
+        }
         Ok(user) => {
-            if user.hashed_pw == hashed_pw {
+            let check = verify_password(&user.hashed_pw, &login_data.password_base64);
+            if check == PasswordCheck::ValidLegacy {
+                // user logs in the first time since the hashing change, upgrade the stored hash
+                match hash_password(&login_data.password_base64)
+                    .map_err(|e| format!("{:?}", e))
+                    .and_then(|hash| db.update_password_hash(&user.id, &hash).map_err(|e| format!("{:?}", e)))
+                {
+                    Ok(()) => info!("Rehashed legacy password of {}", user.id),
+                    Err(e) => error!("Failed to rehash legacy password of {}: {}", user.id, e),
+                }
+            }
+            if check != PasswordCheck::Invalid {
                 info!("User login: {}", user.id);
-                let jwt = jwt::to_jwt(jwt::JWT {
-                    profile_picture_url: None,
-                    user_id: user.id,
-                    user_name: std::mem::replace(&mut login_data.name, String::new()),
-                    user_roll: user.roll
-                })
-                .map_err(|s| status::Unauthorized(Some(s)))?;
-    
+                let expires_at = jwt::JWT::expires_at();
+                let session_id = db
+                    .create_session(&user.id, expires_at)
+                    .map_err(|e| {
+                        error!("Failed to create session: {:?}", e);
+                        status::Unauthorized(Some("Failed to create session"))
+                    })?;
+                let jwt = user_token(&user, session_id, expires_at)
+                    .map_err(|s| status::Unauthorized(Some(s)))?;
+
                 return Ok(jwt);
             }
-            info!("PW hash unmatch: db: {} entered: {}", user.hashed_pw, hashed_pw)
+            info!("Wrong password for {}", user.id)
         },
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            verify_password(&DUMMY_HASH, &login_data.password_base64);
+            info!("Login of unknown user {:?}", login_data.name)
+        }
         Err(e) => error!("login db error: {:?}", e)
     }
 
     Err(status::Unauthorized(Some("Username or password unknown")))
 }
 
-/// Sends token on success, else error
+/// Revokes the session of the token used for this request
 #[get("/user/logout")]
-pub fn logout(user: UserID) {
-    info!("User logout: {}", user);
-    //println!("{}", hashed_pw);
-    warn!("Because we are using JWT, logout is currently not implemented on server side");
+pub fn logout(jwt: jwt::JWT, db: &State<SharedDatabase>) {
+    info!("User logout: {}", jwt.user_id);
+    if let Some(session_id) = &jwt.session_id {
+        if let Err(e) = db.delete_session(session_id) {
+            error!("Failed to delete session: {:?}", e);
+        }
+    }
+}
+
+/// Revokes all sessions of the user, so every device needs to log in again
+#[get("/user/logout/all")]
+pub fn logout_all(user: UserID, db: &State<SharedDatabase>) {
+    match db.delete_user_sessions(&user) {
+        Ok(count) => info!("User {} logged out of {} sessions", user, count),
+        Err(e) => error!("Failed to delete sessions of {}: {:?}", user, e),
+    }
 }
 
 /// Sends token on success, else error
 #[get("/user", rank = 1)]
-pub fn my_user(_user: UserID) -> Result<Json<serde_json::Value>, status::BadRequest<&'static str>> {
-    Ok(Json(json!({"loggedIn": true})))
+pub fn my_user(jwt: jwt::JWT) -> Result<Json<serde_json::Value>, status::BadRequest<&'static str>> {
+    Ok(Json(json!({"loggedIn": true, "roll": jwt.user_roll})))
 }
 
 #[get("/user", rank = 2)]
@@ -123,8 +259,39 @@ pub fn my_user_not_loggedin() -> status::BadRequest<&'static str> {
 #[cfg(test)]
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
-    //use super::*;
+    use super::*;
+
+    #[test]
+    fn test_to_hex() {
+        assert_eq!(quad_to_char(0), '0');
+        assert_eq!(quad_to_char(9), '9');
+        assert_eq!(quad_to_char(10), 'a');
+        assert_eq!(quad_to_char(15), 'f');
+        assert_eq!(hash_str_to_hex("").len(), 64);
+    }
 
     #[test]
-    fn test_to_hex() {}
+    fn test_password_hash() {
+        let hash = hash_password("cGFzc3dvcmQ=").unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+        // salted, so the same password hashes differently
+        assert_ne!(hash, hash_password("cGFzc3dvcmQ=").unwrap());
+
+        assert_eq!(verify_password(&hash, "cGFzc3dvcmQ="), PasswordCheck::Valid);
+        assert_eq!(verify_password(&hash, "d3Jvbmc="), PasswordCheck::Invalid);
+
+        let legacy = hash_str_to_hex("cGFzc3dvcmQ=");
+        assert_eq!(verify_password(&legacy, "cGFzc3dvcmQ="), PasswordCheck::ValidLegacy);
+        assert_eq!(verify_password(&legacy, "d3Jvbmc="), PasswordCheck::Invalid);
+    }
+
+    #[test]
+    fn test_user_id() {
+        use std::convert::TryFrom;
+        assert!(UserID::try_from("user0001".to_string()).is_ok());
+        // ids end up in paths, e.g. of the avatars
+        assert!(UserID::try_from("../abcde".to_string()).is_err());
+        assert!(UserID::try_from("/etc/ab".to_string()).is_err());
+        assert!(UserID::try_from("user001".to_string()).is_err());
+    }
 }
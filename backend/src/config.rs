@@ -1,4 +1,4 @@
-use log::{info, warn};
+use log::{error, info, warn};
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::sync::Once;
@@ -10,10 +10,22 @@ pub struct IconConf {
     pub color: String,
 }
 
+/// Secret used to sign and verify tokens, identified by the `kid` header
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JwtKey {
+    pub kid: String,
+    pub secret: String,
+}
+
 struct ConfigStore {
     data_path: PathBuf,
     db_path: PathBuf,
     icon_conf: HashMap<String, IconConf>,
+    /// first key signs new tokens, all keys are accepted for verification
+    jwt_keys: Vec<JwtKey>,
+    jwt_issuer: String,
+    trash_retention_days: u32,
+    versions_max_count: usize,
 }
 
 static mut CONFIG_STORE: Option<ConfigStore> = None;
@@ -29,10 +41,17 @@ pub fn debug_config_store() -> String {
     res.push_str("\n\tdb_path: ");
     res.push_str(db_path().to_string_lossy().as_ref());
     res.push_str(&format!("\n\tStored icon confs: {}", icon_confs().len()));
+    res.push_str(&format!("\n\ttrash_retention_days: {}", trash_retention_days()));
+    res.push_str(&format!("\n\tversions_max_count: {}", versions_max_count()));
+    res.push_str("\n\tjwt_issuer: ");
+    res.push_str(jwt_issuer());
+    res.push_str("\n\tjwt key ids: ");
+    res.push_str(&jwt_keys().iter().map(|k| k.kid.as_str()).collect::<Vec<_>>().join(", "));
     res
 }
 
 pub fn init() -> Result<(), &'static str> {
+    let mut res = Ok(());
     INIT_ONCE.call_once(|| {
         info!("Initializing ConfigStore...");
 
@@ -67,10 +86,30 @@ pub fn init() -> Result<(), &'static str> {
             }
         }
 
+        // generating a new key would log out everyone and lose the older keys
+        let jwt_keys = match load_jwt_keys() {
+            Ok(keys) => keys,
+            Err(e) => {
+                error!("{}", e);
+                res = Err("Failed to load the jwt keys");
+                return;
+            }
+        };
+
         let conf = ConfigStore {
             data_path: PathBuf::from(m_data_path.unwrap_or("./test_data".into())),
             db_path: PathBuf::from(m_db_path.unwrap_or("./database.sqlite".into())),
             icon_conf,
+            jwt_keys,
+            jwt_issuer: std::env::var("JWT_ISSUER").unwrap_or("cloud.matthiaskind.com".into()),
+            trash_retention_days: std::env::var("TRASH_RETENTION_DAYS")
+                .ok()
+                .and_then(|d| d.parse().ok())
+                .unwrap_or(30),
+            versions_max_count: std::env::var("VERSIONS_MAX_COUNT")
+                .ok()
+                .and_then(|c| c.parse().ok())
+                .unwrap_or(10),
         };
         unsafe {
             assert!(CONFIG_STORE.is_none());
@@ -80,9 +119,79 @@ pub fn init() -> Result<(), &'static str> {
         info!("ConfigStore init finished\n{}", debug_config_store());
     });
 
+    res
+}
+
+fn generate_jwt_key() -> JwtKey {
+    let secret: String = crate::utils::get_rand_token::<64>()
+        .iter()
+        .map(|e| *e as char)
+        .collect();
+    JwtKey {
+        kid: crate::utils::get_rand_token::<8>().iter().map(|e| *e as char).collect(),
+        secret,
+    }
+}
+
+/// kid of the key from JWT_SECRET, the secret isn't stored so it has no generated id
+const ENV_JWT_KID: &str = "env";
+
+/// Loads the keys from JWT_KEYS (json list of {kid, secret}, newest first).
+/// If the file doesn't exist, a new key gets generated and stored there.
+/// A file that can't be read or parsed is an error, it never gets overwritten.
+/// JWT_SECRET overrides the signing key, keys from the file stay valid for verification
+fn load_jwt_keys() -> Result<Vec<JwtKey>, String> {
+    let keys_path = std::env::var("JWT_KEYS").unwrap_or("./jwt-keys.json".into());
+
+    let mut keys: Vec<JwtKey> = match std::fs::read_to_string(&keys_path) {
+        Ok(json) => serde_json::from_str(&json)
+            .map_err(|e| format!("Error while parsing {}, fix or remove it: {:?}", keys_path, e))?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(format!("Failed to load {}: {:?}", keys_path, e)),
+    };
+
+    if let Ok(secret) = std::env::var("JWT_SECRET") {
+        // fixed kid, tokens signed with a previous JWT_SECRET just fail verification
+        keys.retain(|k| k.kid != ENV_JWT_KID);
+        keys.insert(0, JwtKey { kid: ENV_JWT_KID.into(), secret });
+    } else if keys.is_empty() {
+        warn!("No jwt keys found, generating new key at {}", keys_path);
+        keys.push(generate_jwt_key());
+        if let Err(e) = write_jwt_keys(Path::new(&keys_path), &keys) {
+            warn!(
+                "Failed to store jwt keys at {}, tokens will be invalid after restart! {:?}",
+                keys_path, e
+            );
+        }
+    }
+
+    Ok(keys)
+}
+
+fn write_jwt_keys(path: &Path, keys: &[JwtKey]) -> std::io::Result<()> {
+    let json = serde_json::to_string_pretty(keys)?;
+    std::fs::write(path, json)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    }
     Ok(())
 }
 
+/// Initializes the config for tests, all of them share the data path in the returned temp dir
+#[cfg(test)]
+pub fn init_for_tests() -> PathBuf {
+    let mut dir = std::env::temp_dir();
+    dir.push(format!("what-cloud-test-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("data")).unwrap();
+    std::env::set_var("DATA_PATH", dir.join("data"));
+    std::env::set_var("JWT_SECRET", "test-secret");
+    std::env::set_var("JWT_KEYS", dir.join("jwt-keys.json"));
+    init().unwrap();
+    dir
+}
+
 unsafe fn conf() -> &'static ConfigStore {
     CONFIG_STORE.as_ref().expect("Config not initialized")
 }
@@ -98,3 +207,26 @@ pub fn data_path() -> &'static Path {
 pub fn db_path() -> &'static Path {
     unsafe { conf().db_path.as_path() }
 }
+
+/// Key new tokens get signed with
+pub fn jwt_signing_key() -> &'static JwtKey {
+    unsafe { &conf().jwt_keys[0] }
+}
+
+pub fn jwt_keys() -> &'static [JwtKey] {
+    unsafe { &conf().jwt_keys }
+}
+
+pub fn jwt_issuer() -> &'static str {
+    unsafe { &conf().jwt_issuer }
+}
+
+/// Deleted nodes are kept in the trash this long
+pub fn trash_retention_days() -> u32 {
+    unsafe { conf().trash_retention_days }
+}
+
+/// Older versions of a file get pruned when a new one is stored
+pub fn versions_max_count() -> usize {
+    unsafe { conf().versions_max_count }
+}
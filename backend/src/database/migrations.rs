@@ -0,0 +1,182 @@
+use log::info;
+use rusqlite::{params, Connection};
+
+/// (version, sql) in ascending order. The version of a database is stored in `PRAGMA user_version`,
+/// so only migrations with a higher version get applied.
+/// Never change an existing migration, add a new one instead!
+const MIGRATIONS: &[(u32, &str)] = &[
+    (1, include_str!("migrations/0001_initial.sql")),
+    (2, include_str!("migrations/0002_sessions.sql")),
+    (3, include_str!("migrations/0003_shared_uploaded_bytes.sql")),
+    (4, include_str!("migrations/0004_uploads.sql")),
+    (5, include_str!("migrations/0005_trash.sql")),
+    (6, include_str!("migrations/0006_versions.sql")),
+    (7, include_str!("migrations/0007_shared_expiry.sql")),
+    (8, include_str!("migrations/0008_shared_password.sql")),
+    (9, include_str!("migrations/0009_shared_flags.sql")),
+    (10, include_str!("migrations/0010_user_shares.sql")),
+    (11, include_str!("migrations/0011_share_access_log.sql")),
+    (12, include_str!("migrations/0012_user_disabled.sql")),
+    (13, include_str!("migrations/0013_user_settings.sql")),
+    (14, include_str!("migrations/0014_user_quota.sql")),
+    (15, include_str!("migrations/0015_folder_sizes.sql")),
+    (16, include_str!("migrations/0016_file_index.sql")),
+    (17, include_str!("migrations/0017_share_downloads.sql")),
+    (18, include_str!("migrations/0018_trashed_shares.sql")),
+];
+
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map(|(v, _)| *v).unwrap_or(0)
+}
+
+pub fn current_version(conn: &Connection) -> rusqlite::Result<u32> {
+    conn.query_row("PRAGMA user_version", params![], |r| r.get(0))
+}
+
+/// Applies all missing migrations, each one in its own transaction
+pub fn migrate(conn: &mut Connection) -> Result<(), String> {
+    let version = current_version(conn).map_err(|e| format!("{:?}", e))?;
+    if version > latest_version() {
+        return Err(format!(
+            "Database has version {}, but this build only knows up to version {}",
+            version,
+            latest_version()
+        ));
+    }
+
+    if version == 0 {
+        repair_legacy_schema(conn).map_err(|e| format!("Repairing the legacy schema failed: {:?}", e))?;
+    }
+
+    for (migration_version, sql) in MIGRATIONS.iter().filter(|(v, _)| *v > version) {
+        info!("Applying database migration {}", migration_version);
+        let tx = conn.transaction().map_err(|e| format!("{:?}", e))?;
+        tx.execute_batch(sql)
+            .and_then(|_| tx.execute_batch(&format!("PRAGMA user_version = {};", migration_version)))
+            .map_err(|e| format!("Migration {} failed: {:?}", migration_version, e))?;
+        tx.commit().map_err(|e| format!("{:?}", e))?;
+    }
+    Ok(())
+}
+
+fn column_exists(conn: &Connection, table: &str, column: &str) -> rusqlite::Result<bool> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info(\"{}\")", table))?;
+    let names = stmt.query_map(params![], |r| r.get::<_, String>(1))?;
+    for name in names {
+        if name?.eq_ignore_ascii_case(column) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Databases created from the old `up.sql` have no `SHARED.UPLOAD_LIMIT` and call `USERS.ROLLS` `ROLL`.
+/// Migration 1 only creates missing tables, so existing ones are fixed here
+fn repair_legacy_schema(conn: &mut Connection) -> rusqlite::Result<()> {
+    let tx = conn.transaction()?;
+    if column_exists(&tx, "SHARED", "BASE_PATH")? && !column_exists(&tx, "SHARED", "UPLOAD_LIMIT")? {
+        info!("Adding missing SHARED.UPLOAD_LIMIT");
+        tx.execute_batch("ALTER TABLE \"SHARED\" ADD COLUMN \"UPLOAD_LIMIT\" INTEGER;")?;
+    }
+    if column_exists(&tx, "USERS", "ROLL")? && !column_exists(&tx, "USERS", "ROLLS")? {
+        info!("Renaming USERS.ROLL to ROLLS");
+        tx.execute_batch("ALTER TABLE \"USERS\" RENAME COLUMN \"ROLL\" TO \"ROLLS\";")?;
+    }
+    tx.commit()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table_exists(conn: &Connection, name: &str) -> bool {
+        conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?)",
+            params![name],
+            |r| r.get::<usize, u32>(0),
+        )
+        .unwrap()
+            == 1
+    }
+
+    #[test]
+    fn test_fresh_database() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        assert_eq!(current_version(&conn).unwrap(), latest_version());
+        assert!(table_exists(&conn, "USERS"));
+        assert!(table_exists(&conn, "SHARED"));
+        assert!(table_exists(&conn, "SESSIONS"));
+
+        // running again doesn't change anything
+        migrate(&mut conn).unwrap();
+        assert_eq!(current_version(&conn).unwrap(), latest_version());
+    }
+
+    #[test]
+    fn test_unversioned_database() {
+        // databases copied from the shipped database.sqlite before migrations existed
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(MIGRATIONS[0].1).unwrap();
+        conn.execute(
+            "INSERT INTO USERS (ID, NAME, PASSWORD_HASH, ROLLS) VALUES ('asdf1234', 'user', 'hash', 1)",
+            params![],
+        )
+        .unwrap();
+        migrate(&mut conn).unwrap();
+        assert_eq!(current_version(&conn).unwrap(), latest_version());
+        let users: u32 = conn
+            .query_row("SELECT COUNT(*) FROM USERS", params![], |r| r.get(0))
+            .unwrap();
+        assert_eq!(users, 1);
+    }
+
+    #[test]
+    fn test_legacy_up_sql() {
+        // schema of the `up.sql` used before migrations existed
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            r#"create table if not exists "USERS" (
+                ID INTEGER PRIMARY KEY UNIQUE,
+                NAME TEXT NOT NULL,
+                PASSWORD_HASH TEXT NOT NULL,
+                ROLL INTEGER
+            );
+            CREATE TABLE IF NOT EXISTS "SHARED" (
+                "ID"	TEXT NOT NULL UNIQUE,
+                "USER"	TEXT NOT NULL,
+                "BASE_PATH"	TEXT NOT NULL,
+                "FLAGS"	INTEGER,
+                "CREATED_AT"	TEXT NOT NULL,
+                PRIMARY KEY("ID")
+            );"#,
+        )
+        .unwrap();
+        conn.execute("INSERT INTO USERS (ID, NAME, PASSWORD_HASH, ROLL) VALUES (1, 'user', 'hash', 2)", params![])
+            .unwrap();
+        conn.execute(
+            "INSERT INTO SHARED (ID, USER, BASE_PATH, CREATED_AT) VALUES ('share', '1', 'a', datetime('now'))",
+            params![],
+        )
+        .unwrap();
+
+        migrate(&mut conn).unwrap();
+        assert_eq!(current_version(&conn).unwrap(), latest_version());
+        let roll: u32 = conn.query_row("SELECT ROLLS FROM USERS", params![], |r| r.get(0)).unwrap();
+        assert_eq!(roll, 2);
+        // like `update_share`
+        conn.execute("UPDATE SHARED SET UPLOAD_LIMIT = 10, MAX_DOWNLOADS = 1 WHERE ID = 'share'", params![])
+            .unwrap();
+        let flags: u32 = conn.query_row("SELECT FLAGS FROM SHARED", params![], |r| r.get(0)).unwrap();
+        // list + download + preview, it had no upload limit when migration 9 ran
+        assert_eq!(flags, 7);
+    }
+
+    #[test]
+    fn test_newer_database() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(&format!("PRAGMA user_version = {};", latest_version() + 1))
+            .unwrap();
+        assert!(migrate(&mut conn).is_err());
+    }
+}
@@ -0,0 +1,17 @@
+CREATE TABLE IF NOT EXISTS "USERS" (
+	"ID"	TEXT NOT NULL UNIQUE,
+	"NAME"	TEXT NOT NULL,
+	"PASSWORD_HASH"	TEXT NOT NULL,
+	"ROLLS"	INTEGER,
+	PRIMARY KEY("ID")
+);
+
+CREATE TABLE IF NOT EXISTS "SHARED" (
+	"ID"	TEXT NOT NULL UNIQUE,
+	"USER"	TEXT NOT NULL,
+	"BASE_PATH"	TEXT NOT NULL,
+	"FLAGS"	INTEGER,
+	"CREATED_AT"	TEXT NOT NULL,
+	"UPLOAD_LIMIT"	INTEGER,
+	PRIMARY KEY("ID")
+);
@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS "SESSIONS" (
+	"ID"	TEXT NOT NULL UNIQUE,
+	"USER"	TEXT NOT NULL,
+	"CREATED_AT"	TEXT NOT NULL,
+	"LAST_SEEN"	TEXT NOT NULL,
+	"EXPIRES_AT"	TEXT NOT NULL,
+	PRIMARY KEY("ID")
+);
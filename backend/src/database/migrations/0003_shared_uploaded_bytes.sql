@@ -0,0 +1 @@
+ALTER TABLE "SHARED" ADD COLUMN "UPLOADED_BYTES" INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS "UPLOADS" (
+	"ID"	TEXT NOT NULL UNIQUE,
+	"USER"	TEXT NOT NULL,
+	"TARGET_PATH"	TEXT NOT NULL,
+	"UPLOAD_LENGTH"	INTEGER NOT NULL,
+	"UPLOAD_OFFSET"	INTEGER NOT NULL DEFAULT 0,
+	"METADATA"	TEXT,
+	"CREATED_AT"	TEXT NOT NULL,
+	"UPDATED_AT"	TEXT NOT NULL,
+	PRIMARY KEY("ID")
+);
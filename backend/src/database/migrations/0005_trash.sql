@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS "TRASH" (
+	"ID"	TEXT NOT NULL UNIQUE,
+	"USER"	TEXT NOT NULL,
+	"ORIGINAL_PATH"	TEXT NOT NULL,
+	"IS_FOLDER"	INTEGER NOT NULL,
+	"DELETED_AT"	TEXT NOT NULL,
+	PRIMARY KEY("ID")
+);
@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS "VERSIONS" (
+	"ID"	TEXT NOT NULL UNIQUE,
+	"USER"	TEXT NOT NULL,
+	"PATH"	TEXT NOT NULL,
+	"SIZE"	INTEGER NOT NULL,
+	"CREATED_AT"	TEXT NOT NULL,
+	PRIMARY KEY("ID")
+);
+CREATE INDEX IF NOT EXISTS "VERSIONS_USER_PATH" ON "VERSIONS" ("USER", "PATH");
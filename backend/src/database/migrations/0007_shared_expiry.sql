@@ -0,0 +1,3 @@
+ALTER TABLE "SHARED" ADD COLUMN "EXPIRES_AT" TEXT;
+ALTER TABLE "SHARED" ADD COLUMN "MAX_DOWNLOADS" INTEGER;
+ALTER TABLE "SHARED" ADD COLUMN "DOWNLOADS" INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1 @@
+ALTER TABLE "SHARED" ADD COLUMN "PASSWORD_HASH" TEXT;
@@ -0,0 +1,2 @@
+-- shares were implicitly list + download + preview, and upload if they had an upload limit
+UPDATE "SHARED" SET "FLAGS" = 7 | (CASE WHEN "UPLOAD_LIMIT" IS NOT NULL THEN 8 ELSE 0 END) WHERE "FLAGS" IS NULL;
@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS "USER_SHARES" (
+	"ID"	TEXT NOT NULL UNIQUE,
+	"OWNER"	TEXT NOT NULL,
+	"PATH"	TEXT NOT NULL,
+	"TARGET_USER"	TEXT NOT NULL,
+	"WRITABLE"	INTEGER NOT NULL,
+	"CREATED_AT"	TEXT NOT NULL,
+	PRIMARY KEY("ID"),
+	UNIQUE("OWNER", "PATH", "TARGET_USER")
+);
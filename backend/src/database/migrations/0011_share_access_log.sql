@@ -0,0 +1,12 @@
+CREATE TABLE IF NOT EXISTS "SHARE_ACCESS_LOG" (
+	"ID"	INTEGER NOT NULL,
+	"SHARE_ID"	TEXT NOT NULL,
+	"ACCESSED_AT"	TEXT NOT NULL,
+	"KIND"	TEXT NOT NULL,
+	"PATH"	TEXT NOT NULL,
+	"IP"	TEXT,
+	"USER_AGENT"	TEXT,
+	"BYTES"	INTEGER,
+	PRIMARY KEY("ID" AUTOINCREMENT)
+);
+CREATE INDEX IF NOT EXISTS "SHARE_ACCESS_LOG_SHARE" ON "SHARE_ACCESS_LOG" ("SHARE_ID", "ACCESSED_AT");
@@ -0,0 +1 @@
+ALTER TABLE "USERS" ADD COLUMN "DISABLED" INTEGER NOT NULL DEFAULT 0;
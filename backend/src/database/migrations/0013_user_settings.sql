@@ -0,0 +1,2 @@
+ALTER TABLE "USERS" ADD COLUMN "DISPLAY_NAME" TEXT;
+ALTER TABLE "USERS" ADD COLUMN "SETTINGS" TEXT;
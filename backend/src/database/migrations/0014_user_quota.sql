@@ -0,0 +1,3 @@
+-- NULL quota is unlimited, NULL usage gets calculated from the files on startup
+ALTER TABLE "USERS" ADD COLUMN "QUOTA_BYTES" INTEGER;
+ALTER TABLE "USERS" ADD COLUMN "USED_BYTES" INTEGER;
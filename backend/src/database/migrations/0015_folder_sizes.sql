@@ -0,0 +1,7 @@
+-- recursive sizes of the folders in the users roots, PATH is relative to the root ('' is the root)
+CREATE TABLE IF NOT EXISTS "FOLDER_SIZES" (
+	"USER"	TEXT NOT NULL,
+	"PATH"	TEXT NOT NULL,
+	"SIZE"	INTEGER NOT NULL,
+	PRIMARY KEY("USER","PATH")
+);
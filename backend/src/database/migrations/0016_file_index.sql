@@ -0,0 +1,13 @@
+-- names and metadata of all files and folders in the users roots for the search, PATH is relative to the root.
+-- NAME is lowercase, EXTENSION lowercase without dot and NULL for folders, MODIFIED is a unix timestamp
+CREATE TABLE IF NOT EXISTS "FILE_INDEX" (
+	"USER"	TEXT NOT NULL,
+	"PATH"	TEXT NOT NULL,
+	"NAME"	TEXT NOT NULL,
+	"IS_FOLDER"	INTEGER NOT NULL,
+	"EXTENSION"	TEXT,
+	"SIZE"	INTEGER NOT NULL,
+	"MODIFIED"	INTEGER NOT NULL,
+	PRIMARY KEY("USER","PATH")
+);
+CREATE INDEX IF NOT EXISTS "FILE_INDEX_EXTENSION" ON "FILE_INDEX" ("USER", "EXTENSION");
@@ -0,0 +1,8 @@
+-- counted downloads of shares, range requests continuing one from the same ip don't count again
+CREATE TABLE IF NOT EXISTS "SHARE_DOWNLOADS" (
+	"SHARE_ID"	TEXT NOT NULL,
+	"PATH"	TEXT NOT NULL,
+	"IP"	TEXT,
+	"COUNTED_AT"	TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS "SHARE_DOWNLOADS_SHARE" ON "SHARE_DOWNLOADS" ("SHARE_ID", "IP", "PATH");
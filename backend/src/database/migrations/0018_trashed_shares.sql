@@ -0,0 +1,19 @@
+-- shares of deleted nodes are kept with the id of the trash item until it is purged, so restoring brings them back
+ALTER TABLE "SHARED" ADD COLUMN "TRASH_ID" TEXT;
+
+-- only the user shares of existing nodes need unique paths
+CREATE TABLE IF NOT EXISTS "USER_SHARES_NEW" (
+	"ID"	TEXT NOT NULL UNIQUE,
+	"OWNER"	TEXT NOT NULL,
+	"PATH"	TEXT NOT NULL,
+	"TARGET_USER"	TEXT NOT NULL,
+	"WRITABLE"	INTEGER NOT NULL,
+	"CREATED_AT"	TEXT NOT NULL,
+	"TRASH_ID"	TEXT,
+	PRIMARY KEY("ID")
+);
+INSERT INTO "USER_SHARES_NEW" ("ID", "OWNER", "PATH", "TARGET_USER", "WRITABLE", "CREATED_AT")
+	SELECT "ID", "OWNER", "PATH", "TARGET_USER", "WRITABLE", "CREATED_AT" FROM "USER_SHARES";
+DROP TABLE "USER_SHARES";
+ALTER TABLE "USER_SHARES_NEW" RENAME TO "USER_SHARES";
+CREATE UNIQUE INDEX IF NOT EXISTS "USER_SHARES_PATH" ON "USER_SHARES" ("OWNER", "PATH", "TARGET_USER") WHERE "TRASH_ID" IS NULL;
@@ -1,11 +1,14 @@
 use crate::auth::UserID;
-use crate::fs::shared::{SharedEntry, SharedID};
+use crate::fs::search::{NamePattern, SearchFilter};
+use crate::fs::shared::{SharePermissions, SharedEntry, SharedID};
 use log::{error, info, trace, warn};
 use rusqlite::{params, Connection, Result, Row, ToSql};
 use std::convert::{TryFrom, TryInto};
 use std::path::{Path, PathBuf};
 use std::sync::{Mutex, MutexGuard};
 
+mod migrations;
+
 pub struct SharedDatabase {
     conn: Mutex<Connection>,
 }
@@ -13,8 +16,13 @@ pub struct SharedDatabase {
 impl SharedDatabase {
     pub fn new(path: &Path) -> Self {
         info!("Opening database {:?}", path);
+        let mut conn = Connection::open(path).expect("Failed to open database");
+        if let Err(e) = migrations::migrate(&mut conn) {
+            error!("{}", e);
+            panic!("Failed to migrate database");
+        }
         SharedDatabase {
-            conn: Mutex::new(Connection::open(path).expect("Failed to open database")),
+            conn: Mutex::new(conn),
         }
     }
 
@@ -26,24 +34,150 @@ impl SharedDatabase {
     pub fn get_user(&self, query: GetUserQuery) -> rusqlite::Result<DBUser> {
         let conn = self.conn();
         match query {
-            GetUserQuery::ByName(name) => {
-                let mut stmt = conn
-                    .prepare("SELECT ID, NAME, PASSWORD_HASH, ROLLS FROM USERS WHERE NAME = ?")?;
-                let n = stmt.query_map(params![name], user_from_row)?.next();
-                println!("db user by name {} -> {:?}", name, n);
-                return match n {
-                    Some(r) => r,
-                    None => Err(rusqlite::Error::QueryReturnedNoRows)
-                }
-            }
-            _ => panic!("get_user branch not implemented"),
+            GetUserQuery::ByName(name) => conn.query_row(
+                &format!("SELECT {} FROM USERS WHERE NAME = ?", USER_COLUMNS),
+                params![name],
+                user_from_row,
+            ),
+            GetUserQuery::ByID(id) => conn.query_row(
+                &format!("SELECT {} FROM USERS WHERE ID = ?", USER_COLUMNS),
+                params![&id.0],
+                user_from_row,
+            ),
+        }
+    }
+
+    /// `hash` is a PHC string from `auth::hash_password`
+    pub fn create_user(&self, id: &UserID, name: &str, hash: &str, roll: UserRoll) -> rusqlite::Result<()> {
+        let conn = self.conn();
+        conn.execute(
+            "INSERT INTO USERS (ID, NAME, PASSWORD_HASH, ROLLS, DISABLED, USED_BYTES) VALUES (?, ?, ?, ?, 0, 0)",
+            params![&id.0, name, hash, roll as u32],
+        )?;
+        Ok(())
+    }
+
+    pub fn rename_user(&self, user_id: &UserID, name: &str) -> rusqlite::Result<()> {
+        let conn = self.conn();
+        conn.execute("UPDATE USERS SET NAME = ? WHERE ID = ?", params![name, &user_id.0])?;
+        Ok(())
+    }
+
+    pub fn set_user_roll(&self, user_id: &UserID, roll: UserRoll) -> rusqlite::Result<()> {
+        let conn = self.conn();
+        conn.execute("UPDATE USERS SET ROLLS = ? WHERE ID = ?", params![roll as u32, &user_id.0])?;
+        Ok(())
+    }
+
+    /// `settings` is json from the client, stored as is
+    pub fn update_user_settings(
+        &self,
+        user_id: &UserID,
+        display_name: Option<&str>,
+        settings: Option<&str>,
+    ) -> rusqlite::Result<()> {
+        let conn = self.conn();
+        conn.execute(
+            "UPDATE USERS SET DISPLAY_NAME = ?, SETTINGS = ? WHERE ID = ?",
+            params![display_name, settings, &user_id.0],
+        )?;
+        Ok(())
+    }
+
+    /// Disabled users can't log in
+    pub fn set_user_disabled(&self, user_id: &UserID, disabled: bool) -> rusqlite::Result<()> {
+        let conn = self.conn();
+        conn.execute("UPDATE USERS SET DISABLED = ? WHERE ID = ?", params![disabled, &user_id.0])?;
+        Ok(())
+    }
+
+    /// `None` removes the quota
+    pub fn set_user_quota(&self, user_id: &UserID, quota_bytes: Option<u64>) -> rusqlite::Result<()> {
+        let conn = self.conn();
+        conn.execute(
+            "UPDATE USERS SET QUOTA_BYTES = ? WHERE ID = ?",
+            params![quota_bytes.map(|q| q as i64), &user_id.0],
+        )?;
+        Ok(())
+    }
+
+    /// Users whose usage wasn't calculated yet, e.g. because they existed before quotas
+    pub fn get_users_without_usage(&self) -> rusqlite::Result<Vec<UserID>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT ID FROM USERS WHERE USED_BYTES IS NULL")?;
+        let ids = stmt.query_map(params![], |row| row.get(0).map(UserID))?;
+        ids.collect()
+    }
+
+    pub fn set_used_bytes(&self, user_id: &UserID, bytes: u64) -> rusqlite::Result<()> {
+        let conn = self.conn();
+        conn.execute("UPDATE USERS SET USED_BYTES = ? WHERE ID = ?", params![bytes as i64, &user_id.0])?;
+        Ok(())
+    }
+
+    /// Adds `bytes` to the usage if it stays within the quota, returns false if not
+    pub fn reserve_usage(&self, user_id: &UserID, bytes: u64) -> rusqlite::Result<bool> {
+        let conn = self.conn();
+        conn.execute(
+            "UPDATE USERS SET USED_BYTES = USED_BYTES + ?1
+            WHERE ID = ?2 AND (QUOTA_BYTES IS NULL OR USED_BYTES + ?1 <= QUOTA_BYTES)",
+            params![bytes as i64, &user_id.0],
+        )
+        .map(|updated| updated == 1)
+    }
+
+    /// Changes the usage without checking the quota, e.g. for deleted files
+    pub fn change_usage(&self, user_id: &UserID, delta: i64) -> rusqlite::Result<()> {
+        let conn = self.conn();
+        conn.execute(
+            "UPDATE USERS SET USED_BYTES = MAX(USED_BYTES + ?, 0) WHERE ID = ?",
+            params![delta, &user_id.0],
+        )?;
+        Ok(())
+    }
+
+    /// Removes the user and everything stored for them in the database.
+    /// Returns the ids of the users unfinished uploads, their staging files need to be removed
+    pub fn delete_user(&self, user_id: &UserID) -> rusqlite::Result<Vec<String>> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        let uploads = {
+            let mut stmt = tx.prepare("SELECT ID FROM UPLOADS WHERE USER = ?")?;
+            let ids = stmt.query_map(params![&user_id.0], |row| row.get(0))?.collect::<rusqlite::Result<Vec<String>>>()?;
+            ids
+        };
+        for table in ["SHARE_ACCESS_LOG", "SHARE_DOWNLOADS"] {
+            tx.execute(
+                &format!("DELETE FROM {} WHERE SHARE_ID IN (SELECT ID FROM SHARED WHERE USER = ?)", table),
+                params![&user_id.0],
+            )?;
+        }
+        for table in ["SESSIONS", "SHARED", "UPLOADS", "TRASH", "VERSIONS", "FOLDER_SIZES", "FILE_INDEX"] {
+            tx.execute(&format!("DELETE FROM {} WHERE USER = ?", table), params![&user_id.0])?;
         }
+        tx.execute(
+            "DELETE FROM USER_SHARES WHERE OWNER = ?1 OR TARGET_USER = ?1",
+            params![&user_id.0],
+        )?;
+        tx.execute("DELETE FROM USERS WHERE ID = ?", params![&user_id.0])?;
+        tx.commit()?;
+        Ok(uploads)
+    }
+
+    /// `hash` is a PHC string from `auth::hash_password`
+    pub fn update_password_hash(&self, user_id: &UserID, hash: &str) -> rusqlite::Result<()> {
+        let conn = self.conn();
+        conn.execute(
+            "UPDATE USERS SET PASSWORD_HASH = ? WHERE ID = ?",
+            params![hash, &user_id.0],
+        )?;
+        Ok(())
     }
 
     pub fn get_all_users(&self) -> rusqlite::Result<Vec<DBUser>> {
         let conn =self.conn();
         let mut stmt = conn
-            .prepare("SELECT ID, NAME, PASSWORD_HASH,ROLLS FROM USERS")
+            .prepare(&format!("SELECT {} FROM USERS ORDER BY NAME", USER_COLUMNS))
             .unwrap();
         let n = stmt.query_map(params![], user_from_row).unwrap();
         return Ok(n.filter_map(|res| {
@@ -54,6 +188,214 @@ impl SharedDatabase {
         }).collect());
     }
 
+    /// Stores a new session for a freshly issued token and returns its id (the tokens `jti`)
+    pub fn create_session(
+        &self,
+        user_id: &UserID,
+        expires_at: chrono::DateTime<chrono::Utc>,
+    ) -> rusqlite::Result<String> {
+        let conn = self.conn();
+        // sessions of tokens which can't be used anymore
+        conn.execute(
+            "DELETE FROM SESSIONS WHERE EXPIRES_AT <= datetime('now')",
+            params![],
+        )?;
+
+        let session_id: String = crate::utils::get_rand_token::<32>()
+            .iter()
+            .map(|e| *e as char)
+            .collect();
+        conn.execute(
+            "INSERT INTO SESSIONS (ID, USER, CREATED_AT, LAST_SEEN, EXPIRES_AT) VALUES (?, ?, datetime('now'), datetime('now'), datetime(?, 'unixepoch'))",
+            params![&session_id, &user_id.0, expires_at.timestamp()],
+        )?;
+        Ok(session_id)
+    }
+
+    /// Returns true if the session exists, belongs to the user and is not expired.
+    /// Also updates the last seen timestamp of the session
+    pub fn touch_session(&self, session_id: &str, user_id: &UserID) -> bool {
+        let conn = self.conn();
+        match conn.execute(
+            "UPDATE SESSIONS SET LAST_SEEN = datetime('now') WHERE ID = ? AND USER = ? AND EXPIRES_AT > datetime('now')",
+            params![session_id, &user_id.0],
+        ) {
+            Ok(updated) => updated == 1,
+            Err(e) => {
+                error!("Failed to check session: {:?}", e);
+                false
+            }
+        }
+    }
+
+    pub fn delete_session(&self, session_id: &str) -> rusqlite::Result<()> {
+        let conn = self.conn();
+        conn.execute("DELETE FROM SESSIONS WHERE ID = ?", params![session_id])?;
+        Ok(())
+    }
+
+    /// Revokes all sessions of the user but `keep`, returns the number of revoked sessions
+    pub fn delete_other_sessions(&self, user_id: &UserID, keep: &str) -> rusqlite::Result<usize> {
+        let conn = self.conn();
+        conn.execute("DELETE FROM SESSIONS WHERE USER = ? AND ID != ?", params![&user_id.0, keep])
+    }
+
+    /// returns the number of revoked sessions
+    pub fn delete_user_sessions(&self, user_id: &UserID) -> rusqlite::Result<usize> {
+        let conn = self.conn();
+        conn.execute("DELETE FROM SESSIONS WHERE USER = ?", params![&user_id.0])
+    }
+
+    pub fn get_active_sessions(&self) -> rusqlite::Result<Vec<DBSession>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT ID, USER, CREATED_AT, LAST_SEEN, EXPIRES_AT FROM SESSIONS WHERE EXPIRES_AT > datetime('now') ORDER BY LAST_SEEN DESC",
+        )?;
+        let sessions = stmt
+            .query_map(params![], |row| {
+                Ok(DBSession {
+                    id: row.get(0)?,
+                    user: UserID(row.get(1)?),
+                    created_at: row.get(2)?,
+                    last_seen: row.get(3)?,
+                    expires_at: row.get(4)?,
+                })
+            })?
+            .collect();
+        sessions
+    }
+
+    /// Creates a resumable upload session, returns its id
+    pub fn create_upload(
+        &self,
+        user_id: &UserID,
+        target_path: &Path,
+        length: u64,
+        metadata: Option<&str>,
+    ) -> rusqlite::Result<String> {
+        let conn = self.conn();
+        let upload_id: String = crate::utils::get_rand_token::<32>()
+            .iter()
+            .map(|e| *e as char)
+            .collect();
+        conn.execute(
+            "INSERT INTO UPLOADS (ID, USER, TARGET_PATH, UPLOAD_LENGTH, UPLOAD_OFFSET, METADATA, CREATED_AT, UPDATED_AT)
+            VALUES (?, ?, ?, ?, 0, ?, datetime('now'), datetime('now'))",
+            params![&upload_id, &user_id.0, target_path.to_str().unwrap(), length as i64, metadata],
+        )?;
+        Ok(upload_id)
+    }
+
+    /// Only returns uploads of the given user
+    pub fn get_upload(&self, upload_id: &str, user_id: &UserID) -> Option<DBUpload> {
+        use rusqlite::OptionalExtension;
+        let conn = self.conn();
+        conn.query_row(
+            "SELECT ID, USER, TARGET_PATH, UPLOAD_LENGTH, UPLOAD_OFFSET, METADATA FROM UPLOADS WHERE ID = ? AND USER = ?",
+            params![upload_id, &user_id.0],
+            |row| {
+                Ok(DBUpload {
+                    id: row.get(0)?,
+                    user: UserID(row.get(1)?),
+                    target_path: PathBuf::from(row.get::<_, String>(2)?),
+                    length: row.get::<_, i64>(3)? as u64,
+                    offset: row.get::<_, i64>(4)? as u64,
+                    metadata: row.get(5)?,
+                })
+            },
+        )
+        .optional()
+        .map_err(|e| error!("Failed to load upload {}: {:?}", upload_id, e))
+        .ok()
+        .flatten()
+    }
+
+    pub fn set_upload_offset(&self, upload_id: &str, offset: u64) -> rusqlite::Result<()> {
+        let conn = self.conn();
+        conn.execute(
+            "UPDATE UPLOADS SET UPLOAD_OFFSET = ?, UPDATED_AT = datetime('now') WHERE ID = ?",
+            params![offset as i64, upload_id],
+        )?;
+        Ok(())
+    }
+
+    pub fn delete_upload(&self, upload_id: &str) -> rusqlite::Result<()> {
+        let conn = self.conn();
+        conn.execute("DELETE FROM UPLOADS WHERE ID = ?", params![upload_id])?;
+        Ok(())
+    }
+
+    /// Removes uploads that weren't continued for `max_age_days`, returns their ids
+    pub fn delete_stale_uploads(&self, max_age_days: u32) -> rusqlite::Result<Vec<String>> {
+        let conn = self.conn();
+        let max_age = format!("-{} days", max_age_days);
+        let ids = conn
+            .prepare("SELECT ID FROM UPLOADS WHERE UPDATED_AT < datetime('now', ?)")?
+            .query_map(params![&max_age], |r| r.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        conn.execute(
+            "DELETE FROM UPLOADS WHERE UPDATED_AT < datetime('now', ?)",
+            params![&max_age],
+        )?;
+        Ok(ids)
+    }
+
+    pub fn add_trash_item(
+        &self,
+        id: &str,
+        user_id: &UserID,
+        original_path: &Path,
+        is_folder: bool,
+    ) -> rusqlite::Result<()> {
+        let conn = self.conn();
+        conn.execute(
+            "INSERT INTO TRASH (ID, USER, ORIGINAL_PATH, IS_FOLDER, DELETED_AT) VALUES (?, ?, ?, ?, datetime('now'))",
+            params![id, &user_id.0, original_path.to_str().unwrap(), is_folder],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_trash_items(&self, user_id: &UserID) -> rusqlite::Result<Vec<DBTrashItem>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT ID, USER, ORIGINAL_PATH, IS_FOLDER, DELETED_AT FROM TRASH WHERE USER = ? ORDER BY DELETED_AT DESC",
+        )?;
+        let items = stmt.query_map(params![&user_id.0], trash_item_from_row)?.collect();
+        items
+    }
+
+    pub fn get_trash_item(&self, id: &str, user_id: &UserID) -> Option<DBTrashItem> {
+        use rusqlite::OptionalExtension;
+        let conn = self.conn();
+        conn.query_row(
+            "SELECT ID, USER, ORIGINAL_PATH, IS_FOLDER, DELETED_AT FROM TRASH WHERE ID = ? AND USER = ?",
+            params![id, &user_id.0],
+            trash_item_from_row,
+        )
+        .optional()
+        .map_err(|e| error!("Failed to load trash item {}: {:?}", id, e))
+        .ok()
+        .flatten()
+    }
+
+    /// Items of all users deleted more than `max_age_days` ago
+    pub fn get_expired_trash_items(&self, max_age_days: u32) -> rusqlite::Result<Vec<DBTrashItem>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT ID, USER, ORIGINAL_PATH, IS_FOLDER, DELETED_AT FROM TRASH WHERE DELETED_AT < datetime('now', ?)",
+        )?;
+        let items = stmt
+            .query_map(params![format!("-{} days", max_age_days)], trash_item_from_row)?
+            .collect();
+        items
+    }
+
+    pub fn delete_trash_item(&self, id: &str) -> rusqlite::Result<()> {
+        let conn = self.conn();
+        conn.execute("DELETE FROM TRASH WHERE ID = ?", params![id])?;
+        Ok(())
+    }
+
     pub fn get_share_id(
         &self,
         user_id: &UserID,
@@ -62,7 +404,7 @@ impl SharedDatabase {
         use rusqlite::OptionalExtension;
         let conn = self.conn();
         conn.query_row(
-            "SELECT ID FROM SHARED WHERE USER = ? AND BASE_PATH = ?",
+            "SELECT ID FROM SHARED WHERE USER = ? AND BASE_PATH = ? AND TRASH_ID IS NULL",
             params![&user_id.0, path.to_str().unwrap()],
             |row| row.get(0),
         )
@@ -74,28 +416,182 @@ impl SharedDatabase {
         let conn = self.conn();
         // needs rusqlite::Error as E type because get()? returns Err(rusqlite::Error) even though it is never used
         conn.query_row_and_then::<_, rusqlite::Error, _, _>(
-            "SELECT ID, USER, BASE_PATH FROM SHARED WHERE ID = ?",
+            &format!("SELECT {} FROM SHARED WHERE ID = ? AND TRASH_ID IS NULL", SHARED_ENTRY_COLUMNS),
             &[maybe_shared_id],
-            |row| {
-                let id = SharedID::from_string_unchecked(row.get(0)?);
-                let user = UserID(row.get(1)?);
-                let path = row.get::<_, String>(2)?.into();
-                Ok(SharedEntry {
-                    path,
-                    share_id: id,
-                    user,
-                })
-            },
+            shared_entry_from_row,
         )
         .ok()
     }
 
+    /// Counts a download of `path` (relative to the share) by `ip`, if the share has downloads left.
+    /// A `continued` download (range request not starting at 0) is free if the same ip had a download
+    /// of the path counted within the last `continue_minutes`. The last allowed download expires the share
+    pub fn count_share_download(
+        &self,
+        share_id: &SharedID,
+        path: &str,
+        ip: Option<&str>,
+        continued: bool,
+        continue_minutes: u32,
+    ) -> rusqlite::Result<bool> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        if continued && download_counted(&tx, share_id, path, ip, continue_minutes)? {
+            return Ok(true);
+        }
+        let updated = tx.execute(
+            "UPDATE SHARED SET DOWNLOADS = DOWNLOADS + 1,
+                EXPIRES_AT = CASE
+                    WHEN DOWNLOADS + 1 >= MAX_DOWNLOADS AND (EXPIRES_AT IS NULL OR EXPIRES_AT > datetime('now'))
+                    THEN datetime('now') ELSE EXPIRES_AT END
+            WHERE ID = ? AND (MAX_DOWNLOADS IS NULL OR DOWNLOADS < MAX_DOWNLOADS)",
+            params![share_id.as_ref()],
+        )?;
+        if updated == 1 {
+            tx.execute(
+                "INSERT INTO SHARE_DOWNLOADS (SHARE_ID, PATH, IP, COUNTED_AT) VALUES (?, ?, ?, datetime('now'))",
+                params![share_id.as_ref(), path, ip],
+            )?;
+        }
+        tx.commit()?;
+        Ok(updated == 1)
+    }
+
+    /// True if a download of `path` by `ip` was counted within the last `continue_minutes`
+    pub fn is_download_counted(
+        &self,
+        share_id: &SharedID,
+        path: &str,
+        ip: Option<&str>,
+        continue_minutes: u32,
+    ) -> rusqlite::Result<bool> {
+        download_counted(&self.conn(), share_id, path, ip, continue_minutes)
+    }
+
+    /// `password_hash` as PHC string, None removes the password
+    pub fn set_share_password(&self, share_id: &SharedID, password_hash: Option<&str>) -> rusqlite::Result<()> {
+        let conn = self.conn();
+        conn.execute(
+            "UPDATE SHARED SET PASSWORD_HASH = ? WHERE ID = ?",
+            params![password_hash, share_id.as_ref()],
+        )?;
+        Ok(())
+    }
+
+    /// `path` is relative to the share, `bytes` None if unknown (e.g. zip streams)
+    pub fn log_share_access(
+        &self,
+        share_id: &SharedID,
+        kind: &str,
+        path: &str,
+        ip: Option<&str>,
+        user_agent: Option<&str>,
+        bytes: Option<u64>,
+    ) -> rusqlite::Result<()> {
+        let conn = self.conn();
+        conn.execute(
+            "INSERT INTO SHARE_ACCESS_LOG (SHARE_ID, ACCESSED_AT, KIND, PATH, IP, USER_AGENT, BYTES)
+            VALUES (?, datetime('now'), ?, ?, ?, ?, ?)",
+            params![share_id.as_ref(), kind, path, ip, user_agent, bytes.map(|b| b as i64)],
+        )?;
+        Ok(())
+    }
+
+    /// Number of accesses, transferred bytes and the last access per kind
+    pub fn get_share_access_counts(&self, share_id: &SharedID) -> rusqlite::Result<Vec<DBShareAccessCount>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT KIND, COUNT(*), COALESCE(SUM(BYTES), 0), MAX(ACCESSED_AT) FROM SHARE_ACCESS_LOG
+            WHERE SHARE_ID = ? GROUP BY KIND ORDER BY KIND",
+        )?;
+        let counts = stmt
+            .query_map(params![share_id.as_ref()], |row| {
+                Ok(DBShareAccessCount {
+                    kind: row.get(0)?,
+                    count: row.get(1)?,
+                    bytes: row.get::<_, i64>(2)? as u64,
+                    last_access: row.get(3)?,
+                })
+            })?
+            .collect();
+        counts
+    }
+
+    /// Newest first
+    pub fn get_share_access_log(&self, share_id: &SharedID, limit: u32) -> rusqlite::Result<Vec<DBShareAccess>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT ACCESSED_AT, KIND, PATH, IP, USER_AGENT, BYTES FROM SHARE_ACCESS_LOG
+            WHERE SHARE_ID = ? ORDER BY ID DESC LIMIT ?",
+        )?;
+        let events = stmt
+            .query_map(params![share_id.as_ref(), limit], |row| {
+                Ok(DBShareAccess {
+                    accessed_at: row.get(0)?,
+                    kind: row.get(1)?,
+                    path: row.get(2)?,
+                    ip: row.get(3)?,
+                    user_agent: row.get(4)?,
+                    bytes: row.get::<_, Option<i64>>(5)?.map(|b| b as u64),
+                })
+            })?
+            .collect();
+        events
+    }
+
+    /// Removes access log entries older than `max_age_days` and the ones of deleted shares,
+    /// counted downloads are only needed for a day
+    pub fn delete_old_share_access(&self, max_age_days: u32) -> rusqlite::Result<usize> {
+        let conn = self.conn();
+        conn.execute(
+            "DELETE FROM SHARE_DOWNLOADS WHERE COUNTED_AT < datetime('now', '-1 day')
+            OR SHARE_ID NOT IN (SELECT ID FROM SHARED)",
+            params![],
+        )?;
+        conn.execute(
+            "DELETE FROM SHARE_ACCESS_LOG WHERE ACCESSED_AT < datetime('now', ?)
+            OR SHARE_ID NOT IN (SELECT ID FROM SHARED)",
+            params![format!("-{} days", max_age_days)],
+        )
+    }
+
+    /// Removes shares that expired more than `grace_days` ago, until then they are reported as gone
+    pub fn delete_expired_shares(&self, grace_days: u32) -> rusqlite::Result<usize> {
+        let conn = self.conn();
+        conn.execute(
+            "DELETE FROM SHARED WHERE EXPIRES_AT IS NOT NULL AND EXPIRES_AT < datetime('now', ?)",
+            params![format!("-{} days", grace_days)],
+        )
+    }
+
+    /// Adds `bytes` to the uploaded bytes of the share, if the upload limit allows it.
+    /// Returns false if the share has no upload limit (uploads disabled) or it would be exceeded
+    pub fn reserve_share_upload(&self, share_id: &SharedID, bytes: u64) -> rusqlite::Result<bool> {
+        let conn = self.conn();
+        conn.execute(
+            "UPDATE SHARED SET UPLOADED_BYTES = UPLOADED_BYTES + ?1
+            WHERE ID = ?2 AND UPLOAD_LIMIT IS NOT NULL AND UPLOADED_BYTES + ?1 <= UPLOAD_LIMIT * 1024 * 1024",
+            params![bytes as i64, share_id.as_ref()],
+        )
+        .map(|updated| updated == 1)
+    }
+
+    /// Gives back bytes reserved by `reserve_share_upload` that were not uploaded
+    pub fn release_share_upload(&self, share_id: &SharedID, bytes: u64) -> rusqlite::Result<()> {
+        let conn = self.conn();
+        conn.execute(
+            "UPDATE SHARED SET UPLOADED_BYTES = MAX(UPLOADED_BYTES - ?, 0) WHERE ID = ?",
+            params![bytes as i64, share_id.as_ref()],
+        )?;
+        Ok(())
+    }
+
     #[allow(dead_code)]
     pub fn is_active_shared_id(&self, id: &str) -> bool {
         let conn = self.conn();
         // returns 1 if id exists in table
         conn.query_row::<u32, _, _>(
-            "SELECT EXISTS(SELECT ID FROM SHARED WHERE ID = ?)",
+            "SELECT EXISTS(SELECT ID FROM SHARED WHERE ID = ? AND TRASH_ID IS NULL)",
             &[id],
             |r| r.get(0),
         )
@@ -106,33 +602,472 @@ impl SharedDatabase {
     pub fn get_all_shared(&self, user_id: &UserID) -> Vec<crate::fs::shared::SharedEntry> {
         let conn = self.conn();
         let mut prep = conn
-            .prepare("SELECT ID, BASE_PATH FROM SHARED WHERE USER = ?")
+            .prepare(&format!("SELECT {} FROM SHARED WHERE USER = ? AND TRASH_ID IS NULL", SHARED_ENTRY_COLUMNS))
             .unwrap();
 
-        prep.query_map(params![&user_id.0], |r| {
-            Ok((r.get(0), r.get::<_, String>(1)))
-        })
-        .unwrap()
-        .filter_map(|r| match r {
-            Ok((Ok(id), Ok(path))) => Some(crate::fs::shared::SharedEntry {
-                path: PathBuf::from(path),
-                user: user_id.clone(),
-                share_id: SharedID::from_string_unchecked(id),
-            }),
-            _ => None,
+        prep.query_map(params![&user_id.0], shared_entry_from_row)
+            .unwrap()
+            .filter_map(|r| r.map_err(|e| warn!("{:?}", e)).ok())
+            .collect()
+    }
+
+    /// Updates the base path of all shares at or below `from` after it was moved to `to`
+    pub fn move_shares(&self, user_id: &UserID, from: &Path, to: &Path) -> rusqlite::Result<usize> {
+        let moved: Vec<(SharedID, PathBuf)> = self
+            .get_all_shared(user_id)
+            .into_iter()
+            .filter_map(|se| {
+                let rest = se.path.strip_prefix(from).ok()?;
+                Some((se.share_id, to.join(rest)))
+            })
+            .collect();
+
+        let conn = self.conn();
+        for (id, path) in &moved {
+            conn.execute(
+                "UPDATE SHARED SET BASE_PATH = ? WHERE ID = ?",
+                params![crate::fs::netfilepath::NetFilePath::from_path(path).as_str(), id.as_ref()],
+            )?;
+        }
+        Ok(moved.len())
+    }
+
+    /// `path` is relative to the users root
+    pub fn add_version(&self, id: &str, user_id: &UserID, path: &Path, size: u64) -> rusqlite::Result<()> {
+        let conn = self.conn();
+        conn.execute(
+            "INSERT INTO VERSIONS (ID, USER, PATH, SIZE, CREATED_AT) VALUES (?, ?, ?, ?, datetime('now'))",
+            params![id, &user_id.0, crate::fs::netfilepath::NetFilePath::from_path(path).as_str(), size],
+        )?;
+        Ok(())
+    }
+
+    /// Versions of the file at `path`, newest first
+    pub fn get_versions(&self, user_id: &UserID, path: &Path) -> rusqlite::Result<Vec<DBVersion>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT ID, USER, PATH, SIZE, CREATED_AT FROM VERSIONS WHERE USER = ? AND PATH = ? ORDER BY CREATED_AT DESC, rowid DESC",
+        )?;
+        let versions = stmt
+            .query_map(
+                params![&user_id.0, crate::fs::netfilepath::NetFilePath::from_path(path).as_str()],
+                version_from_row,
+            )?
+            .collect();
+        versions
+    }
+
+    /// Versions of the file at `path` older than `max_age_days`
+    pub fn get_expired_versions(
+        &self,
+        user_id: &UserID,
+        path: &Path,
+        max_age_days: u32,
+    ) -> rusqlite::Result<Vec<DBVersion>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT ID, USER, PATH, SIZE, CREATED_AT FROM VERSIONS WHERE USER = ? AND PATH = ? AND CREATED_AT < datetime('now', ?)",
+        )?;
+        let versions = stmt
+            .query_map(
+                params![
+                    &user_id.0,
+                    crate::fs::netfilepath::NetFilePath::from_path(path).as_str(),
+                    format!("-{} days", max_age_days)
+                ],
+                version_from_row,
+            )?
+            .collect();
+        versions
+    }
+
+    pub fn get_version(&self, id: &str, user_id: &UserID) -> Option<DBVersion> {
+        use rusqlite::OptionalExtension;
+        let conn = self.conn();
+        conn.query_row(
+            "SELECT ID, USER, PATH, SIZE, CREATED_AT FROM VERSIONS WHERE ID = ? AND USER = ?",
+            params![id, &user_id.0],
+            version_from_row,
+        )
+        .optional()
+        .map_err(|e| error!("Failed to load version {}: {:?}", id, e))
+        .ok()
+        .flatten()
+    }
+
+    pub fn delete_version(&self, id: &str) -> rusqlite::Result<()> {
+        let conn = self.conn();
+        conn.execute("DELETE FROM VERSIONS WHERE ID = ?", params![id])?;
+        Ok(())
+    }
+
+    /// Versions of files at or below `path`
+    pub fn get_versions_below(&self, user_id: &UserID, path: &Path) -> rusqlite::Result<Vec<DBVersion>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT ID, USER, PATH, SIZE, CREATED_AT FROM VERSIONS WHERE USER = ?")?;
+        let versions: rusqlite::Result<Vec<DBVersion>> =
+            stmt.query_map(params![&user_id.0], version_from_row)?.collect();
+        Ok(versions?.into_iter().filter(|v| v.path.starts_with(path)).collect())
+    }
+
+    /// Updates the path of all versions at or below `from`, like `move_shares`
+    pub fn move_versions(&self, user_id: &UserID, from: &Path, to: &Path) -> rusqlite::Result<usize> {
+        let moved: Vec<(String, PathBuf)> = self
+            .get_versions_below(user_id, from)?
+            .into_iter()
+            .filter_map(|v| {
+                let rest = v.path.strip_prefix(from).ok()?;
+                Some((v.id, to.join(rest)))
+            })
+            .collect();
+
+        let conn = self.conn();
+        for (id, path) in &moved {
+            conn.execute(
+                "UPDATE VERSIONS SET PATH = ? WHERE ID = ?",
+                params![crate::fs::netfilepath::NetFilePath::from_path(path).as_str(), id],
+            )?;
+        }
+        Ok(moved.len())
+    }
+
+    /// Cached recursive size of the folder, `path` like `fs::sizes::key`
+    pub fn get_folder_size(&self, user_id: &UserID, path: &str) -> rusqlite::Result<Option<u64>> {
+        let conn = self.conn();
+        conn.query_row(
+            "SELECT SIZE FROM FOLDER_SIZES WHERE USER = ? AND PATH = ?",
+            params![&user_id.0, path],
+            |row| row.get::<_, i64>(0),
+        )
+        .map(|size| Some(size as u64))
+        .or_else(|e| match e {
+            rusqlite::Error::QueryReturnedNoRows => Ok(None),
+            e => Err(e),
         })
-        .collect()
+    }
+
+    /// Cached sizes of the folders directly inside of `parent`, as (name, size)
+    pub fn get_child_folder_sizes(&self, user_id: &UserID, parent: &str) -> rusqlite::Result<Vec<(String, u64)>> {
+        let conn = self.conn();
+        let prefix = if parent.is_empty() { String::new() } else { format!("{}/", parent) };
+        let mut stmt = conn.prepare(
+            "SELECT substr(PATH, length(?2) + 1), SIZE FROM FOLDER_SIZES
+            WHERE USER = ?1 AND PATH != ?3 AND substr(PATH, 1, length(?2)) = ?2 AND instr(substr(PATH, length(?2) + 1), '/') = 0",
+        )?;
+        let sizes = stmt.query_map(params![&user_id.0, &prefix, parent], |row| {
+            Ok((row.get(0)?, row.get::<_, i64>(1)? as u64))
+        })?;
+        sizes.collect()
+    }
+
+    pub fn set_folder_size(&self, user_id: &UserID, path: &str, size: u64) -> rusqlite::Result<()> {
+        let conn = self.conn();
+        conn.execute(
+            "INSERT OR REPLACE INTO FOLDER_SIZES (USER, PATH, SIZE) VALUES (?, ?, ?)",
+            params![&user_id.0, path, size as i64],
+        )?;
+        Ok(())
+    }
+
+    /// Adds `delta` to the cached sizes of `paths`, folders that are not cached are skipped
+    pub fn change_folder_sizes(&self, user_id: &UserID, paths: &[String], delta: i64) -> rusqlite::Result<()> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        for path in paths {
+            tx.execute(
+                "UPDATE FOLDER_SIZES SET SIZE = MAX(SIZE + ?, 0) WHERE USER = ? AND PATH = ?",
+                params![delta, &user_id.0, path],
+            )?;
+        }
+        tx.commit()
+    }
+
+    /// Forgets the sizes of the folder at `path` and all folders below it
+    pub fn delete_folder_sizes_below(&self, user_id: &UserID, path: &str) -> rusqlite::Result<usize> {
+        let conn = self.conn();
+        if path.is_empty() {
+            return conn.execute("DELETE FROM FOLDER_SIZES WHERE USER = ?", params![&user_id.0]);
+        }
+        conn.execute(
+            "DELETE FROM FOLDER_SIZES WHERE USER = ?1 AND (PATH = ?2 OR substr(PATH, 1, length(?2) + 1) = ?2 || '/')",
+            params![&user_id.0, path],
+        )
+    }
+
+    /// Replaces all cached sizes of the user, e.g. after a scan of the files
+    pub fn replace_folder_sizes(&self, user_id: &UserID, sizes: &[(String, u64)]) -> rusqlite::Result<()> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM FOLDER_SIZES WHERE USER = ?", params![&user_id.0])?;
+        {
+            let mut stmt = tx.prepare("INSERT INTO FOLDER_SIZES (USER, PATH, SIZE) VALUES (?, ?, ?)")?;
+            for (path, size) in sizes {
+                stmt.execute(params![&user_id.0, path, *size as i64])?;
+            }
+        }
+        tx.commit()
+    }
+
+    /// Adds the entries to the search index, replaces entries with the same path
+    pub fn upsert_index_entries(&self, user_id: &UserID, entries: &[DBIndexEntry]) -> rusqlite::Result<()> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        insert_index_entries(&tx, user_id, entries)?;
+        tx.commit()
+    }
+
+    /// Removes the node at `path` and all nodes below it from the search index
+    pub fn delete_index_below(&self, user_id: &UserID, path: &str) -> rusqlite::Result<usize> {
+        let conn = self.conn();
+        if path.is_empty() {
+            return conn.execute("DELETE FROM FILE_INDEX WHERE USER = ?", params![&user_id.0]);
+        }
+        conn.execute(
+            "DELETE FROM FILE_INDEX WHERE USER = ?1 AND (PATH = ?2 OR substr(PATH, 1, length(?2) + 1) = ?2 || '/')",
+            params![&user_id.0, path],
+        )
+    }
+
+    /// Replaces the search index of the user, e.g. after a scan of the files
+    pub fn replace_index(&self, user_id: &UserID, entries: &[DBIndexEntry]) -> rusqlite::Result<()> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM FILE_INDEX WHERE USER = ?", params![&user_id.0])?;
+        insert_index_entries(&tx, user_id, entries)?;
+        tx.commit()
+    }
+
+    /// Nodes below `scope` (`""` for the whole root) matching `filter`, ordered by path.
+    /// Starts after the path `after`, returns at most `limit` entries
+    pub fn search_index(
+        &self,
+        user_id: &UserID,
+        scope: &str,
+        filter: &SearchFilter,
+        after: Option<&str>,
+        limit: usize,
+    ) -> rusqlite::Result<Vec<DBIndexEntry>> {
+        let mut sql = String::from("SELECT PATH, IS_FOLDER, SIZE, MODIFIED FROM FILE_INDEX WHERE USER = ?");
+        let mut values: Vec<Box<dyn ToSql>> = vec![Box::new(user_id.0.clone())];
+        if !scope.is_empty() {
+            // all paths starting with "scope/", '0' follows '/'
+            sql.push_str(" AND PATH > ? AND PATH < ?");
+            values.push(Box::new(format!("{}/", scope)));
+            values.push(Box::new(format!("{}0", scope)));
+        }
+        if let Some(after) = after {
+            sql.push_str(" AND PATH > ?");
+            values.push(Box::new(after.to_owned()));
+        }
+        match &filter.name {
+            Some(NamePattern::Substring(part)) => {
+                sql.push_str(" AND instr(NAME, ?) > 0");
+                values.push(Box::new(part.clone()));
+            }
+            Some(NamePattern::Glob(glob)) => {
+                sql.push_str(" AND NAME GLOB ?");
+                values.push(Box::new(glob.clone()));
+            }
+            None => {}
+        }
+        if let Some(is_folder) = filter.is_folder {
+            sql.push_str(" AND IS_FOLDER = ?");
+            values.push(Box::new(is_folder));
+        }
+        if !filter.extensions.is_empty() {
+            sql.push_str(&format!(" AND EXTENSION IN ({})", vec!["?"; filter.extensions.len()].join(", ")));
+            for ext in &filter.extensions {
+                values.push(Box::new(ext.clone()));
+            }
+        }
+        // folders have no size in the index
+        if let Some(min) = filter.min_size {
+            sql.push_str(" AND IS_FOLDER = 0 AND SIZE >= ?");
+            values.push(Box::new(min as i64));
+        }
+        if let Some(max) = filter.max_size {
+            sql.push_str(" AND IS_FOLDER = 0 AND SIZE <= ?");
+            values.push(Box::new(max as i64));
+        }
+        if let Some(after) = filter.modified_after {
+            sql.push_str(" AND MODIFIED >= ?");
+            values.push(Box::new(after));
+        }
+        if let Some(before) = filter.modified_before {
+            sql.push_str(" AND MODIFIED < ?");
+            values.push(Box::new(before));
+        }
+        sql.push_str(" ORDER BY PATH LIMIT ?");
+        values.push(Box::new(limit as i64));
+
+        let conn = self.conn();
+        let mut stmt = conn.prepare(&sql)?;
+        let entries = stmt.query_map(rusqlite::params_from_iter(values.iter()), index_entry_from_row)?;
+        entries.collect()
+    }
+
+    /// Shares `path` of `owner` with `target`, updates `writable` if it is shared already.
+    /// Returns the id of the share
+    pub fn upsert_user_share(
+        &self,
+        owner: &UserID,
+        path: &Path,
+        target: &UserID,
+        writable: bool,
+    ) -> rusqlite::Result<String> {
+        let id: String = crate::utils::get_rand_token::<16>()
+            .iter()
+            .map(|e| *e as char)
+            .collect();
+        let path = crate::fs::netfilepath::NetFilePath::from_path(path);
+        let conn = self.conn();
+        conn.execute(
+            "INSERT INTO USER_SHARES (ID, OWNER, PATH, TARGET_USER, WRITABLE, CREATED_AT) VALUES (?, ?, ?, ?, ?, datetime('now'))
+            ON CONFLICT(OWNER, PATH, TARGET_USER) WHERE TRASH_ID IS NULL DO UPDATE SET WRITABLE = excluded.WRITABLE",
+            params![id, &owner.0, path.as_str(), &target.0, writable],
+        )?;
+        conn.query_row(
+            "SELECT ID FROM USER_SHARES WHERE OWNER = ? AND PATH = ? AND TARGET_USER = ? AND TRASH_ID IS NULL",
+            params![&owner.0, path.as_str(), &target.0],
+            |r| r.get(0),
+        )
+    }
+
+    /// Shares the user created
+    pub fn get_outgoing_user_shares(&self, owner: &UserID) -> rusqlite::Result<Vec<DBUserShare>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(&format!("{} AND S.OWNER = ? ORDER BY S.PATH", USER_SHARE_SELECT))?;
+        let shares = stmt.query_map(params![&owner.0], user_share_from_row)?.collect();
+        shares
+    }
+
+    /// Shares with the user, oldest first
+    pub fn get_incoming_user_shares(&self, target: &UserID) -> rusqlite::Result<Vec<DBUserShare>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(&format!(
+            "{} AND S.TARGET_USER = ? ORDER BY S.CREATED_AT, S.ID",
+            USER_SHARE_SELECT
+        ))?;
+        let shares = stmt.query_map(params![&target.0], user_share_from_row)?.collect();
+        shares
+    }
+
+    pub fn get_user_share(&self, id: &str) -> Option<DBUserShare> {
+        use rusqlite::OptionalExtension;
+        let conn = self.conn();
+        conn.query_row(
+            &format!("{} AND S.ID = ?", USER_SHARE_SELECT),
+            params![id],
+            user_share_from_row,
+        )
+        .optional()
+        .map_err(|e| error!("Failed to load user share {}: {:?}", id, e))
+        .ok()
+        .flatten()
+    }
+
+    pub fn delete_user_share(&self, id: &str) -> rusqlite::Result<()> {
+        let conn = self.conn();
+        conn.execute("DELETE FROM USER_SHARES WHERE ID = ?", params![id])?;
+        Ok(())
+    }
+
+    /// Updates the path of all user shares at or below `from`, like `move_shares`
+    pub fn move_user_shares(&self, owner: &UserID, from: &Path, to: &Path) -> rusqlite::Result<usize> {
+        let moved: Vec<(String, PathBuf)> = self
+            .get_outgoing_user_shares(owner)?
+            .into_iter()
+            .filter_map(|s| {
+                let rest = s.path.strip_prefix(from).ok()?;
+                Some((s.id, to.join(rest)))
+            })
+            .collect();
+
+        let conn = self.conn();
+        for (id, path) in &moved {
+            conn.execute(
+                "UPDATE USER_SHARES SET PATH = ? WHERE ID = ?",
+                params![crate::fs::netfilepath::NetFilePath::from_path(path).as_str(), id],
+            )?;
+        }
+        Ok(moved.len())
+    }
+
+    /// Disables all link and user shares at or below `path` of the user, because it was moved to the trash
+    /// as `trash_id`. They are kept until the trash item gets restored or purged
+    pub fn trash_shares_below(&self, user_id: &UserID, path: &Path, trash_id: &str) -> rusqlite::Result<usize> {
+        let link_ids: Vec<SharedID> = self
+            .get_all_shared(user_id)
+            .into_iter()
+            .filter(|se| se.path.starts_with(path))
+            .map(|se| se.share_id)
+            .collect();
+        let user_share_ids: Vec<String> = self
+            .get_outgoing_user_shares(user_id)?
+            .into_iter()
+            .filter(|s| s.path.starts_with(path))
+            .map(|s| s.id)
+            .collect();
+
+        let conn = self.conn();
+        for id in &link_ids {
+            conn.execute("UPDATE SHARED SET TRASH_ID = ? WHERE ID = ?", params![trash_id, id.as_ref()])?;
+        }
+        for id in &user_share_ids {
+            conn.execute("UPDATE USER_SHARES SET TRASH_ID = ? WHERE ID = ?", params![trash_id, id])?;
+        }
+        Ok(link_ids.len() + user_share_ids.len())
+    }
+
+    /// Enables the shares disabled by `trash_shares_below` again, `from` is the original path of the trash item
+    /// and `to` the path it was restored to
+    pub fn restore_trashed_shares(&self, trash_id: &str, from: &Path, to: &Path) -> rusqlite::Result<usize> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        let mut restored = 0;
+        for table in ["SHARED", "USER_SHARES"] {
+            let path_column = if table == "SHARED" { "BASE_PATH" } else { "PATH" };
+            let rows: Vec<(String, String)> = {
+                let mut stmt = tx.prepare(&format!("SELECT ID, {} FROM {} WHERE TRASH_ID = ?", path_column, table))?;
+                let rows = stmt.query_map(params![trash_id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+                rows.collect::<rusqlite::Result<_>>()?
+            };
+            for (id, path) in rows {
+                let path = match Path::new(&path).strip_prefix(from) {
+                    Ok(rest) => to.join(rest),
+                    Err(_) => PathBuf::from(path),
+                };
+                tx.execute(
+                    &format!("UPDATE {} SET {} = ?, TRASH_ID = NULL WHERE ID = ?", table, path_column),
+                    params![crate::fs::netfilepath::NetFilePath::from_path(path).as_str(), id],
+                )?;
+                restored += 1;
+            }
+        }
+        tx.commit()?;
+        Ok(restored)
+    }
+
+    /// Removes the shares disabled by `trash_shares_below`, because the trash item got purged
+    pub fn delete_trashed_shares(&self, trash_id: &str) -> rusqlite::Result<usize> {
+        let conn = self.conn();
+        let links = conn.execute("DELETE FROM SHARED WHERE TRASH_ID = ?", params![trash_id])?;
+        let user_shares = conn.execute("DELETE FROM USER_SHARES WHERE TRASH_ID = ?", params![trash_id])?;
+        Ok(links + user_shares)
     }
 
     /// if enabled, returns the share id
     /// expects path to be valid
-    /// `upload_limit` in megabytes
+    /// `upload_limit` in megabytes, `expires_at` as `YYYY-MM-DD HH:MM:SS` (UTC)
     pub fn update_share(
         &self,
         user_id: &UserID,
         path: &std::path::Path,
         enabled: bool,
         mut upload_limit: Option<u32>,
+        expires_at: Option<String>,
+        mut max_downloads: Option<u32>,
+        permissions: SharePermissions,
     ) -> Option<SharedID> {
         let conn = self.conn();
         let path_str = path.to_str().unwrap();
@@ -142,7 +1077,7 @@ impl SharedDatabase {
             // first check if share allready exists
             shared_id = match conn
                 .query_row(
-                    "SELECT ID FROM SHARED WHERE USER = ? AND BASE_PATH = ?",
+                    "SELECT ID FROM SHARED WHERE USER = ? AND BASE_PATH = ? AND TRASH_ID IS NULL",
                     params![&user_id.0, path_str],
                     |row| row.get(0).map(|s| SharedID::from_string_unchecked(s)),
                 )
@@ -182,6 +1117,9 @@ impl SharedDatabase {
             if let Some(0) = upload_limit {
                 upload_limit = None;
             }
+            if let Some(0) = max_downloads {
+                max_downloads = None;
+            }
 
             dbg!(&shared_id);
 
@@ -194,13 +1132,20 @@ impl SharedDatabase {
                 let upload_limit: Box<dyn ToSql> = upload_limit
                     .map(|ul| Box::new(ul) as Box<dyn ToSql>)
                     .unwrap_or_else(|| Box::new(rusqlite::types::Null));
+                // keep CREATED_AT, UPLOADED_BYTES and DOWNLOADS if the share exists already
                 conn.execute(
-                    "INSERT OR REPLACE INTO SHARED (ID, USER, BASE_PATH, CREATED_AT, UPLOAD_LIMIT) VALUES (?, ?, ?, datetime('now'), ?)", 
-                    params![id.as_ref(), &user_id.0, path_str, upload_limit]).map_err(|e| error!("{:?}", e)).ok()?;
+                    "INSERT INTO SHARED (ID, USER, BASE_PATH, CREATED_AT, UPLOAD_LIMIT, EXPIRES_AT, MAX_DOWNLOADS, FLAGS)
+                    VALUES (?, ?, ?, datetime('now'), ?, ?, ?, ?)
+                    ON CONFLICT(ID) DO UPDATE SET UPLOAD_LIMIT = excluded.UPLOAD_LIMIT,
+                        EXPIRES_AT = excluded.EXPIRES_AT, MAX_DOWNLOADS = excluded.MAX_DOWNLOADS, FLAGS = excluded.FLAGS", 
+                    params![id.as_ref(), &user_id.0, path_str, upload_limit, expires_at, max_downloads, permissions.0]).map_err(|e| error!("{:?}", e)).ok()?;
                 Some(id)
             }
             None => {
-                conn.execute("DELETE FROM SHARED WHERE BASE_PATH = ?", params![path_str])
+                conn.execute(
+                    "DELETE FROM SHARED WHERE USER = ? AND BASE_PATH = ? AND TRASH_ID IS NULL",
+                    params![&user_id.0, path_str],
+                )
                     .ok();
                 None
             }
@@ -208,30 +1153,45 @@ impl SharedDatabase {
     }
 }
 
+/// Ids are 8 alphanumeric characters, see `utils::get_rand_token`
 impl TryFrom<String> for UserID {
     type Error = ();
 
     fn try_from(value: String) -> Result<Self, Self::Error> {
-        if value.len() != 8 {
+        if value.len() != 8 || !value.chars().all(|c| c.is_ascii_alphanumeric()) {
             return Err(());
         }
         Ok(UserID(value))
     }
 }
 
-#[allow(dead_code)]
 pub enum GetUserQuery<'a> {
     ByName(&'a str),
     ByID(&'a UserID),
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+/// Ordered by privileges, Guest < User < Admin
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, PartialOrd)]
 pub enum UserRoll {
     Guest = 0,
     User = 1,
     Admin = 2,
 }
 
+/// Case insensitive, e.g. for the command line
+impl std::str::FromStr for UserRoll {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "guest" => Ok(UserRoll::Guest),
+            "user" => Ok(UserRoll::User),
+            "admin" => Ok(UserRoll::Admin),
+            _ => Err(()),
+        }
+    }
+}
+
 impl TryFrom<u32> for UserRoll {
     type Error = ();
 
@@ -251,6 +1211,224 @@ pub struct DBUser {
     pub id: UserID,
     pub hashed_pw: String,
     pub roll: UserRoll,
+    pub disabled: bool,
+    /// shown instead of the name if set
+    pub display_name: Option<String>,
+    pub settings: Option<String>,
+    /// None is unlimited
+    pub quota_bytes: Option<u64>,
+    /// None until `fs::quota::init_usage` calculated it
+    pub used_bytes: Option<u64>,
+}
+
+impl DBUser {
+    pub fn shown_name(&self) -> &str {
+        self.display_name.as_deref().unwrap_or(&self.name)
+    }
+}
+
+/// Columns read by `user_from_row`
+const USER_COLUMNS: &str = "ID, NAME, PASSWORD_HASH, ROLLS, DISABLED, DISPLAY_NAME, SETTINGS, QUOTA_BYTES, USED_BYTES";
+
+/// Columns read by `shared_entry_from_row`
+fn download_counted(
+    conn: &rusqlite::Connection,
+    share_id: &SharedID,
+    path: &str,
+    ip: Option<&str>,
+    continue_minutes: u32,
+) -> rusqlite::Result<bool> {
+    let ip = match ip {
+        Some(ip) => ip,
+        None => return Ok(false),
+    };
+    conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM SHARE_DOWNLOADS
+            WHERE SHARE_ID = ? AND IP = ? AND PATH = ? AND COUNTED_AT > datetime('now', ?))",
+        params![share_id.as_ref(), ip, path, format!("-{} minutes", continue_minutes)],
+        |row| row.get(0),
+    )
+}
+
+const SHARED_ENTRY_COLUMNS: &str = "ID, USER, BASE_PATH, UPLOAD_LIMIT, UPLOADED_BYTES, EXPIRES_AT, MAX_DOWNLOADS, DOWNLOADS,
+    (EXPIRES_AT IS NOT NULL AND EXPIRES_AT <= datetime('now')) OR DOWNLOADS >= MAX_DOWNLOADS, PASSWORD_HASH, FLAGS";
+
+fn shared_entry_from_row(row: &Row) -> Result<SharedEntry, rusqlite::Error> {
+    let password_hash: Option<String> = row.get(9)?;
+    Ok(SharedEntry {
+        share_id: SharedID::from_string_unchecked(row.get(0)?),
+        user: UserID(row.get(1)?),
+        path: PathBuf::from(row.get::<_, String>(2)?),
+        upload_limit: row.get(3)?,
+        uploaded_bytes: row.get::<_, i64>(4)? as u64,
+        expires_at: row.get(5)?,
+        max_downloads: row.get(6)?,
+        downloads: row.get(7)?,
+        expired: row.get::<_, Option<bool>>(8)?.unwrap_or(false),
+        password_protected: password_hash.is_some(),
+        password_hash,
+        permissions: row
+            .get::<_, Option<u32>>(10)?
+            .map(SharePermissions)
+            .unwrap_or_default(),
+    })
+}
+
+/// Resumable upload, `target_path` is relative to the users root
+#[derive(Debug)]
+pub struct DBUpload {
+    pub id: String,
+    pub user: UserID,
+    pub target_path: PathBuf,
+    pub length: u64,
+    pub offset: u64,
+    pub metadata: Option<String>,
+}
+
+/// Deleted node, stored in `trash::trash_dir()` under its id.
+/// `original_path` is relative to the users root
+#[derive(Debug)]
+pub struct DBTrashItem {
+    pub id: String,
+    pub user: UserID,
+    pub original_path: PathBuf,
+    pub is_folder: bool,
+    pub deleted_at: String,
+}
+
+fn trash_item_from_row(row: &Row) -> Result<DBTrashItem, rusqlite::Error> {
+    Ok(DBTrashItem {
+        id: row.get(0)?,
+        user: UserID(row.get(1)?),
+        original_path: PathBuf::from(row.get::<_, String>(2)?),
+        is_folder: row.get(3)?,
+        deleted_at: row.get(4)?,
+    })
+}
+
+/// Previous content of the file at `path` (relative to the users root),
+/// stored in `versions::versions_dir()` under its id
+#[derive(Debug)]
+pub struct DBVersion {
+    pub id: String,
+    pub user: UserID,
+    pub path: PathBuf,
+    pub size: u64,
+    pub created_at: String,
+}
+
+fn version_from_row(row: &Row) -> Result<DBVersion, rusqlite::Error> {
+    Ok(DBVersion {
+        id: row.get(0)?,
+        user: UserID(row.get(1)?),
+        path: PathBuf::from(row.get::<_, String>(2)?),
+        size: row.get(3)?,
+        created_at: row.get(4)?,
+    })
+}
+
+/// Node in the search index, `path` is relative to the users root like `fs::sizes::key`
+#[derive(Debug, Clone, PartialEq)]
+pub struct DBIndexEntry {
+    pub path: String,
+    pub is_folder: bool,
+    /// 0 for folders
+    pub size: u64,
+    /// unix timestamp
+    pub modified: i64,
+}
+
+fn index_entry_from_row(row: &Row) -> Result<DBIndexEntry, rusqlite::Error> {
+    Ok(DBIndexEntry {
+        path: row.get(0)?,
+        is_folder: row.get(1)?,
+        size: row.get::<_, i64>(2)? as u64,
+        modified: row.get(3)?,
+    })
+}
+
+/// Stores the lowercase name and extension next to the entry, they are what the search matches on
+fn insert_index_entries(tx: &rusqlite::Transaction, user_id: &UserID, entries: &[DBIndexEntry]) -> rusqlite::Result<()> {
+    let mut stmt = tx.prepare(
+        "INSERT OR REPLACE INTO FILE_INDEX (USER, PATH, NAME, IS_FOLDER, EXTENSION, SIZE, MODIFIED) VALUES (?, ?, ?, ?, ?, ?, ?)",
+    )?;
+    for entry in entries {
+        let name = entry.path.rsplit('/').next().unwrap_or_default();
+        let extension = match entry.is_folder {
+            true => None,
+            false => Path::new(name).extension().map(|ext| ext.to_string_lossy().to_lowercase()),
+        };
+        stmt.execute(params![
+            &user_id.0,
+            &entry.path,
+            name.to_lowercase(),
+            entry.is_folder,
+            extension,
+            entry.size as i64,
+            entry.modified
+        ])?;
+    }
+    Ok(())
+}
+
+/// Folder `path` (relative to the owners root) shared with another registered user
+#[derive(Debug)]
+pub struct DBUserShare {
+    pub id: String,
+    pub owner: UserID,
+    pub owner_name: String,
+    pub path: PathBuf,
+    pub target: UserID,
+    pub target_name: String,
+    pub writable: bool,
+    pub created_at: String,
+}
+
+/// Only shares of existing nodes, see `trash_shares_below`
+const USER_SHARE_SELECT: &str =
+    "SELECT S.ID, S.OWNER, O.NAME, S.PATH, S.TARGET_USER, T.NAME, S.WRITABLE, S.CREATED_AT FROM USER_SHARES S
+    JOIN USERS O ON O.ID = S.OWNER JOIN USERS T ON T.ID = S.TARGET_USER WHERE S.TRASH_ID IS NULL";
+
+fn user_share_from_row(row: &Row) -> Result<DBUserShare, rusqlite::Error> {
+    Ok(DBUserShare {
+        id: row.get(0)?,
+        owner: UserID(row.get(1)?),
+        owner_name: row.get(2)?,
+        path: PathBuf::from(row.get::<_, String>(3)?),
+        target: UserID(row.get(4)?),
+        target_name: row.get(5)?,
+        writable: row.get(6)?,
+        created_at: row.get(7)?,
+    })
+}
+
+/// Entry of the share access log, `path` is relative to the share
+#[derive(Debug)]
+pub struct DBShareAccess {
+    pub accessed_at: String,
+    pub kind: String,
+    pub path: String,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub bytes: Option<u64>,
+}
+
+#[derive(Debug)]
+pub struct DBShareAccessCount {
+    pub kind: String,
+    pub count: u64,
+    pub bytes: u64,
+    pub last_access: String,
+}
+
+/// Timestamps as stored by sqlite (`YYYY-MM-DD HH:MM:SS`, UTC)
+#[derive(Debug)]
+pub struct DBSession {
+    pub id: String,
+    pub user: UserID,
+    pub created_at: String,
+    pub last_seen: String,
+    pub expires_at: String,
 }
 
 fn user_from_row(row: &Row) -> Result<DBUser, rusqlite::Error> {
@@ -267,11 +1445,17 @@ fn user_from_row(row: &Row) -> Result<DBUser, rusqlite::Error> {
         .ok()
         .flatten()
         .unwrap_or(UserRoll::Guest);
-    trace!("user from row: {:?} {} {} {:?}", id, name, hashed_pw, roll);
+    let disabled = row.get(4)?;
+    trace!("user from row: {:?} {} {:?}", id, name, roll);
     Ok(DBUser {
         id,
         name,
         hashed_pw,
         roll,
+        disabled,
+        display_name: row.get(5)?,
+        settings: row.get(6)?,
+        quota_bytes: row.get::<_, Option<i64>>(7)?.map(|q| q as u64),
+        used_bytes: row.get::<_, Option<i64>>(8)?.map(|u| u as u64),
     })
 }
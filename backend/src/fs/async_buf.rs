@@ -120,15 +120,20 @@ impl rocket::tokio::io::AsyncRead for AsyncConsumer {
             // store the waker first, otherwise it may freeze?
             self.produced_waker.store(Some(ctx.waker().clone()));
             self.consumed.unpark();
-            return Poll::Pending;
+
+            // the producer could have written between the check above and storing the waker,
+            // so check again before sleeping
+            if self.inner.is_empty() && self.producer_alive() {
+                return Poll::Pending;
+            }
         }
 
         if self.inner.is_empty() {
             return Poll::Ready(Ok(()));
         }
 
-        // we are the only holder of this Arc -> Producer was dropped
-        let read = self.inner.read(buf.)?;
+        let read = self.inner.read(buf.initialize_unfilled())?;
+        buf.advance(read);
 
         // wake producer
         self.consumed.unpark();
@@ -178,7 +183,7 @@ mod test {
                 prod.write_all(&mut wbuf).expect("write failed");
             }
         });
-        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let rt = tokio::runtime::Runtime::new().unwrap();
         rt.block_on(async move {
             loop {
                 eprintln!("waiting for data");
@@ -1,7 +1,8 @@
 use crate::database::SharedDatabase;
-use crate::fs::{to_abs_data_path, /* zipwriter,*/ NetFilePath, UserID};
-use log::warn;
-use rocket::{Request, State};
+use crate::fs::{contained_data_path, zipwriter, NetFilePath, UserID};
+use log::{error, warn};
+use rocket::{Request, Response, State};
+use rocket::http::ContentType;
 use rocket::fs::NamedFile;
 use rocket::response::Responder;
 use rocket::request::FromRequest;
@@ -9,7 +10,10 @@ use std::borrow::Borrow;
 use std::path::Path;
 use regex::Regex;
 
+use super::async_buf::AsyncConsumer;
 use super::partial_file::PartialFile;
+use super::shared::{self, ShareAccessKind, ShareClient, ShareDenied, ShareUnlockToken};
+use super::user_shares::{self, Access, ResolvedPath};
 
 #[derive(Responder)]
 pub enum FileDownloadResponse {
@@ -17,15 +21,39 @@ pub enum FileDownloadResponse {
     File(RangeAcceptingFile),
     #[response(status = 206)]
     PartialFile(PartialFile),
-    /* #[response(status = 200)]
-    Zip(ByteStream), */
+    #[response(status = 200)]
+    Zip(ZipDownload),
     #[response(status = 401)]
     Unauthorized(()),
+    #[response(status = 403)]
+    Forbidden(&'static str),
     #[response(status = 404)]
     NotFound(()),
+    #[response(status = 410)]
+    Gone(&'static str),
+    #[response(status = 503)]
+    Unavailable(()),
 }
 
 
+impl FileDownloadResponse {
+    fn is_content(&self) -> bool {
+        matches!(
+            self,
+            FileDownloadResponse::File(_) | FileDownloadResponse::PartialFile(_) | FileDownloadResponse::Zip(_)
+        )
+    }
+
+    /// Bytes of the body, None if unknown (zip streams) or no content
+    async fn body_size(&self) -> Option<u64> {
+        match self {
+            FileDownloadResponse::File(RangeAcceptingFile(nf)) => nf.file().metadata().await.ok().map(|m| m.len()),
+            FileDownloadResponse::PartialFile(pf) => Some(pf.len()),
+            _ => None,
+        }
+    }
+}
+
 pub struct RequestedRange{
     start: u64,
     end: Option<u64>
@@ -70,21 +98,76 @@ impl<'r> Responder<'r, 'static> for RangeAcceptingFile {
     }
 }
 
+/// Folder streamed as zip archive, produced by a worker from `zipwriter::new_zip_writer`
+pub struct ZipDownload {
+    file_name: String,
+    stream: AsyncConsumer,
+}
+
+impl<'r> Responder<'r, 'static> for ZipDownload {
+    fn respond_to(self, _request: &'r Request<'_>) -> rocket::response::Result<'static> {
+        // quotes would end the filename parameter early
+        let file_name = self.file_name.replace('"', "");
+        Response::build()
+            .header(ContentType::ZIP)
+            .raw_header(
+                "Content-Disposition",
+                format!("attachment; filename=\"{}.zip\"", file_name),
+            )
+            .streamed_body(self.stream)
+            .ok()
+    }
+}
+
 const PARTIAL_MAX_SIZE: u64 = 1024 * 1024 * 4;
 
-#[get("/download/file?<path>&<token>", rank = 1)]
-pub async fn download_file(path: NetFilePath, token: UserID, range: Option<RequestedRange>) -> FileDownloadResponse {
-    let abs_path = to_abs_data_path(&token, Borrow::<Path>::borrow(&path));
+/// token gets read by the `UserID` guard from the `token` query parameter
+#[get("/download/file?<path>", rank = 2)]
+pub async fn download_file(
+    path: NetFilePath,
+    token: UserID,
+    db: &State<SharedDatabase>,
+    range: Option<RequestedRange>,
+) -> FileDownloadResponse {
+    match user_shares::resolve(db, &token, path, Access::Read) {
+        Ok(ResolvedPath::Node { owner, path, .. }) => download_node(path, owner, range).await,
+        Ok(ResolvedPath::SharedRoot) | Err(_) => FileDownloadResponse::NotFound(()),
+    }
+}
+
+/// `path` from the root of `owner`
+async fn download_node(path: NetFilePath, owner: UserID, range: Option<RequestedRange>) -> FileDownloadResponse {
+    let abs_path = match contained_data_path(&owner, Borrow::<Path>::borrow(&path)) {
+        Some(p) => p,
+        None => return FileDownloadResponse::Forbidden("Path leaves the root folder"),
+    };
 
     if abs_path.is_dir() {
         // handle zip file
-        /* 
-        let cons = zipwriter::new_zip_writer(abs_path).unwrap();
-        FileDownloadResponse::Zip(ByteStream!{
+        let file_name = abs_path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_else(|| "download".into());
+        // new_zip_writer blocks while MAX_ZIP_WRITERS are busy, so don't stall the async runtime
+        match rocket::tokio::task::spawn_blocking(move || zipwriter::new_zip_writer(abs_path)).await {
+            Ok(Ok(stream)) => FileDownloadResponse::Zip(ZipDownload { file_name, stream }),
+            Ok(Err(e)) => {
+                error!("Failed to start zip stream: {}", e);
+                FileDownloadResponse::Unavailable(())
+            }
+            Err(e) => {
+                error!("Zip writer task failed: {:?}", e);
+                FileDownloadResponse::Unavailable(())
+            }
+        }
+    } else {
+        serve_file(&abs_path, range).await
+    }
+}
 
-        }) */
-        panic!("Zip ByteStream not implemented")
-    } else if let Some(req_range) = range {
+/// Responds with the whole file, or only the requested range
+pub async fn serve_file(abs_path: &Path, range: Option<RequestedRange>) -> FileDownloadResponse {
+    if let Some(req_range) = range {
         let start = req_range.start;
         
         let file = if let Ok(f) = tokio::fs::File::open(abs_path).await {
@@ -105,7 +188,7 @@ pub async fn download_file(path: NetFilePath, token: UserID, range: Option<Reque
         // partial file
         FileDownloadResponse::PartialFile(PartialFile::new(file,start..=end).await)
     } else {
-        match NamedFile::open(&abs_path).await {
+        match NamedFile::open(abs_path).await {
             Ok(nf) => FileDownloadResponse::File(RangeAcceptingFile(nf)),
             Err(e) => {
                 warn!("Error while reading file {:?} : {:?}", abs_path, e);
@@ -115,17 +198,46 @@ pub async fn download_file(path: NetFilePath, token: UserID, range: Option<Reque
     }
 }
 
-#[get("/download/file?<path>&<shared_id>", rank = 2)]
+#[get("/download/file?<path>&<shared_id>", rank = 1)]
 pub async fn download_shared_file(
-    mut path: NetFilePath,
+    path: NetFilePath,
     shared_id: &str,
     db: &State<SharedDatabase>,
+    share_token: ShareUnlockToken,
+    client: ShareClient,
     range: Option<RequestedRange>
 ) -> FileDownloadResponse {
     if let Some(se) = db.get_shared_entry(&shared_id) {
-        path.add_prefix(&se.path);
-
-        download_file(path, se.user, range).await
+        let requested = path.as_str().to_owned();
+        // range requests continuing a download of the same client don't count again
+        let continued = range.as_ref().map(|r| r.start > 0).unwrap_or(false);
+        match se.check_download(db, &share_token, &requested, client.ip(), continued) {
+            Ok(()) => {}
+            Err(ShareDenied::Expired) => return FileDownloadResponse::Gone("Share expired"),
+            Err(ShareDenied::Locked) => return FileDownloadResponse::Unauthorized(()),
+            Err(ShareDenied::NotPermitted) => return FileDownloadResponse::Forbidden("Download not permitted"),
+        }
+        let path = match se.node_path(path) {
+            Some(p) => p,
+            None => return FileDownloadResponse::NotFound(()),
+        };
+        if !contained_data_path(&se.user, Borrow::<Path>::borrow(&path)).map(|p| p.exists()).unwrap_or(false) {
+            return FileDownloadResponse::NotFound(());
+        }
+        match db.count_share_download(&se.share_id, &requested, client.ip(), continued, shared::DOWNLOAD_CONTINUE_MINUTES) {
+            Ok(true) => {}
+            Ok(false) => return FileDownloadResponse::Gone("Download limit reached"),
+            Err(e) => {
+                error!("Failed to count download of share: {:?}", e);
+                return FileDownloadResponse::Unavailable(());
+            }
+        }
+        let res = download_node(path, se.user.clone(), range).await;
+        if res.is_content() {
+            let bytes = res.body_size().await;
+            shared::log_access(db, &se, &client, ShareAccessKind::Download, &requested, bytes);
+        }
+        res
     } else {
         FileDownloadResponse::Unauthorized(())
     }
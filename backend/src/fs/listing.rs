@@ -0,0 +1,283 @@
+//! Sorting and pagination of folder listings, with `details` every child gets its `NodeMetadata`.
+//! The cursor contains the sort key and name of the last returned child, so pages stay consistent
+//! when children get added or removed in between
+
+use super::metadata::{self, NodeMetadata};
+use super::sizes;
+use crate::auth::UserID;
+use crate::database::SharedDatabase;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Children per page in the detailed listing, and the maximum for `limit`
+pub const MAX_PAGE_SIZE: usize = 1000;
+
+#[derive(FromFormField, Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum SortKey {
+    #[field(value = "name")]
+    #[serde(rename = "name")]
+    Name,
+    #[field(value = "size")]
+    #[serde(rename = "size")]
+    Size,
+    #[field(value = "modified")]
+    #[serde(rename = "modified")]
+    Modified,
+}
+
+#[derive(FromFormField, Debug, Clone, Copy, PartialEq)]
+pub enum SortOrder {
+    #[field(value = "asc")]
+    Asc,
+    #[field(value = "desc")]
+    Desc,
+}
+
+/// Query parameters of `/node`, all optional. Without any of them the listing is unsorted and complete
+#[derive(FromForm, Debug, Default)]
+pub struct ListingOptions {
+    pub details: Option<bool>,
+    sort: Option<SortKey>,
+    order: Option<SortOrder>,
+    limit: Option<usize>,
+    cursor: Option<String>,
+}
+
+impl ListingOptions {
+    pub fn detailed(&self) -> bool {
+        self.details.unwrap_or(false)
+    }
+
+    fn page_size(&self) -> Option<usize> {
+        match (self.limit, self.detailed() || self.cursor.is_some()) {
+            (Some(limit), _) => Some(limit.clamp(1, MAX_PAGE_SIZE)),
+            (None, true) => Some(MAX_PAGE_SIZE),
+            (None, false) => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Cursor {
+    sort: SortKey,
+    key: u64,
+    name: String,
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        base64::encode_config(serde_json::to_vec(self).unwrap_or_default(), base64::URL_SAFE_NO_PAD)
+    }
+
+    fn decode(raw: &str) -> Option<Self> {
+        let json = base64::decode_config(raw, base64::URL_SAFE_NO_PAD).ok()?;
+        serde_json::from_slice(&json).ok()
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct ChildNode {
+    name: String,
+    metadata: NodeMetadata,
+}
+
+impl ChildNode {
+    pub fn new(name: String, metadata: NodeMetadata) -> Self {
+        ChildNode { name, metadata }
+    }
+}
+
+/// One page of a folder
+#[derive(Debug, Default)]
+pub struct Listing {
+    pub folders: Vec<String>,
+    pub files: Vec<String>,
+    /// only with `details`, folders and files in the requested order
+    pub children: Option<Vec<ChildNode>>,
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum ListingError {
+    /// the cursor is invalid or from a listing with a different sort
+    InvalidCursor,
+    Io(std::io::Error),
+}
+
+struct Entry {
+    name: String,
+    is_dir: bool,
+    /// the value of the sort key, 0 when sorting by name
+    key: u64,
+}
+
+/// Case insensitive name, the exact name decides between names that only differ in case
+fn compare_names(a: &str, b: &str) -> Ordering {
+    a.to_lowercase().cmp(&b.to_lowercase()).then_with(|| a.cmp(b))
+}
+
+fn compare(a: (u64, &str), b: (u64, &str), order: SortOrder) -> Ordering {
+    let ordering = a.0.cmp(&b.0).then_with(|| compare_names(a.1, b.1));
+    match order {
+        SortOrder::Asc => ordering,
+        SortOrder::Desc => ordering.reverse(),
+    }
+}
+
+/// Lists the folder at `abs_folder` (`rel_folder` from the root of `user_id`) with the options.
+/// The details contain the share ids only `with_shares`, they are secrets only the owner may see
+pub fn list_folder(
+    db: &SharedDatabase,
+    user_id: &UserID,
+    abs_folder: &Path,
+    rel_folder: &Path,
+    options: &ListingOptions,
+    with_shares: bool,
+) -> Result<Listing, ListingError> {
+    let sort = options.sort.unwrap_or(SortKey::Name);
+    let order = options.order.unwrap_or(SortOrder::Asc);
+    let cursor = match &options.cursor {
+        Some(raw) => match Cursor::decode(raw) {
+            Some(cursor) if cursor.sort == sort => Some(cursor),
+            _ => return Err(ListingError::InvalidCursor),
+        },
+        None => None,
+    };
+    let sorted = options.sort.is_some() || options.order.is_some() || options.page_size().is_some();
+
+    // folder sizes are only needed to sort by them
+    let child_sizes = if sort == SortKey::Size && sorted {
+        sizes::cached_child_sizes(db, user_id, abs_folder)
+    } else {
+        HashMap::new()
+    };
+    let mut entries = Vec::new();
+    for entry in abs_folder.read_dir().map_err(ListingError::Io)?.filter_map(Result::ok) {
+        let file_type = match entry.file_type() {
+            Ok(t) if t.is_dir() || t.is_file() => t,
+            _ => continue,
+        };
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let key = match (sort, sorted) {
+            (SortKey::Name, _) | (_, false) => 0,
+            (SortKey::Size, true) if file_type.is_dir() => match child_sizes.get(&name) {
+                Some(size) => *size,
+                None => sizes::folder_size(db, user_id, &entry.path()),
+            },
+            (SortKey::Size, true) => entry.metadata().map(|md| md.len()).unwrap_or(0),
+            (SortKey::Modified, true) => entry.metadata().map(|md| metadata::modified_secs(&md) as u64).unwrap_or(0),
+        };
+        entries.push(Entry {
+            name,
+            is_dir: file_type.is_dir(),
+            key,
+        });
+    }
+
+    if sorted {
+        entries.sort_by(|a, b| compare((a.key, &a.name), (b.key, &b.name), order));
+    }
+    if let Some(cursor) = &cursor {
+        entries.retain(|e| compare((e.key, &e.name), (cursor.key, &cursor.name), order) == Ordering::Greater);
+    }
+    let mut next_cursor = None;
+    if let Some(page_size) = options.page_size() {
+        if entries.len() > page_size {
+            entries.truncate(page_size);
+            next_cursor = entries.last().map(|last| {
+                Cursor {
+                    sort,
+                    key: last.key,
+                    name: last.name.clone(),
+                }
+                .encode()
+            });
+        }
+    }
+
+    let mut listing = Listing {
+        next_cursor,
+        ..Listing::default()
+    };
+    if options.detailed() {
+        listing.children = Some(child_details(db, user_id, abs_folder, rel_folder, &entries, with_shares));
+    }
+    for entry in entries {
+        if entry.is_dir {
+            listing.folders.push(entry.name);
+        } else {
+            listing.files.push(entry.name);
+        }
+    }
+    Ok(listing)
+}
+
+fn child_details(
+    db: &SharedDatabase,
+    user_id: &UserID,
+    abs_folder: &Path,
+    rel_folder: &Path,
+    entries: &[Entry],
+    with_shares: bool,
+) -> Vec<ChildNode> {
+    let shares: HashMap<PathBuf, String> = match with_shares {
+        true => db
+            .get_all_shared(user_id)
+            .into_iter()
+            .map(|se| (se.path, se.share_id.as_ref().to_owned()))
+            .collect(),
+        false => HashMap::new(),
+    };
+    let child_sizes = sizes::cached_child_sizes(db, user_id, abs_folder);
+
+    entries
+        .iter()
+        .filter_map(|entry| {
+            let abs_path = abs_folder.join(&entry.name);
+            // removed since the folder was read
+            let meta = std::fs::metadata(&abs_path).ok()?;
+            let size = if entry.is_dir {
+                match child_sizes.get(&entry.name) {
+                    Some(size) => *size,
+                    None => sizes::folder_size(db, user_id, &abs_path),
+                }
+            } else {
+                meta.len()
+            };
+            let shared = shares.get(&rel_folder.join(&entry.name)).cloned();
+            Some(ChildNode::new(
+                entry.name.clone(),
+                NodeMetadata::from_fs(&entry.name, &meta, size, shared),
+            ))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compare() {
+        let mut names = vec![(0, "b"), (0, "B"), (0, "a"), (0, "C")];
+        names.sort_by(|a, b| compare(*a, *b, SortOrder::Asc));
+        assert_eq!(names, vec![(0, "a"), (0, "B"), (0, "b"), (0, "C")]);
+
+        let mut sizes = vec![(5, "x"), (10, "a"), (5, "b")];
+        sizes.sort_by(|a, b| compare(*a, *b, SortOrder::Desc));
+        assert_eq!(sizes, vec![(10, "a"), (5, "x"), (5, "b")]);
+    }
+
+    #[test]
+    fn test_cursor() {
+        let cursor = Cursor {
+            sort: SortKey::Size,
+            key: 42,
+            name: "a/b ü".into(),
+        };
+        let decoded = Cursor::decode(&cursor.encode()).unwrap();
+        assert_eq!((decoded.sort, decoded.key, decoded.name.as_str()), (SortKey::Size, 42, "a/b ü"));
+        assert!(Cursor::decode("not a cursor").is_none());
+    }
+}
@@ -1,6 +1,8 @@
 use crate::auth::UserID;
 use crate::database::SharedDatabase;
 use log::{info, warn};
+use chrono::TimeZone;
+use rocket::http::ContentType;
 use rocket::State;
 use std::path::{Path, PathBuf};
 
@@ -12,15 +14,70 @@ pub struct NodeMetadata {
     #[serde(rename = "lastModified")]
     last_modified: chrono::DateTime<chrono::Utc>,
     shared: Option<String>,
+    /// guessed from the extension, null for folders
+    #[serde(rename = "mimeType")]
+    mime_type: Option<String>,
 }
 
-/// Path from user perspective (not absolute)
+impl NodeMetadata {
+    /// Folders that only exist in listings, like `user_shares::SHARED_WITH_ME`
+    pub fn virtual_folder() -> Self {
+        NodeMetadata {
+            node_type: "folder",
+            size: 0.0,
+            last_modified: chrono::Utc::now(),
+            shared: None,
+            mime_type: None,
+        }
+    }
+
+    /// `size` is the recursive size for folders, see `sizes::folder_size`
+    pub fn from_fs(name: &str, meta: &std::fs::Metadata, size: u64, shared: Option<String>) -> Self {
+        Self::from_index(name, meta.is_dir(), size, modified_secs(meta), shared)
+    }
+
+    /// From the values in the search index, `modified` is a unix timestamp
+    pub fn from_index(name: &str, is_dir: bool, size: u64, modified: i64, shared: Option<String>) -> Self {
+        NodeMetadata {
+            node_type: if is_dir { "folder" } else { "file" },
+            size: size as f64,
+            last_modified: chrono::Utc.timestamp(modified, 0),
+            shared,
+            mime_type: if is_dir { None } else { Some(mime_type(name)) },
+        }
+    }
+}
+
+/// Last modification as unix timestamp, 0 if the platform doesn't support it
+pub fn modified_secs(meta: &std::fs::Metadata) -> i64 {
+    meta.modified()
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|dur| dur.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// From the extension of `name`, `application/octet-stream` if unknown
+pub fn mime_type(name: &str) -> String {
+    Path::new(name)
+        .extension()
+        .and_then(|ext| ContentType::from_extension(&ext.to_string_lossy()))
+        .unwrap_or(ContentType::Binary)
+        .to_string()
+}
+
+/// Path from user perspective (not absolute).
+/// The share id is only set with `with_share`, share ids are secrets only the owner may see
 pub fn get_metadata(
     path: &Path,
     user_id: &UserID,
     db: &State<SharedDatabase>,
+    with_share: bool,
 ) -> Option<NodeMetadata> {
-    let shared: Option<String> = db.get_share_id(&user_id, path).ok().flatten();
+    let shared: Option<String> = match with_share {
+        true => db.get_share_id(&user_id, path).ok().flatten(),
+        false => None,
+    };
 
     let combined = super::to_abs_data_path(&user_id, path);
     let mut root: PathBuf = PathBuf::from(crate::config::data_path());
@@ -37,48 +94,19 @@ pub fn get_metadata(
         return None;
     }
 
-    match std::fs::metadata(&combined) {
-        Err(_) => None,
-        Ok(meta) => {
-            let last_mod: chrono::DateTime<chrono::Utc> = {
-                let dur: std::time::Duration = meta
-                    .modified()
-                    .unwrap()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .expect("Timestamp of file to DateTime failed");
-                use chrono::TimeZone;
-                chrono::Utc.timestamp(dur.as_secs() as i64, 0)
-            };
-
-            if meta.file_type().is_dir() {
-                // calculate folder size
-                // First idea: calc size of all files in folder and write >= size?
-                // or cache all calculated sizes somewhere
-                let size: u64 = std::fs::read_dir(&combined)
-                    .unwrap()
-                    .map(|f| {
-                        let direntry: std::fs::DirEntry = f.unwrap();
-                        if direntry.file_type().unwrap().is_file() {
-                            return direntry.metadata().unwrap().len();
-                        }
-                        0
-                    })
-                    .sum();
-
-                Some(NodeMetadata {
-                    node_type: "folder",
-                    size: size as f64,
-                    last_modified: last_mod,
-                    shared,
-                })
-            } else {
-                Some(NodeMetadata {
-                    node_type: "file",
-                    size: meta.len() as f64,
-                    last_modified: last_mod,
-                    shared,
-                })
-            }
+    let meta = match std::fs::metadata(&combined) {
+        Ok(meta) => meta,
+        Err(e) => {
+            warn!("Failed to read metadata of {:?}: {:?}", combined, e);
+            return None;
         }
-    }
+    };
+    let name = path.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+    let size = if meta.is_dir() {
+        // recursive, from the size index
+        super::sizes::folder_size(db, user_id, &combined)
+    } else {
+        meta.len()
+    };
+    Some(NodeMetadata::from_fs(&name, &meta, size, shared))
 }
@@ -1,19 +1,28 @@
-use crate::auth::UserID;
+use crate::auth::{UserID, WritableUser};
 use log::{info, warn};
 use rocket::response::Responder;
 use rocket::serde::json::Json;
 use std::borrow::Borrow;
 use std::path::{Path, PathBuf};
 
-// mod async_buf;
+mod async_buf;
 mod blocking_buf;
 pub mod download;
+pub mod listing;
 pub mod metadata;
 pub mod netfilepath;
+pub mod node_ops;
 pub mod previews;
+pub mod quota;
+pub mod search;
 pub mod shared;
+pub mod sizes;
+pub mod trash;
+pub mod tus;
 pub mod upload;
-// pub mod zipwriter;
+pub mod user_shares;
+pub mod versions;
+pub mod zipwriter;
 pub mod partial_file;
 
 use netfilepath::NetFilePath;
@@ -29,19 +38,34 @@ pub struct NetNode {
     path_from_root: Vec<String>,
     metadata: metadata::NodeMetadata,
     #[serde(rename = "ownedBy")]
-    owned_by: UserID
+    owned_by: UserID,
+    /// only with `details`, see `listing::ListingOptions`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    children: Option<Vec<listing::ChildNode>>,
+    #[serde(rename = "nextCursor", skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
 }
 
 #[derive(Responder, Debug)]
 pub enum NodeContentResponse {
+    #[response(status = 400)]
+    BadRequest(String),
+    #[response(status = 401)]
+    Locked(String),
+    #[response(status = 403)]
+    Forbidden(String),
     #[response(status = 404)]
     PathNotFound(String),
     #[response(status = 409)]
     DirError(String),
+    #[response(status = 410)]
+    Gone(String),
     #[response(status = 200)]
     NodeData(Json<NetNode>),
 }
 
+/// Raw mapping into the users tree, paths from requests in `user_shares::SHARED_WITH_ME`
+/// need to go through `user_shares::resolve` first
 fn to_abs_data_path<P: AsRef<Path>>(user: &UserID, p: P) -> PathBuf {
     let path: &Path = p.as_ref();
     let mut root: PathBuf = PathBuf::from(crate::config::data_path());
@@ -50,32 +74,139 @@ fn to_abs_data_path<P: AsRef<Path>>(user: &UserID, p: P) -> PathBuf {
     root
 }
 
+/// Removes the root, trash, versions and unfinished uploads of a deleted user
+pub fn remove_user_files(user: &UserID, upload_ids: &[String]) -> std::io::Result<()> {
+    for dir in [to_abs_data_path(user, ""), trash::trash_dir(user), versions::versions_dir(user)] {
+        match std::fs::remove_dir_all(&dir) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    for id in upload_ids {
+        if let Err(e) = std::fs::remove_file(tus::staging_file(id)) {
+            warn!("Failed to remove staging file of upload {}: {:?}", id, e);
+        }
+    }
+    Ok(())
+}
+
+/// Like `to_abs_data_path`, but None if the path leaves the users root (e.g. through a symlink).
+/// Use this for all paths from requests
+fn contained_data_path<P: AsRef<Path>>(user: &UserID, p: P) -> Option<PathBuf> {
+    contained_path(&to_abs_data_path(user, ""), p.as_ref())
+}
+
+/// `rel` joined to the absolute `root`, None if it leaves the root
+fn contained_path(root: &Path, rel: &Path) -> Option<PathBuf> {
+    match netfilepath::contain(root, rel) {
+        Ok(path) => Some(path),
+        Err(e) => {
+            warn!("Rejected path {:?} below {:?}: {}", rel, root, e);
+            None
+        }
+    }
+}
+
 use super::database::SharedDatabase;
 use rocket::State;
+use shared::{ShareAccessKind, ShareClient, ShareDenied, SharePermissions, ShareUnlockToken};
+use user_shares::{Access, ResolveError, ResolvedPath};
 
-#[get("/node?<file_path>&<shared_id>", rank = 1)]
+#[get("/node?<file_path>&<shared_id>&<listing..>", rank = 1)]
 pub fn get_node_data_shared(
-    mut file_path: NetFilePath,
+    file_path: NetFilePath,
     db: &State<SharedDatabase>,
     shared_id: String,
+    listing: listing::ListingOptions,
+    share_token: ShareUnlockToken,
+    client: ShareClient,
 ) -> NodeContentResponse {
     // check if shared id is allowed
     if let Some(se) = db.get_shared_entry(&shared_id) {
-        file_path.add_prefix(&se.path);
-
-        get_node(file_path, se.user, db, Some(&se.path))
+        match se.check_access(&share_token, SharePermissions::LIST) {
+            Ok(()) => {}
+            Err(ShareDenied::Expired) => return NodeContentResponse::Gone("Share expired".into()),
+            Err(ShareDenied::Locked) => return NodeContentResponse::Locked("Share is password protected".into()),
+            Err(ShareDenied::NotPermitted) => return NodeContentResponse::Forbidden("Listing not permitted".into()),
+        }
+        let requested = file_path.as_str().to_owned();
+        let res = match se.node_path(file_path) {
+            Some(file_path) => get_node(file_path, se.user.clone(), db, Some(&se.path), &listing),
+            None => NodeContentResponse::PathNotFound("Path doesn't exist".into()),
+        };
+        if let NodeContentResponse::NodeData(_) = res {
+            shared::log_access(db, &se, &client, ShareAccessKind::List, &requested, None);
+        }
+        res
     } else {
         NodeContentResponse::PathNotFound("Shared ID doesn't exist".into())
     }
 }
 
-#[get("/node?<file_path>", rank = 2)]
+/// Paths in `user_shares::SHARED_WITH_ME` are resolved to the folders other users shared with the user.
+/// See `listing::ListingOptions` for the detailed, sorted and paginated listing
+#[get("/node?<file_path>&<listing..>", rank = 2)]
 pub fn get_node_data(
     file_path: NetFilePath,
+    listing: listing::ListingOptions,
     user_id: UserID,
     db: &State<SharedDatabase>,
 ) -> NodeContentResponse {
-    get_node(file_path, user_id, db, None)
+    let is_root = Borrow::<Path>::borrow(&file_path).as_os_str().is_empty();
+    match user_shares::resolve(db, &user_id, file_path, Access::Read) {
+        Ok(ResolvedPath::SharedRoot) => {
+            let names: Vec<String> = user_shares::incoming_shares(db, &user_id)
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect();
+            NodeContentResponse::NodeData(Json(NetNode {
+                name: user_shares::SHARED_WITH_ME.into(),
+                children: listing.detailed().then(|| virtual_children(&names)),
+                children_folder: Some(names),
+                files: Some(Vec::new()),
+                path_from_root: vec![user_shares::SHARED_WITH_ME.into()],
+                metadata: metadata::NodeMetadata::virtual_folder(),
+                owned_by: user_id,
+                next_cursor: None,
+            }))
+        }
+        Ok(ResolvedPath::Node { owner, path, share: None }) => {
+            let mut res = get_node(path, owner, db, None, &listing);
+            if let NodeContentResponse::NodeData(Json(node)) = &mut res {
+                let has_incoming = !user_shares::incoming_shares(db, &user_id).is_empty();
+                // on the last page
+                if let (true, true, None, Some(children)) = (is_root, has_incoming, &node.next_cursor, node.children_folder.as_mut()) {
+                    children.push(user_shares::SHARED_WITH_ME.into());
+                    if let Some(details) = node.children.as_mut() {
+                        details.extend(virtual_children(&[user_shares::SHARED_WITH_ME.into()]));
+                    }
+                }
+            }
+            res
+        }
+        Ok(ResolvedPath::Node { owner, path, share: Some(share) }) => {
+            let mut res = get_node(path, owner, db, Some(&share.owner_path), &listing);
+            if let NodeContentResponse::NodeData(Json(node)) = &mut res {
+                let mut path_from_root = share.virtual_prefix.clone();
+                path_from_root.append(&mut node.path_from_root);
+                node.path_from_root = path_from_root;
+                if node.path_from_root.len() == 2 {
+                    // the shared folder has the name from `user_shares::incoming_shares`
+                    node.name = share.virtual_prefix[1].clone();
+                }
+            }
+            res
+        }
+        Err(ResolveError::NotFound) => NodeContentResponse::PathNotFound("Path doesn't exist".into()),
+        Err(ResolveError::Forbidden) => NodeContentResponse::Forbidden("Not permitted".into()),
+    }
+}
+
+fn virtual_children(names: &[String]) -> Vec<listing::ChildNode> {
+    names
+        .iter()
+        .map(|name| listing::ChildNode::new(name.clone(), metadata::NodeMetadata::virtual_folder()))
+        .collect()
 }
 
 /// folder_path: Path from base folder of user, but WITHOUT user_id prefix!!!
@@ -84,6 +215,7 @@ fn get_node(
     user_id: UserID,
     db: &State<SharedDatabase>,
     base_path: Option<&Path>,
+    listing: &listing::ListingOptions,
 ) -> NodeContentResponse {
     let mut root: PathBuf = PathBuf::from(crate::config::data_path());
     root.push(&user_id.0);
@@ -93,8 +225,10 @@ fn get_node(
             Err(e) => warn!("Failed to create base dir of user {}: {:?}", user_id.0, e),
         }
     }
-    root.push(Borrow::<str>::borrow(&folder_path));
-    let combined = root;
+    let combined = match contained_path(&root, Borrow::<Path>::borrow(&folder_path)) {
+        Some(path) => path,
+        None => return NodeContentResponse::Forbidden("Path leaves the root folder".into()),
+    };
     if !combined.exists() {
         // check if user has allready folder or needs to get created
         return NodeContentResponse::PathNotFound("Path doesn't exist".into());
@@ -102,6 +236,8 @@ fn get_node(
 
     let mut children_folder: Option<Vec<String>> = None;
     let mut files: Option<Vec<String>> = None;
+    let mut children = None;
+    let mut next_cursor = None;
 
     let is_dir = combined.is_dir();
 
@@ -131,31 +267,24 @@ fn get_node(
     .map(|oss| oss.as_os_str().to_string_lossy().to_string())
     .collect();
 
+    // share ids are only shown to the owner, not through link or user shares
+    let owner_view = base_path.is_none();
     if is_dir {
-        match combined.read_dir() {
-            Err(e) => return NodeContentResponse::DirError(e.to_string()),
-            Ok(dir) => {
-                let mut cf = Vec::new();
-                let mut f = Vec::new();
-                for maybe_entry in dir {
-                    if let Ok(e) = maybe_entry {
-                        if let Ok(ft) = e.file_type() {
-                            let fname = e.file_name().into_string().unwrap();
-                            if ft.is_dir() {
-                                cf.push(fname);
-                            } else if ft.is_file() {
-                                f.push(fname);
-                            }
-                        }
-                    }
-                }
-                children_folder = Some(cf);
-                files = Some(f);
+        match listing::list_folder(db, &user_id, &combined, folder_path, listing, owner_view) {
+            Err(listing::ListingError::InvalidCursor) => {
+                return NodeContentResponse::BadRequest("Invalid cursor".into())
+            }
+            Err(listing::ListingError::Io(e)) => return NodeContentResponse::DirError(e.to_string()),
+            Ok(page) => {
+                children_folder = Some(page.folders);
+                files = Some(page.files);
+                children = page.children;
+                next_cursor = page.next_cursor;
             }
         }
     }
 
-    let metadata = match metadata::get_metadata(&folder_path, &user_id, db) {
+    let metadata = match metadata::get_metadata(&folder_path, &user_id, db, owner_view) {
         Some(md) => md,
         None => return NodeContentResponse::DirError("Metadata fetch failed".into()),
     };
@@ -171,36 +300,92 @@ fn get_node(
         path_from_root,
         metadata,
         owned_by: user_id,
+        children,
+        next_cursor,
     }))
 }
 
 use rocket::response::status;
-#[delete("/node?<path>")]
+/// Moves the node to the trash of the owner, the shared folder itself can't be deleted
+#[delete("/node?<path>&<shared_id>", rank = 1)]
+pub async fn delete_node_data_shared(
+    path: NetFilePath,
+    shared_id: &str,
+    share_token: ShareUnlockToken,
+    client: ShareClient,
+    db: &State<SharedDatabase>,
+) -> Result<status::Accepted<()>, status::Custom<&'static str>> {
+    use rocket::http::Status;
+
+    let se = match db.get_shared_entry(shared_id) {
+        Some(se) => se,
+        None => return Err(status::Custom(Status::NotFound, "Shared ID doesn't exist")),
+    };
+    match se.check_access(&share_token, SharePermissions::DELETE) {
+        Ok(()) => {}
+        Err(ShareDenied::Expired) => return Err(status::Custom(Status::Gone, "Share expired")),
+        Err(ShareDenied::Locked) => return Err(status::Custom(Status::Unauthorized, "Share is password protected")),
+        Err(ShareDenied::NotPermitted) => return Err(status::Custom(Status::Forbidden, "Deleting not permitted")),
+    }
+    if Borrow::<Path>::borrow(&path).as_os_str().is_empty() {
+        return Err(status::Custom(Status::Forbidden, "Can't delete the shared folder"));
+    }
+    let requested = path.as_str().to_owned();
+    let path = match se.node_path(path) {
+        Some(p) => p,
+        None => return Err(status::Custom(Status::NotFound, "Path doesn't exist")),
+    };
+    let rel_path: &Path = Borrow::<Path>::borrow(&path);
+    if !contained_data_path(&se.user, rel_path).map(|p| p.exists()).unwrap_or(false) {
+        return Err(status::Custom(Status::NotFound, "Path doesn't exist"));
+    }
+    info!("IP {:?} deletes {:?} of share {}", client.ip(), &path, shared_id);
+    if let Err(e) = trash::move_to_trash(db, &se.user, rel_path) {
+        warn!("Failed to move {:?} to trash: {:?}", path, e);
+        return Err(status::Custom(Status::InternalServerError, "Failed to delete"));
+    }
+    shared::log_access(db, &se, &client, ShareAccessKind::Delete, &requested, None);
+    Ok(status::Accepted(None))
+}
+
+/// Moves the node to the trash (of the owner for folders shared with the user), see `trash::restore_trash_item`
+#[delete("/node?<path>", rank = 2)]
 pub async fn delete_node_data(
     path: NetFilePath,
-    user_id: UserID,
+    writable: WritableUser,
+    db: &State<SharedDatabase>,
     addr: std::net::SocketAddr,
 ) -> Result<status::Accepted<()>, status::Forbidden<()>> {
-    let mut root: PathBuf = PathBuf::from(crate::config::data_path());
-    root.push(&user_id.0);
-    root.push(Borrow::<str>::borrow(&path));
+    let user_id = writable.0;
+    let (owner, path) = match user_shares::resolve(db, &user_id, path, Access::Write) {
+        Ok(ResolvedPath::Node { owner, path, share }) => {
+            if share.map(|s| Borrow::<Path>::borrow(&path) == s.owner_path).unwrap_or(false) {
+                warn!("User tried to delete folder shared with them");
+                return Err(status::Forbidden(None));
+            }
+            (owner, path)
+        }
+        _ => return Err(status::Forbidden(None)),
+    };
+    let rel_path: &Path = Borrow::<Path>::borrow(&path);
+    if rel_path.as_os_str().is_empty() {
+        warn!("User tried to delete root folder");
+        return Err(status::Forbidden(None));
+    }
 
+    let root = match contained_data_path(&owner, rel_path) {
+        Some(root) => root,
+        None => return Err(status::Forbidden(None)),
+    };
     if !root.exists() {
         warn!("User tried to delete {:?} which doesn't exist", &root);
         return Err(status::Forbidden(None));
     }
 
     info!("IP {:?} deletes {:?}", addr, &path);
-    if root.is_file() {
-        // delete file
-        if let Err(_) = std::fs::remove_file(&root) {
-            return Err(status::Forbidden(None));
-        }
-    } else {
-        // delete folder and all its children
-        if let Err(_) = std::fs::remove_dir_all(&root) {
-            return Err(status::Forbidden(None));
-        }
+    if let Err(e) = trash::move_to_trash(db, &owner, rel_path) {
+        warn!("Failed to move {:?} to trash: {:?}", root, e);
+        return Err(status::Forbidden(None));
     }
     Ok(status::Accepted(None))
 }
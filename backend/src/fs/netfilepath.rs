@@ -1,19 +1,76 @@
 use path_slash::PathExt;
 use rocket::form::{FromFormField, ValueField};
 use std::borrow::Borrow;
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
 
+/// Path relative to a users (or shares) root, always with `/` as separator.
+/// Paths from requests go through `NetFilePath::parse`, so they never contain `..`, drive letters or control characters
 #[derive(Debug)]
 pub struct NetFilePath(String);
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PathError {
+    /// `..` component
+    ParentDir,
+    /// network path like `//server/share`
+    Absolute,
+    /// windows drive like `C:`
+    Drive,
+    /// NUL or other control characters
+    InvalidChar,
+    /// leaves the root by following a symlink
+    Escapes,
+}
+
+impl std::fmt::Display for PathError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            PathError::ParentDir => "No .. allowed",
+            PathError::Absolute => "No network paths allowed",
+            PathError::Drive => "No drive letters allowed",
+            PathError::InvalidChar => "No control characters allowed",
+            PathError::Escapes => "Path leaves the root folder",
+        })
+    }
+}
+
+fn is_drive(component: &str) -> bool {
+    let bytes = component.as_bytes();
+    bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':'
+}
+
 impl NetFilePath {
-    #[allow(dead_code)]
     pub fn from_path<P: AsRef<Path>>(path: P) -> Self {
         Self(path.as_ref().to_slash_lossy())
     }
 
+    /// Normalises `\` to `/` and drops empty and `.` components, so a leading `/` means the root.
+    /// Rejects everything that could point outside of the root
+    pub fn parse(raw: &str) -> Result<Self, PathError> {
+        let normalised = raw.replace('\\', "/");
+        if normalised.starts_with("//") {
+            return Err(PathError::Absolute);
+        }
+
+        let mut components: Vec<&str> = Vec::new();
+        for component in normalised.split('/') {
+            match component {
+                "" | "." => {}
+                ".." => return Err(PathError::ParentDir),
+                c if c.chars().any(char::is_control) => return Err(PathError::InvalidChar),
+                c if components.is_empty() && is_drive(c) => return Err(PathError::Drive),
+                c => components.push(c),
+            }
+        }
+        Ok(NetFilePath(components.join("/")))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
     pub fn add_prefix<P: AsRef<Path>>(&mut self, prefix: P) {
-        let mut n_base: String = prefix.as_ref().to_slash_lossy();
+        let mut n_base: String = prefix.as_ref().to_slash_lossy().replace('\\', "/");
         if n_base.ends_with('/') && self.0.starts_with('/') {
             n_base.push_str(&self.0[1..]);
         } else if n_base.ends_with('/') || self.0.starts_with('/') {
@@ -26,6 +83,44 @@ impl NetFilePath {
     }
 }
 
+/// Joins `rel` to `root` and checks that the result stays inside of `root`, also after resolving symlinks.
+/// Trailing components that don't exist yet (e.g. upload targets) are fine, dangling symlinks are not
+pub fn contain(root: &Path, rel: &Path) -> Result<PathBuf, PathError> {
+    let mut joined = root.to_path_buf();
+    for component in rel.components() {
+        match component {
+            Component::Normal(c) => joined.push(c),
+            // paths from `from_path` may start with a `/`, they are relative to the root as well
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir => return Err(PathError::ParentDir),
+            Component::Prefix(_) => return Err(PathError::Drive),
+        }
+    }
+
+    let canonical_root = match root.canonicalize() {
+        Ok(r) => r,
+        // nothing can exist below a missing root
+        Err(_) => return Ok(joined),
+    };
+    let mut existing = joined.as_path();
+    while existing.symlink_metadata().is_err() {
+        match existing.parent() {
+            Some(parent) => existing = parent,
+            None => break,
+        }
+    }
+    match existing.canonicalize() {
+        Ok(resolved) if resolved.starts_with(&canonical_root) => Ok(joined),
+        _ => Err(PathError::Escapes),
+    }
+}
+
+impl From<NetFilePath> for String {
+    fn from(path: NetFilePath) -> String {
+        path.0
+    }
+}
+
 impl Borrow<str> for NetFilePath {
     fn borrow(&self) -> &str {
         &self.0
@@ -41,23 +136,7 @@ impl Borrow<Path> for NetFilePath {
 #[rocket::async_trait]
 impl<'v> FromFormField<'v> for NetFilePath {
     fn from_value(field: ValueField<'v>) -> rocket::form::Result<Self> {
-        let mut raw_path = field.value.to_owned();
-        /*let mut raw_path: String = match raw.percent_decode() {
-            Ok(s) => s.into_owned(),
-            Err(_) => {
-                return Err(());
-            }
-        };*/
-
-        if raw_path.contains("..") {
-            // illegal
-            // TODO are there other symbolic links or ways to escape the dir?
-            return Err(rocket::form::Error::validation("No .. allowed").into());
-        };
-        if raw_path.starts_with('/') {
-            raw_path.remove(0);
-        }
-        Ok(NetFilePath(Path::new(&raw_path).to_slash_lossy()))
+        NetFilePath::parse(field.value).map_err(|e| rocket::form::Error::validation(e.to_string()).into())
     }
 }
 
@@ -65,6 +144,7 @@ impl<'v> FromFormField<'v> for NetFilePath {
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
+    use proptest::prelude::*;
 
     #[test]
     fn test_netfilepath() {
@@ -73,4 +153,131 @@ mod tests {
         nfp.add_prefix("\\User1\\");
         assert_eq!(Borrow::<str>::borrow(&nfp), "/User1/folder1/test");
     }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(NetFilePath::parse("/a\\b//./c/").unwrap().as_str(), "a/b/c");
+        assert_eq!(NetFilePath::parse("").unwrap().as_str(), "");
+        assert_eq!(NetFilePath::parse("a/...b/c:d").unwrap().as_str(), "a/...b/c:d");
+        assert_eq!(NetFilePath::parse("a/../b").unwrap_err(), PathError::ParentDir);
+        assert_eq!(NetFilePath::parse("a\\..\\..\\b").unwrap_err(), PathError::ParentDir);
+        assert_eq!(NetFilePath::parse("C:\\Windows").unwrap_err(), PathError::Drive);
+        assert_eq!(NetFilePath::parse("/c:/x").unwrap_err(), PathError::Drive);
+        assert_eq!(NetFilePath::parse("\\\\server\\share").unwrap_err(), PathError::Absolute);
+        assert_eq!(NetFilePath::parse("a\0b").unwrap_err(), PathError::InvalidChar);
+        assert_eq!(NetFilePath::parse("a/b\nc").unwrap_err(), PathError::InvalidChar);
+    }
+
+    fn segment() -> impl Strategy<Value = String> {
+        "[a-zA-Z0-9 _.-]{1,12}".prop_filter("no dot segments", |s| s != "." && s != "..")
+    }
+
+    fn separator() -> impl Strategy<Value = &'static str> {
+        prop::sample::select(vec!["/", "\\", "//", "/./", "\\.\\"])
+    }
+
+    proptest! {
+        #[test]
+        fn parsed_paths_are_normalised(raw in any::<String>()) {
+            if let Ok(path) = NetFilePath::parse(&raw) {
+                let s = path.as_str();
+                prop_assert!(!s.starts_with('/') && !s.ends_with('/') && !s.contains('\\'));
+                prop_assert!(!s.chars().any(char::is_control));
+                prop_assert!(s.is_empty() || s.split('/').all(|c| !c.is_empty() && c != "." && c != ".."));
+                prop_assert!(Path::new(s).components().all(|c| matches!(c, Component::Normal(_))));
+                // parsing is idempotent
+                prop_assert_eq!(String::from(NetFilePath::parse(s).unwrap()), s);
+            }
+        }
+
+        #[test]
+        fn separators_get_normalised(
+            segments in prop::collection::vec(segment(), 1..6),
+            separators in prop::collection::vec(separator(), 6),
+            leading in any::<bool>(),
+        ) {
+            let mut raw = if leading { "/".to_string() } else { String::new() };
+            for (segment, separator) in segments.iter().zip(&separators) {
+                raw.push_str(segment);
+                raw.push_str(separator);
+            }
+            prop_assert_eq!(String::from(NetFilePath::parse(&raw).unwrap()), segments.join("/"));
+        }
+
+        #[test]
+        fn parent_components_get_rejected(
+            before in prop::collection::vec(segment(), 0..4),
+            after in prop::collection::vec(segment(), 0..4),
+            separator in separator(),
+        ) {
+            let raw = before.iter().map(String::as_str).chain(Some("..")).chain(after.iter().map(String::as_str))
+                .collect::<Vec<_>>()
+                .join(separator);
+            prop_assert_eq!(NetFilePath::parse(&raw).unwrap_err(), PathError::ParentDir);
+        }
+
+        #[test]
+        fn control_characters_get_rejected(prefix in "[a-z]{0,4}(/[a-z]{1,4}){0,2}", c in "[\\x00-\\x1f\\x7f]", suffix in "[a-z/]{0,8}") {
+            let raw = format!("{}{}{}", prefix, c, suffix);
+            prop_assert_eq!(NetFilePath::parse(&raw).unwrap_err(), PathError::InvalidChar);
+        }
+    }
+
+    #[cfg(unix)]
+    mod symlinks {
+        use super::*;
+        use std::os::unix::fs::symlink;
+
+        struct Dirs {
+            base: PathBuf,
+            root: PathBuf,
+        }
+
+        impl Drop for Dirs {
+            fn drop(&mut self) {
+                let _ = std::fs::remove_dir_all(&self.base);
+            }
+        }
+
+        /// root/inside, root/link_in -> root/inside, root/link_out -> outside, root/dangling -> missing
+        fn setup(name: &str) -> Dirs {
+            let mut base = std::env::temp_dir();
+            base.push(format!("what-cloud-path-test-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&base);
+            let root = base.join("root");
+            std::fs::create_dir_all(root.join("inside")).unwrap();
+            std::fs::create_dir_all(base.join("outside")).unwrap();
+            symlink(root.join("inside"), root.join("link_in")).unwrap();
+            symlink(base.join("outside"), root.join("link_out")).unwrap();
+            symlink(base.join("missing"), root.join("dangling")).unwrap();
+            Dirs { base, root }
+        }
+
+        #[test]
+        fn test_contain() {
+            let dirs = setup("contain");
+            let root = &dirs.root;
+            assert_eq!(contain(root, Path::new("inside/new/file")), Ok(root.join("inside/new/file")));
+            assert_eq!(contain(root, Path::new("/link_in/x")), Ok(root.join("link_in/x")));
+            assert_eq!(contain(root, Path::new("")), Ok(root.clone()));
+            assert_eq!(contain(root, Path::new("link_out")), Err(PathError::Escapes));
+            assert_eq!(contain(root, Path::new("dangling")), Err(PathError::Escapes));
+            assert_eq!(contain(root, Path::new("inside/../../outside")), Err(PathError::ParentDir));
+            assert_eq!(contain(&dirs.base.join("no_root"), Path::new("a")), Ok(dirs.base.join("no_root/a")));
+        }
+
+        proptest! {
+            #[test]
+            fn paths_through_links_stay_contained(segments in prop::collection::vec(segment(), 0..5)) {
+                let dirs = setup("prop");
+                let rest = NetFilePath::parse(&segments.join("/")).unwrap();
+                let rest: &Path = Borrow::<Path>::borrow(&rest);
+
+                let inside = contain(&dirs.root, &Path::new("link_in").join(rest));
+                prop_assert_eq!(inside, Ok(dirs.root.join("link_in").join(rest)));
+                prop_assert_eq!(contain(&dirs.root, &Path::new("link_out").join(rest)), Err(PathError::Escapes));
+                prop_assert_eq!(contain(&dirs.root, &Path::new("dangling").join(rest)), Err(PathError::Escapes));
+            }
+        }
+    }
 }
@@ -0,0 +1,292 @@
+use super::upload::{free_file_name, ConflictPolicy};
+use super::user_shares::{self, Access, ResolveError, ResolvedPath, SharedBase};
+use super::{contained_data_path, previews, quota, search, sizes, NetFilePath};
+use crate::auth::{UserID, WritableUser};
+use crate::database::SharedDatabase;
+use log::{error, info, warn};
+use rocket::serde::json::Json;
+use rocket::State;
+use std::borrow::Borrow;
+use std::path::{Path, PathBuf};
+
+#[derive(Serialize, Debug)]
+pub struct ChangedNode {
+    /// new path from the users root
+    pub(super) path: String,
+}
+
+#[derive(Responder, Debug)]
+pub enum NodeChangeResponse {
+    #[response(status = 202)]
+    Accepted(Json<ChangedNode>),
+    #[response(status = 403)]
+    Forbidden(&'static str),
+    #[response(status = 404)]
+    NotFound(()),
+    #[response(status = 409)]
+    Conflict(&'static str),
+    #[response(status = 413)]
+    QuotaExceeded(&'static str),
+    #[response(status = 500)]
+    ServerError(()),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Operation {
+    Move,
+    Copy,
+}
+
+/// Renaming is moving inside the same folder.
+/// `conflict` defaults to fail, only files can be overwritten.
+#[post("/node/move?<path>&<destination>&<conflict>")]
+pub async fn move_node(
+    path: NetFilePath,
+    destination: NetFilePath,
+    conflict: Option<ConflictPolicy>,
+    writable: WritableUser,
+    db: &State<SharedDatabase>,
+) -> NodeChangeResponse {
+    let user_id = writable.0;
+    change_node(Operation::Move, path, destination, conflict, user_id, db).await
+}
+
+/// Folders get copied recursively, shares are not copied.
+/// `conflict` defaults to fail, only files can be overwritten. Copies count against the quota.
+#[post("/node/copy?<path>&<destination>&<conflict>")]
+pub async fn copy_node(
+    path: NetFilePath,
+    destination: NetFilePath,
+    conflict: Option<ConflictPolicy>,
+    writable: WritableUser,
+    db: &State<SharedDatabase>,
+) -> NodeChangeResponse {
+    let user_id = writable.0;
+    change_node(Operation::Copy, path, destination, conflict, user_id, db).await
+}
+
+async fn change_node(
+    op: Operation,
+    path: NetFilePath,
+    destination: NetFilePath,
+    conflict: Option<ConflictPolicy>,
+    user_id: UserID,
+    db: &State<SharedDatabase>,
+) -> NodeChangeResponse {
+    let requested = path.as_str().to_owned();
+    // copies only read the source
+    let src_access = if op == Operation::Move { Access::Write } else { Access::Read };
+    let (owner, src_path, _) = match resolve_node(db, &user_id, path, src_access) {
+        Ok(resolved) => resolved,
+        Err(res) => return res,
+    };
+    let (dest_owner, dest_path, dest_share) = match resolve_node(db, &user_id, destination, Access::Write) {
+        Ok(resolved) => resolved,
+        Err(res) => return res,
+    };
+    if owner != dest_owner {
+        return NodeChangeResponse::Forbidden("Can't move or copy between the files of different users");
+    }
+
+    let src_rel: &Path = Borrow::<Path>::borrow(&src_path);
+    let dest_rel: &Path = Borrow::<Path>::borrow(&dest_path);
+    if src_rel.as_os_str().is_empty() || dest_rel.as_os_str().is_empty() {
+        return NodeChangeResponse::Forbidden("Can't move or copy the root folder");
+    }
+    if dest_rel.starts_with(src_rel) {
+        return NodeChangeResponse::Forbidden("Destination is inside of source");
+    }
+
+    let (src, mut dest) = match (contained_data_path(&owner, src_rel), contained_data_path(&owner, dest_rel)) {
+        (Some(src), Some(dest)) => (src, dest),
+        _ => return NodeChangeResponse::Forbidden("Path leaves the root folder"),
+    };
+    if !src.exists() {
+        return NodeChangeResponse::NotFound(());
+    }
+    if !dest.parent().map(Path::is_dir).unwrap_or(false) {
+        return NodeChangeResponse::NotFound(());
+    }
+
+    let mut overwrite = false;
+    if dest.exists() {
+        match conflict.unwrap_or(ConflictPolicy::Fail) {
+            ConflictPolicy::Overwrite if src.is_file() && dest.is_file() => overwrite = true,
+            ConflictPolicy::Overwrite => {
+                return NodeChangeResponse::Conflict("Only files can be overwritten")
+            }
+            ConflictPolicy::Rename => match free_file_name(&dest) {
+                Some(free) => dest = free,
+                None => return NodeChangeResponse::Conflict("No free name found"),
+            },
+            ConflictPolicy::Fail => return NodeChangeResponse::Conflict("Destination exists"),
+        }
+    }
+
+    // moves stay in the users files, so only copies change the usage
+    let size = match op {
+        Operation::Move => sizes::size_of(db, &owner, &src),
+        Operation::Copy => sizes::node_size(&src),
+    };
+    let copied = if op == Operation::Copy { size } else { 0 };
+    // the overwritten file stays as version (counted by `archive_version`), so it only moves out of the users root
+    let replaced = if overwrite {
+        std::fs::metadata(&dest).map(|md| md.len()).unwrap_or(0)
+    } else {
+        0
+    };
+    // only the growth of the root needs to fit into the quota
+    let reserved = copied.saturating_sub(replaced);
+    if !quota::reserve(db, &owner, reserved) {
+        return NodeChangeResponse::QuotaExceeded("Not enough space left");
+    }
+    if overwrite {
+        info!("{} overwrites {:?}", owner, dest);
+        if let Err(e) = super::versions::archive_version(db, &owner, &dest) {
+            error!("Failed to keep version of {:?}: {:?}", dest, e);
+            quota::change(db, &owner, -(reserved as i64));
+            return NodeChangeResponse::ServerError(());
+        }
+    }
+
+    let (task_src, task_dest) = (src.clone(), dest.clone());
+    let res = rocket::tokio::task::spawn_blocking(move || {
+        match op {
+            Operation::Move => std::fs::rename(&task_src, &task_dest)?,
+            Operation::Copy => copy_recursive(&task_src, &task_dest)?,
+        }
+        // previews are cached by path, so the overwritten ones are stale and the moved ones orphaned
+        previews::invalidate_cache(&task_dest);
+        if op == Operation::Move {
+            previews::invalidate_moved_cache(&task_src, &task_dest);
+        }
+        Ok::<_, std::io::Error>(())
+    })
+    .await;
+    match res {
+        Ok(Ok(())) => {
+            quota::change(db, &owner, copied as i64 - replaced as i64 - reserved as i64);
+            if op == Operation::Move {
+                sizes::removed(db, &owner, &src, size);
+                search::removed(db, &owner, &src);
+            }
+            sizes::changed(db, &owner, &dest, size as i64 - replaced as i64);
+            search::added(db, &owner, &dest);
+        }
+        Ok(Err(e)) => {
+            error!("Failed to move / copy {:?} to {:?}: {:?}", src, dest, e);
+            quota::change(db, &owner, -(reserved as i64));
+            return NodeChangeResponse::ServerError(());
+        }
+        Err(e) => {
+            error!("Move / copy task failed: {:?}", e);
+            quota::change(db, &owner, -(reserved as i64));
+            return NodeChangeResponse::ServerError(());
+        }
+    }
+
+    let mut user_root = PathBuf::from(crate::config::data_path());
+    user_root.push(&owner.0);
+    let new_rel = NetFilePath::from_path(dest.strip_prefix(&user_root).unwrap_or(dest_rel));
+
+    if op == Operation::Move {
+        match db.move_shares(&owner, src_rel, Borrow::<Path>::borrow(&new_rel)) {
+            Ok(0) => {}
+            Ok(n) => info!("Moved {} shares of {} to {:?}", n, owner, new_rel),
+            Err(e) => error!("Failed to update shares after move: {:?}", e),
+        }
+        if let Err(e) = db.move_versions(&owner, src_rel, Borrow::<Path>::borrow(&new_rel)) {
+            error!("Failed to update versions after move: {:?}", e);
+        }
+        if let Err(e) = db.move_user_shares(&owner, src_rel, Borrow::<Path>::borrow(&new_rel)) {
+            error!("Failed to update user shares after move: {:?}", e);
+        }
+    }
+
+    info!("{} {} {:?} to {:?} of {}", user_id, if op == Operation::Move { "moved" } else { "copied" }, requested, new_rel, owner);
+    // path as seen by the user
+    let path = match dest_share {
+        Some(share) => share.to_virtual(Borrow::<Path>::borrow(&new_rel)).into(),
+        None => new_rel.into(),
+    };
+    NodeChangeResponse::Accepted(Json(ChangedNode { path }))
+}
+
+/// Owner of the node and its path in the owners tree, folders shared with the user need `access`.
+/// The shared folders themselves belong to the owner, they can't be moved, copied or replaced through `SHARED_WITH_ME`
+fn resolve_node(
+    db: &SharedDatabase,
+    user_id: &UserID,
+    path: NetFilePath,
+    access: Access,
+) -> Result<(UserID, NetFilePath, Option<SharedBase>), NodeChangeResponse> {
+    match user_shares::resolve(db, user_id, path, access) {
+        Ok(ResolvedPath::Node { share: Some(share), path, .. })
+            if Borrow::<Path>::borrow(&path) == share.owner_path.as_path() =>
+        {
+            Err(NodeChangeResponse::Forbidden("Can't move or copy folders shared with you"))
+        }
+        Ok(ResolvedPath::Node { owner, path, share }) => Ok((owner, path, share)),
+        Ok(ResolvedPath::SharedRoot) => Err(NodeChangeResponse::Forbidden("Can't move or copy folders shared with you")),
+        Err(ResolveError::NotFound) => Err(NodeChangeResponse::NotFound(())),
+        Err(ResolveError::Forbidden) => Err(NodeChangeResponse::Forbidden("Not permitted")),
+    }
+}
+
+/// Like std::fs::copy, but copies folders with all children. Symlinks are skipped.
+/// Files are copied next to `dest` and renamed over it, an existing file at `dest` can be
+/// hard linked as version (see `versions::archive_version`) and must not be rewritten
+fn copy_recursive(src: &Path, dest: &Path) -> std::io::Result<()> {
+    let file_type = std::fs::symlink_metadata(src)?.file_type();
+    if file_type.is_dir() {
+        std::fs::create_dir(dest)?;
+        for entry in src.read_dir()? {
+            let entry = entry?;
+            copy_recursive(&entry.path(), &dest.join(entry.file_name()))?;
+        }
+    } else if file_type.is_file() {
+        let token: String = crate::utils::get_rand_token::<8>().iter().map(|e| *e as char).collect();
+        let name = dest.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+        let partial = dest.with_file_name(format!(".{}.{}.copy", name, token));
+        let copied = std::fs::copy(src, &partial).and_then(|_| std::fs::rename(&partial, dest));
+        if copied.is_err() {
+            let _ = std::fs::remove_file(&partial);
+        }
+        copied?;
+    } else {
+        warn!("Skipping {:?} while copying, not a file or folder", src);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_copy_recursive() {
+        let mut dir = std::env::temp_dir();
+        dir.push(format!("what-cloud-copy-test-{}", std::process::id()));
+        let src = dir.join("src");
+        std::fs::create_dir_all(src.join("sub")).unwrap();
+        std::fs::write(src.join("a.txt"), b"a").unwrap();
+        std::fs::write(src.join("sub").join("b.txt"), b"b").unwrap();
+
+        let dest = dir.join("dest");
+        copy_recursive(&src, &dest).unwrap();
+        assert_eq!(std::fs::read(dest.join("a.txt")).unwrap(), b"a");
+        assert_eq!(std::fs::read(dest.join("sub").join("b.txt")).unwrap(), b"b");
+        // source is untouched
+        assert!(src.join("sub").join("b.txt").is_file());
+
+        // overwriting replaces the file, links to the old one (versions) keep their content
+        std::fs::hard_link(dest.join("a.txt"), dir.join("version")).unwrap();
+        std::fs::write(src.join("a.txt"), b"new").unwrap();
+        copy_recursive(&src.join("a.txt"), &dest.join("a.txt")).unwrap();
+        assert_eq!(std::fs::read(dest.join("a.txt")).unwrap(), b"new");
+        assert_eq!(std::fs::read(dir.join("version")).unwrap(), b"a");
+        assert_eq!(std::fs::read_dir(&dest).unwrap().count(), 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
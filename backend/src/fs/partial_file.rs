@@ -32,6 +32,13 @@ impl PartialFile {
 }
 
 
+impl PartialFile {
+    /// Bytes that get sent
+    pub fn len(&self) -> u64 {
+        self.range.end() - self.range.start() + 1
+    }
+}
+
 impl<'r> Responder<'r, 'static> for PartialFile {
     fn respond_to(self, _request: &'r Request<'_>) -> response::Result<'static> {
         let start = *self.range.start();
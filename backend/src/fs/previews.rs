@@ -1,6 +1,8 @@
 use crate::auth::hash_str_to_hex;
-use crate::fs::to_abs_data_path;
+use crate::fs::contained_data_path;
 use crate::fs::NetFilePath;
+use crate::fs::shared::{self, ShareAccessKind, ShareClient, ShareDenied, SharePermissions, ShareUnlockToken};
+use crate::fs::user_shares::{self, Access, ResolvedPath};
 use crate::fs::SharedDatabase;
 use crate::fs::UserID;
 use log::{error, info, warn};
@@ -18,8 +20,14 @@ pub enum ImagePreviewResponse {
     WrongSize(&'static str),
     #[response(status = 406)]
     NoImage(&'static str),
+    #[response(status = 401)]
+    Locked(&'static str),
+    #[response(status = 403)]
+    Forbidden(&'static str),
     #[response(status = 404)]
     NotFound(()),
+    #[response(status = 410)]
+    Gone(&'static str),
     // TODO allow even if cache doesn't work?
     #[response(status = 500)]
     ServerError(()),
@@ -53,6 +61,47 @@ pub fn cache_path() -> std::path::PathBuf {
     cache_dir
 }
 
+/// Removes all cached previews of the files at or below abs_path, e.g. if they got moved or overwritten
+pub fn invalidate_cache(abs_path: &Path) {
+    invalidate_moved_cache(abs_path, abs_path)
+}
+
+/// Like `invalidate_cache` for the old paths of the nodes moved from `old_path` to `new_path`,
+/// called after the move, so the files are found at `new_path`
+pub fn invalidate_moved_cache(old_path: &Path, new_path: &Path) {
+    fn collect_hashes(path: &Path, as_path: &Path, hashes: &mut Vec<String>) {
+        if path.is_dir() {
+            if let Ok(dir) = path.read_dir() {
+                for entry in dir.flatten() {
+                    collect_hashes(&entry.path(), &as_path.join(entry.file_name()), hashes);
+                }
+            }
+        } else if let Some(p) = as_path.to_str() {
+            hashes.push(hash_str_to_hex(p)[0..30].to_string());
+        }
+    }
+
+    let mut hashes = Vec::new();
+    collect_hashes(new_path, old_path, &mut hashes);
+    if hashes.is_empty() {
+        return;
+    }
+
+    let cache_dir = match cache_path().read_dir() {
+        Ok(dir) => dir,
+        Err(_) => return,
+    };
+    for dentry in cache_dir.flatten() {
+        let fname = dentry.file_name();
+        let fname = fname.to_string_lossy();
+        if hashes.iter().any(|h| fname.contains(h.as_str())) {
+            if let Err(e) = std::fs::remove_file(dentry.path()) {
+                warn!("Failed to remove cached preview {:?}: {:?}", fname, e);
+            }
+        }
+    }
+}
+
 fn get_highest_cached(cache_dir: &Path, hashed_path: &str) -> Option<u32> {
     let mut highest = None;
 
@@ -72,16 +121,27 @@ fn get_highest_cached(cache_dir: &Path, hashed_path: &str) -> Option<u32> {
     highest
 }
 
-#[get("/preview/file?<path>&<token>&<resolution>", rank = 1)]
+/// token gets read by the `UserID` guard from the `token` query parameter
+#[get("/preview/file?<path>&<resolution>", rank = 2)]
 pub async fn preview_image(
     path: NetFilePath,
     token: UserID,
+    db: &State<SharedDatabase>,
     resolution: Option<u32>,
 ) -> ImagePreviewResponse {
-    let abs_path = to_abs_data_path(&token, Borrow::<Path>::borrow(&path));
-    if !abs_path.is_file() {
-        return ImagePreviewResponse::NotFound(());
+    match user_shares::resolve(db, &token, path, Access::Read) {
+        Ok(ResolvedPath::Node { owner, path, .. }) => preview_node(path, owner, resolution).await,
+        Ok(ResolvedPath::SharedRoot) | Err(_) => ImagePreviewResponse::NotFound(()),
     }
+}
+
+/// `path` from the root of `owner`
+async fn preview_node(path: NetFilePath, owner: UserID, resolution: Option<u32>) -> ImagePreviewResponse {
+    let abs_path = match contained_data_path(&owner, Borrow::<Path>::borrow(&path)) {
+        Some(p) if p.is_file() => p,
+        Some(_) => return ImagePreviewResponse::NotFound(()),
+        None => return ImagePreviewResponse::Forbidden("Path leaves the root folder"),
+    };
 
     // fails if extension is unknwon or no extension present
     if !abs_path
@@ -150,17 +210,31 @@ pub async fn preview_image(
     }
 }
 
-#[get("/preview/file?<path>&<shared_id>&<resolution>", rank = 2)]
+#[get("/preview/file?<path>&<shared_id>&<resolution>", rank = 1)]
 pub async fn preview_image_shared(
-    mut path: NetFilePath,
+    path: NetFilePath,
     shared_id: &str,
     db: &State<SharedDatabase>,
+    share_token: ShareUnlockToken,
+    client: ShareClient,
     resolution: Option<u32>,
 ) -> ImagePreviewResponse {
     if let Some(se) = db.get_shared_entry(&shared_id) {
-        path.add_prefix(&se.path);
-
-        preview_image(path, se.user, resolution).await
+        match se.check_access(&share_token, SharePermissions::PREVIEW) {
+            Ok(()) => {}
+            Err(ShareDenied::Expired) => return ImagePreviewResponse::Gone("Share expired"),
+            Err(ShareDenied::Locked) => return ImagePreviewResponse::Locked("Share is password protected"),
+            Err(ShareDenied::NotPermitted) => return ImagePreviewResponse::Forbidden("Preview not permitted"),
+        }
+        let requested = path.as_str().to_owned();
+        let res = match se.node_path(path) {
+            Some(path) => preview_node(path, se.user.clone(), resolution).await,
+            None => ImagePreviewResponse::NotFound(()),
+        };
+        if let ImagePreviewResponse::Preview(_) = res {
+            shared::log_access(db, &se, &client, ShareAccessKind::Preview, &requested, None);
+        }
+        res
     } else {
         ImagePreviewResponse::NotFound(())
     }
@@ -0,0 +1,96 @@
+//! Storage usage of the users. The usage counts all files of the user: the root, trash and versions.
+//! It gets updated incrementally by the operations changing files, `reserve` checks the quota before adding.
+//! Files changed outside of the server are picked up by `recalculate_usage` (`backend users recalc`)
+
+use super::sizes::node_size;
+use super::{to_abs_data_path, trash, versions};
+use crate::auth::UserID;
+use crate::database::{GetUserQuery, SharedDatabase};
+use log::{error, info};
+use rocket::response::status;
+use rocket::serde::json::Json;
+use rocket::State;
+
+/// Usage from the files on disk
+pub fn calculate_usage(user_id: &UserID) -> u64 {
+    node_size(&to_abs_data_path(user_id, ""))
+        + node_size(&trash::trash_dir(user_id))
+        + node_size(&versions::versions_dir(user_id))
+}
+
+pub fn recalculate_usage(db: &SharedDatabase, user_id: &UserID) -> rusqlite::Result<u64> {
+    let used = calculate_usage(user_id);
+    db.set_used_bytes(user_id, used)?;
+    Ok(used)
+}
+
+/// Calculates the usage of users that don't have one yet, call before the server starts
+pub fn init_usage(db: &SharedDatabase) {
+    let users = match db.get_users_without_usage() {
+        Ok(users) => users,
+        Err(e) => {
+            error!("Failed to load users without usage: {:?}", e);
+            return;
+        }
+    };
+    for user_id in users {
+        match recalculate_usage(db, &user_id) {
+            Ok(used) => info!("Calculated usage of {}: {} bytes", user_id, used),
+            Err(e) => error!("Failed to store usage of {}: {:?}", user_id, e),
+        }
+    }
+}
+
+/// Bytes the user can still store, None without quota
+pub fn available_bytes(db: &SharedDatabase, user_id: &UserID) -> rusqlite::Result<Option<u64>> {
+    let user = db.get_user(GetUserQuery::ByID(user_id))?;
+    Ok(user
+        .quota_bytes
+        .map(|quota| quota.saturating_sub(user.used_bytes.unwrap_or(0))))
+}
+
+/// Adds `bytes` to the usage, false if that would exceed the quota
+pub(super) fn reserve(db: &SharedDatabase, user_id: &UserID, bytes: u64) -> bool {
+    match db.reserve_usage(user_id, bytes) {
+        Ok(reserved) => reserved,
+        Err(e) => {
+            error!("Failed to reserve {} bytes for {}: {:?}", bytes, user_id, e);
+            false
+        }
+    }
+}
+
+/// Changes the usage without checking the quota, negative for removed files
+pub(super) fn change(db: &SharedDatabase, user_id: &UserID, delta: i64) {
+    if delta == 0 {
+        return;
+    }
+    if let Err(e) = db.change_usage(user_id, delta) {
+        error!("Failed to change usage of {} by {}: {:?}", user_id, delta, e);
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct Usage {
+    #[serde(rename = "usedBytes")]
+    used_bytes: u64,
+    /// null without quota
+    #[serde(rename = "quotaBytes")]
+    quota_bytes: Option<u64>,
+    #[serde(rename = "availableBytes")]
+    available_bytes: Option<u64>,
+}
+
+#[get("/user/usage")]
+pub fn get_usage(user_id: UserID, db: &State<SharedDatabase>) -> Result<Json<Usage>, status::Custom<()>> {
+    let user = db.get_user(GetUserQuery::ByID(&user_id)).map_err(|e| {
+        error!("Failed to load usage of {}: {:?}", user_id, e);
+        status::Custom(rocket::http::Status::InternalServerError, ())
+    })?;
+    let used_bytes = user.used_bytes.unwrap_or(0);
+    Ok(Json(Usage {
+        used_bytes,
+        quota_bytes: user.quota_bytes,
+        available_bytes: user.quota_bytes.map(|quota| quota.saturating_sub(used_bytes)),
+    }))
+}
@@ -0,0 +1,436 @@
+//! Search over the names and metadata of the files and folders in the users roots, backed by the `FILE_INDEX` table.
+//! Like the folder sizes the routes changing files update the index, the rescan job of `sizes` rebuilds it
+//! so changes from outside of the server get picked up as well
+
+use super::metadata::{self, NodeMetadata};
+use super::netfilepath::NetFilePath;
+use super::shared::{ShareAccessKind, ShareClient, ShareDenied, SharePermissions, ShareUnlockToken};
+use super::user_shares::{self, Access, ResolveError, ResolvedPath};
+use super::{contained_data_path, sizes, to_abs_data_path};
+use crate::auth::UserID;
+use crate::database::{DBIndexEntry, SharedDatabase};
+use chrono::TimeZone;
+use log::{error, info, warn};
+use rocket::serde::json::Json;
+use rocket::State;
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Results per page without `limit`
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 1000;
+
+/// How the lowercase name gets matched
+#[derive(Debug, PartialEq)]
+pub enum NamePattern {
+    Substring(String),
+    /// `*`, `?` and `[...]` like in a shell, matches the whole name
+    Glob(String),
+}
+
+impl NamePattern {
+    /// Globs if `query` contains a wildcard, None if it is empty
+    pub fn parse(query: &str) -> Option<Self> {
+        let query = query.trim().to_lowercase();
+        if query.is_empty() {
+            None
+        } else if query.contains(&['*', '?', '['][..]) {
+            Some(NamePattern::Glob(query))
+        } else {
+            Some(NamePattern::Substring(query))
+        }
+    }
+}
+
+/// Conditions of a search, all set ones need to match
+#[derive(Debug, Default)]
+pub struct SearchFilter {
+    pub name: Option<NamePattern>,
+    pub is_folder: Option<bool>,
+    /// lowercase without dot, any of them
+    pub extensions: Vec<String>,
+    /// sizes only match files
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    /// unix timestamps, after is inclusive and before exclusive
+    pub modified_after: Option<i64>,
+    pub modified_before: Option<i64>,
+}
+
+#[derive(FromFormField, Debug, Clone, Copy, PartialEq)]
+pub enum NodeType {
+    #[field(value = "file")]
+    File,
+    #[field(value = "folder")]
+    Folder,
+}
+
+/// Query parameters of `/search`, all optional. Without any of them everything below the path matches
+#[derive(FromForm, Debug)]
+pub struct SearchQuery {
+    /// substring of the name, or a glob if it contains `*`, `?` or `[`. Case insensitive
+    q: Option<String>,
+    #[field(name = "type")]
+    node_type: Option<NodeType>,
+    /// comma separated extensions, e.g. `jpg,png`
+    ext: Option<String>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    /// rfc3339 or yyyy-mm-dd (UTC)
+    modified_after: Option<String>,
+    modified_before: Option<String>,
+    limit: Option<usize>,
+    cursor: Option<String>,
+}
+
+/// Unix timestamp of a rfc3339 date or the start of a yyyy-mm-dd day in UTC
+fn parse_date(date: &str) -> Option<i64> {
+    if let Ok(date) = chrono::DateTime::parse_from_rfc3339(date) {
+        return Some(date.timestamp());
+    }
+    let day = chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()?;
+    Some(chrono::Utc.from_utc_datetime(&day.and_hms_opt(0, 0, 0)?).timestamp())
+}
+
+impl SearchQuery {
+    fn filter(&self) -> Result<SearchFilter, &'static str> {
+        let date = |date: &Option<String>| match date {
+            Some(date) => parse_date(date).map(Some).ok_or("Dates need to be rfc3339 or yyyy-mm-dd"),
+            None => Ok(None),
+        };
+        Ok(SearchFilter {
+            name: self.q.as_deref().and_then(NamePattern::parse),
+            is_folder: self.node_type.map(|t| t == NodeType::Folder),
+            extensions: self
+                .ext
+                .as_deref()
+                .unwrap_or_default()
+                .split(',')
+                .map(|ext| ext.trim().trim_start_matches('.').to_lowercase())
+                .filter(|ext| !ext.is_empty())
+                .collect(),
+            min_size: self.min_size,
+            max_size: self.max_size,
+            modified_after: date(&self.modified_after)?,
+            modified_before: date(&self.modified_before)?,
+        })
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct SearchResult {
+    /// like the paths of the request, so it can be passed to the other routes
+    path: String,
+    name: String,
+    metadata: NodeMetadata,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SearchResults {
+    results: Vec<SearchResult>,
+    /// only set if there are more results, pass it as `cursor` for the next page
+    #[serde(rename = "nextCursor", skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
+}
+
+#[derive(Responder, Debug)]
+pub enum SearchResponse {
+    #[response(status = 200)]
+    Results(Json<SearchResults>),
+    #[response(status = 400)]
+    BadRequest(&'static str),
+    #[response(status = 401)]
+    Locked(&'static str),
+    #[response(status = 403)]
+    Forbidden(&'static str),
+    #[response(status = 404)]
+    NotFound(&'static str),
+    #[response(status = 410)]
+    Gone(&'static str),
+    #[response(status = 500)]
+    ServerError(()),
+}
+
+/// Searches below `scope` (relative to the root of `owner`), `to_request_path` turns the paths
+/// from the owners root into the ones the client uses.
+/// The results contain the share ids only `with_shares`, they are secrets only the owner may see
+fn search(
+    db: &SharedDatabase,
+    owner: &UserID,
+    scope: &Path,
+    query: &SearchQuery,
+    to_request_path: impl Fn(&Path) -> NetFilePath,
+    with_shares: bool,
+) -> SearchResponse {
+    let filter = match query.filter() {
+        Ok(filter) => filter,
+        Err(e) => return SearchResponse::BadRequest(e),
+    };
+    let after = match &query.cursor {
+        Some(cursor) => match base64::decode_config(cursor, base64::URL_SAFE_NO_PAD).map(String::from_utf8) {
+            Ok(Ok(path)) => Some(path),
+            _ => return SearchResponse::BadRequest("Invalid cursor"),
+        },
+        None => None,
+    };
+    let scope_key = match contained_data_path(owner, scope) {
+        Some(abs) if abs.is_dir() => sizes::key(owner, &abs),
+        _ => None,
+    };
+    let scope_key = match scope_key {
+        Some(key) => key,
+        None => return SearchResponse::NotFound("Folder doesn't exist"),
+    };
+
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    // one more to know if there is a next page
+    let mut entries = match db.search_index(owner, &scope_key, &filter, after.as_deref(), limit + 1) {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!("Failed to search files of {}: {:?}", owner, e);
+            return SearchResponse::ServerError(());
+        }
+    };
+    let mut next_cursor = None;
+    if entries.len() > limit {
+        entries.truncate(limit);
+        next_cursor = entries
+            .last()
+            .map(|last| base64::encode_config(&last.path, base64::URL_SAFE_NO_PAD));
+    }
+
+    let shares: HashMap<PathBuf, String> = match with_shares {
+        true => db
+            .get_all_shared(owner)
+            .into_iter()
+            .map(|se| (se.path, se.share_id.as_ref().to_owned()))
+            .collect(),
+        false => HashMap::new(),
+    };
+    let results = entries
+        .into_iter()
+        .map(|entry| {
+            let path = PathBuf::from(&entry.path);
+            let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+            let size = if entry.is_folder {
+                sizes::folder_size(db, owner, &to_abs_data_path(owner, &path))
+            } else {
+                entry.size
+            };
+            let shared = shares.get(&path).cloned();
+            SearchResult {
+                path: to_request_path(&path).into(),
+                metadata: NodeMetadata::from_index(&name, entry.is_folder, size, entry.modified, shared),
+                name,
+            }
+        })
+        .collect();
+    SearchResponse::Results(Json(SearchResults { results, next_cursor }))
+}
+
+/// Searches below `path` of the share, the share needs to allow listing
+#[get("/search?<shared_id>&<path>&<query..>", rank = 1)]
+pub fn search_shared(
+    shared_id: &str,
+    path: NetFilePath,
+    query: SearchQuery,
+    share_token: ShareUnlockToken,
+    client: ShareClient,
+    db: &State<SharedDatabase>,
+) -> SearchResponse {
+    let se = match db.get_shared_entry(shared_id) {
+        Some(se) => se,
+        None => return SearchResponse::NotFound("Shared ID doesn't exist"),
+    };
+    match se.check_access(&share_token, SharePermissions::LIST) {
+        Ok(()) => {}
+        Err(ShareDenied::Expired) => return SearchResponse::Gone("Share expired"),
+        Err(ShareDenied::Locked) => return SearchResponse::Locked("Share is password protected"),
+        Err(ShareDenied::NotPermitted) => return SearchResponse::Forbidden("Listing not permitted"),
+    }
+    let requested = path.as_str().to_owned();
+    let scope = match se.node_path(path) {
+        Some(scope) => scope,
+        None => return SearchResponse::NotFound("Path doesn't exist"),
+    };
+    let res = search(
+        db,
+        &se.user,
+        Borrow::<Path>::borrow(&scope),
+        &query,
+        |p| NetFilePath::from_path(p.strip_prefix(&se.path).unwrap_or(p)),
+        false,
+    );
+    if let SearchResponse::Results(_) = res {
+        super::shared::log_access(db, &se, &client, ShareAccessKind::List, &requested, None);
+    }
+    res
+}
+
+/// Searches the own files below `path` (empty for the root), paths in `user_shares::SHARED_WITH_ME`
+/// search in the shared folder
+#[get("/search?<path>&<query..>", rank = 2)]
+pub fn search_files(
+    path: NetFilePath,
+    query: SearchQuery,
+    user_id: UserID,
+    db: &State<SharedDatabase>,
+) -> SearchResponse {
+    match user_shares::resolve(db, &user_id, path, Access::Read) {
+        Ok(ResolvedPath::SharedRoot) => SearchResponse::BadRequest("Search in one of the shared folders"),
+        Ok(ResolvedPath::Node { owner, path, share: None }) => {
+            search(db, &owner, Borrow::<Path>::borrow(&path), &query, |p| NetFilePath::from_path(p), true)
+        }
+        Ok(ResolvedPath::Node { owner, path, share: Some(share) }) => {
+            search(db, &owner, Borrow::<Path>::borrow(&path), &query, |p| share.to_virtual(p), false)
+        }
+        Err(ResolveError::NotFound) => SearchResponse::NotFound("Path doesn't exist"),
+        Err(ResolveError::Forbidden) => SearchResponse::Forbidden("Not permitted"),
+    }
+}
+
+/// Index entry of the node at `key`, None for the root and things that are neither files nor folders
+pub(super) fn entry(key: String, meta: &std::fs::Metadata) -> Option<DBIndexEntry> {
+    if key.is_empty() || !(meta.is_dir() || meta.is_file()) {
+        return None;
+    }
+    Some(DBIndexEntry {
+        path: key,
+        is_folder: meta.is_dir(),
+        size: if meta.is_file() { meta.len() } else { 0 },
+        modified: metadata::modified_secs(meta),
+    })
+}
+
+/// Pushes the entries of the node at `abs_path` and all nodes below it. Symlinks are not followed
+fn collect(abs_path: &Path, key: String, entries: &mut Vec<DBIndexEntry>) {
+    let meta = match std::fs::symlink_metadata(abs_path) {
+        Ok(meta) => meta,
+        Err(_) => return,
+    };
+    if meta.is_dir() {
+        match std::fs::read_dir(abs_path) {
+            Ok(children) => {
+                for child in children.filter_map(Result::ok) {
+                    let name = child.file_name();
+                    let child_key = if key.is_empty() {
+                        name.to_string_lossy().into_owned()
+                    } else {
+                        format!("{}/{}", key, name.to_string_lossy())
+                    };
+                    collect(&child.path(), child_key, entries);
+                }
+            }
+            Err(e) => warn!("Failed to read {:?} while indexing: {:?}", abs_path, e),
+        }
+    }
+    entries.extend(entry(key, &meta));
+}
+
+/// Entries of the folders containing `key`, their modification time changes with their content
+fn parent_entries(user_id: &UserID, key: &str) -> Vec<DBIndexEntry> {
+    sizes::ancestors(key)
+        .into_iter()
+        .filter_map(|parent| {
+            let meta = std::fs::symlink_metadata(to_abs_data_path(user_id, &parent)).ok()?;
+            entry(parent, &meta)
+        })
+        .collect()
+}
+
+/// The node at `abs_path` got created or changed, indexes it with everything below it
+pub(super) fn added(db: &SharedDatabase, user_id: &UserID, abs_path: &Path) {
+    if let Some(key) = sizes::key(user_id, abs_path) {
+        let mut entries = parent_entries(user_id, &key);
+        collect(abs_path, key, &mut entries);
+        if let Err(e) = db.upsert_index_entries(user_id, &entries) {
+            error!("Failed to index {:?}: {:?}", abs_path, e);
+        }
+    }
+}
+
+/// The node at `abs_path` got moved away or deleted, removes it with everything below it
+pub(super) fn removed(db: &SharedDatabase, user_id: &UserID, abs_path: &Path) {
+    if let Some(key) = sizes::key(user_id, abs_path) {
+        let res = db
+            .delete_index_below(user_id, &key)
+            .and_then(|_| db.upsert_index_entries(user_id, &parent_entries(user_id, &key)));
+        if let Err(e) = res {
+            error!("Failed to remove {:?} from the index: {:?}", abs_path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_name_pattern() {
+        assert_eq!(NamePattern::parse("  "), None);
+        assert_eq!(NamePattern::parse("Report"), Some(NamePattern::Substring("report".into())));
+        assert_eq!(NamePattern::parse("*.JPG"), Some(NamePattern::Glob("*.jpg".into())));
+        assert_eq!(parse_date("2021-03-04"), Some(1614816000));
+        assert_eq!(parse_date("2021-03-04T01:00:00+01:00"), Some(1614816000));
+        assert_eq!(parse_date("yesterday"), None);
+    }
+
+    #[test]
+    fn test_search_index() {
+        let mut path = std::env::temp_dir();
+        path.push(format!("what-cloud-search-test-{}.sqlite", std::process::id()));
+        let db = SharedDatabase::new(&path);
+        let user = UserID("user0001".into());
+        let entry = |path: &str, is_folder, size, modified| DBIndexEntry {
+            path: path.into(),
+            is_folder,
+            size,
+            modified,
+        };
+        db.replace_index(
+            &user,
+            &[
+                entry("Photos", true, 0, 100),
+                entry("Photos/Beach.JPG", false, 3000, 200),
+                entry("Photos/notes.txt", false, 10, 300),
+                entry("Photos0.txt", false, 10, 300),
+                entry("work", true, 0, 100),
+                entry("work/photos.pdf", false, 500, 400),
+            ],
+        )
+        .unwrap();
+        let paths = |scope: &str, filter: &SearchFilter, after: Option<&str>| -> Vec<String> {
+            let entries = db.search_index(&user, scope, filter, after, 10).unwrap();
+            entries.into_iter().map(|e| e.path).collect()
+        };
+
+        let name = |q: &str| SearchFilter {
+            name: NamePattern::parse(q),
+            ..SearchFilter::default()
+        };
+        assert_eq!(paths("", &name("photo"), None), vec!["Photos", "Photos0.txt", "work/photos.pdf"]);
+        assert_eq!(paths("", &name("*.jpg"), None), vec!["Photos/Beach.JPG"]);
+        assert_eq!(paths("Photos", &SearchFilter::default(), None), vec!["Photos/Beach.JPG", "Photos/notes.txt"]);
+        assert_eq!(paths("", &name("photo"), Some("Photos0.txt")), vec!["work/photos.pdf"]);
+
+        let filter = SearchFilter {
+            extensions: vec!["jpg".into(), "pdf".into()],
+            min_size: Some(1000),
+            ..SearchFilter::default()
+        };
+        assert_eq!(paths("", &filter, None), vec!["Photos/Beach.JPG"]);
+        let filter = SearchFilter {
+            is_folder: Some(false),
+            modified_after: Some(300),
+            modified_before: Some(400),
+            ..SearchFilter::default()
+        };
+        assert_eq!(paths("", &filter, None), vec!["Photos/notes.txt", "Photos0.txt"]);
+
+        assert_eq!(db.delete_index_below(&user, "Photos").unwrap(), 3);
+        assert_eq!(paths("", &name("photo"), None), vec!["Photos0.txt", "work/photos.pdf"]);
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
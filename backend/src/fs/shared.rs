@@ -1,7 +1,9 @@
-use crate::auth::UserID;
+use crate::auth::{UserID, WritableUser};
 use crate::database::SharedDatabase;
 use crate::fs::NetFilePath;
-use log::info;
+use log::{error, info, warn};
+use rocket::http::Status;
+use rocket::response::status;
 use rocket::State;
 use rocket::serde::json::Json;
 use std::borrow::Borrow;
@@ -40,18 +42,129 @@ impl std::convert::AsRef<str> for SharedID {
     }
 }
 
-/// Set folders / files shared state
-/// upload_limit in mb
-#[patch("/folder/shared?<path>&<enabled>&<upload_limit>")]
+/// Expired shares are reported as gone for this long before they get deleted
+const EXPIRED_SHARE_GRACE_DAYS: u32 = 7;
+/// Entries of the share access log are kept this long
+const ACCESS_LOG_RETENTION_DAYS: u32 = 90;
+/// Range requests of the same ip for the same path continue a counted download for this long
+pub const DOWNLOAD_CONTINUE_MINUTES: u32 = 60;
+/// How often the cleanup job looks for expired shares
+const CLEANUP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+/// What visitors of a share are allowed to do, stored in the FLAGS column
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SharePermissions(pub u32);
+
+impl SharePermissions {
+    pub const LIST: Self = Self(1);
+    pub const DOWNLOAD: Self = Self(1 << 1);
+    pub const PREVIEW: Self = Self(1 << 2);
+    /// uploads also need an upload limit
+    pub const UPLOAD: Self = Self(1 << 3);
+    pub const DELETE: Self = Self(1 << 4);
+    /// replace existing files with uploads
+    pub const OVERWRITE: Self = Self(1 << 5);
+
+    const NAMES: [(&'static str, Self); 6] = [
+        ("list", Self::LIST),
+        ("download", Self::DOWNLOAD),
+        ("preview", Self::PREVIEW),
+        ("upload", Self::UPLOAD),
+        ("delete", Self::DELETE),
+        ("overwrite", Self::OVERWRITE),
+    ];
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Comma separated names, e.g. `list,preview`
+    pub fn parse(names: &str) -> Option<Self> {
+        names
+            .split(',')
+            .map(str::trim)
+            .filter(|n| !n.is_empty())
+            .try_fold(Self(0), |acc, name| {
+                Self::NAMES
+                    .iter()
+                    .find(|(n, _)| *n == name)
+                    .map(|(_, p)| Self(acc.0 | p.0))
+            })
+    }
+}
+
+impl Default for SharePermissions {
+    /// what shares could do before permissions existed
+    fn default() -> Self {
+        Self(Self::LIST.0 | Self::DOWNLOAD.0 | Self::PREVIEW.0)
+    }
+}
+
+/// Serialized as list of names
+impl serde::Serialize for SharePermissions {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(
+            Self::NAMES
+                .iter()
+                .filter(|(_, p)| self.contains(*p))
+                .map(|(n, _)| n),
+        )
+    }
+}
+
+/// Set folders / files shared state.
+/// Shared files can only be listed, downloaded and previewed
+/// upload_limit in mb, expires_at as rfc 3339 (e.g. 2021-06-01T12:00:00Z), max_downloads 0 for unlimited,
+/// permissions as comma separated names (see `SharePermissions`), defaults to list, download, preview
+/// and upload if upload_limit is set
+#[patch("/folder/shared?<path>&<enabled>&<upload_limit>&<expires_at>&<max_downloads>&<permissions>")]
 pub fn update_folder_share(
     path: NetFilePath,
     enabled: bool,
     upload_limit: Option<u32>,
-    user_id: UserID,
+    expires_at: Option<&str>,
+    max_downloads: Option<u32>,
+    permissions: Option<&str>,
+    writable: WritableUser,
     db: &State<SharedDatabase>,
 ) -> Result<SharedID, ()> {
-    let combined: PathBuf = super::to_abs_data_path(&user_id, Borrow::<Path>::borrow(&path));
-    if !combined.exists() || combined.is_file() {
+    let user_id = writable.0;
+    let combined: PathBuf = match super::contained_data_path(&user_id, Borrow::<Path>::borrow(&path)) {
+        Some(p) if p.exists() => p,
+        _ => return Err(()),
+    };
+    // stored like sqlite's datetime('now'), so it can be compared in queries
+    let expires_at = match expires_at.map(chrono::DateTime::parse_from_rfc3339) {
+        Some(Ok(date)) => Some(
+            date.with_timezone(&chrono::Utc)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string(),
+        ),
+        Some(Err(e)) => {
+            warn!("Invalid share expiry date: {:?}", e);
+            return Err(());
+        }
+        None => None,
+    };
+    let permissions = match permissions {
+        Some(names) => match SharePermissions::parse(names) {
+            Some(p) => p,
+            None => {
+                warn!("Invalid share permissions: {}", names);
+                return Err(());
+            }
+        },
+        None if upload_limit.unwrap_or(0) > 0 => {
+            SharePermissions(SharePermissions::default().0 | SharePermissions::UPLOAD.0)
+        }
+        None => SharePermissions::default(),
+    };
+    // a shared file has no children to upload or delete
+    let folder_only = SharePermissions(
+        SharePermissions::UPLOAD.0 | SharePermissions::DELETE.0 | SharePermissions::OVERWRITE.0,
+    );
+    if combined.is_file() && (permissions.0 & folder_only.0 != 0 || upload_limit.unwrap_or(0) > 0) {
+        warn!("Tried to share file {:?} with folder permissions", path);
         return Err(());
     }
     // create new share
@@ -60,6 +173,9 @@ pub fn update_folder_share(
         Borrow::<Path>::borrow(&path),
         enabled,
         upload_limit,
+        expires_at,
+        max_downloads,
+        permissions,
     );
     info!("User {} set shared of {:?} to {:?}", &user_id, &path, &r);
     r.ok_or(())
@@ -72,9 +188,470 @@ pub struct SharedEntry {
     pub share_id: SharedID,
     pub user: UserID,
     pub path: PathBuf,
+    /// in mb, uploads are only allowed if set
+    pub upload_limit: Option<u32>,
+    pub uploaded_bytes: u64,
+    /// `YYYY-MM-DD HH:MM:SS` (UTC), set when the last allowed download happened
+    pub expires_at: Option<String>,
+    pub max_downloads: Option<u32>,
+    pub downloads: u32,
+    /// expired or all downloads used, the share can't be accessed anymore
+    pub expired: bool,
+    /// needs a token from `unlock_share`
+    pub password_protected: bool,
+    #[serde(skip)]
+    pub password_hash: Option<String>,
+    pub permissions: SharePermissions,
+}
+
+/// Why a share can't be accessed
+#[derive(Debug, PartialEq)]
+pub enum ShareDenied {
+    Expired,
+    /// password protected and no valid token was sent
+    Locked,
+    /// the share doesn't have the required permission
+    NotPermitted,
+}
+
+impl SharedEntry {
+    /// Has to be checked by every route accessing the share
+    pub fn check_access(&self, token: &ShareUnlockToken, required: SharePermissions) -> Result<(), ShareDenied> {
+        if self.expired {
+            return Err(ShareDenied::Expired);
+        }
+        self.check_unlocked(token, required)
+    }
+
+    /// `check_access` for downloading `path` (relative to the share). The last allowed download expires the share,
+    /// range requests continuing it (see `DOWNLOAD_CONTINUE_MINUTES`) are still allowed
+    pub fn check_download(
+        &self,
+        db: &SharedDatabase,
+        token: &ShareUnlockToken,
+        path: &str,
+        ip: Option<&str>,
+        continued: bool,
+    ) -> Result<(), ShareDenied> {
+        let downloads_used = self.max_downloads.map(|max| self.downloads >= max).unwrap_or(false);
+        if self.expired && continued && downloads_used {
+            match db.is_download_counted(&self.share_id, path, ip, DOWNLOAD_CONTINUE_MINUTES) {
+                Ok(true) => return self.check_unlocked(token, SharePermissions::DOWNLOAD),
+                Ok(false) => {}
+                Err(e) => error!("Failed to check continued download of share: {:?}", e),
+            }
+        }
+        self.check_access(token, SharePermissions::DOWNLOAD)
+    }
+
+    /// Permission and password part of `check_access`
+    fn check_unlocked(&self, token: &ShareUnlockToken, required: SharePermissions) -> Result<(), ShareDenied> {
+        if !self.permissions.contains(required) {
+            return Err(ShareDenied::NotPermitted);
+        }
+        let password_hash = match &self.password_hash {
+            Some(hash) => hash,
+            None => return Ok(()),
+        };
+        let unlocked = token
+            .0
+            .as_deref()
+            .and_then(|t| crate::auth::jwt::validate_share_token(t).ok())
+            .map(|t| t.shared_id == self.share_id.0 && t.password_tag == password_tag(password_hash))
+            .unwrap_or(false);
+        if unlocked {
+            Ok(())
+        } else {
+            Err(ShareDenied::Locked)
+        }
+    }
+
+    /// Path of the node (from the owners root) at `path` inside the share.
+    /// A shared file has no children, so only the empty path resolves to it.
+    /// None if `path` leaves the shared folder, e.g. through a symlink
+    pub fn node_path(&self, mut path: NetFilePath) -> Option<NetFilePath> {
+        let share_root = super::contained_data_path(&self.user, &self.path)?;
+        if share_root.is_file() && !Borrow::<Path>::borrow(&path).as_os_str().is_empty() {
+            return None;
+        }
+        super::contained_path(&share_root, Borrow::<Path>::borrow(&path))?;
+        path.add_prefix(&self.path);
+        Some(path)
+    }
+
+    /// None if uploads are disabled for this share
+    pub fn remaining_upload_bytes(&self) -> Option<u64> {
+        self.upload_limit
+            .map(|limit| (limit as u64 * 1024 * 1024).saturating_sub(self.uploaded_bytes))
+    }
 }
 
 #[get("/shared")]
 pub fn get_my_shared(user_id: UserID, db: &State<SharedDatabase>) -> Json<Vec<SharedEntry>> {
     Json(db.get_all_shared(&user_id))
 }
+
+/// Client of a shared route, for the access log. Always succeeds.
+/// The ip is the one of the connection, headers like `X-Real-IP` can be set by the client
+pub struct ShareClient {
+    ip: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl ShareClient {
+    pub fn ip(&self) -> Option<&str> {
+        self.ip.as_deref()
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> rocket::request::FromRequest<'r> for ShareClient {
+    type Error = ();
+
+    async fn from_request(request: &'r rocket::Request<'_>) -> rocket::request::Outcome<Self, Self::Error> {
+        rocket::request::Outcome::Success(ShareClient {
+            ip: request.remote().map(|addr| addr.ip().to_string()),
+            user_agent: request.headers().get_one("User-Agent").map(String::from),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShareAccessKind {
+    List,
+    Download,
+    Preview,
+    Upload,
+    Delete,
+}
+
+impl ShareAccessKind {
+    fn name(self) -> &'static str {
+        match self {
+            ShareAccessKind::List => "list",
+            ShareAccessKind::Download => "download",
+            ShareAccessKind::Preview => "preview",
+            ShareAccessKind::Upload => "upload",
+            ShareAccessKind::Delete => "delete",
+        }
+    }
+}
+
+/// Records a successful access to the share, `path` as requested (relative to the share)
+pub fn log_access(
+    db: &SharedDatabase,
+    se: &SharedEntry,
+    client: &ShareClient,
+    kind: ShareAccessKind,
+    path: &str,
+    bytes: Option<u64>,
+) {
+    if let Err(e) = db.log_share_access(
+        &se.share_id,
+        kind.name(),
+        path,
+        client.ip.as_deref(),
+        client.user_agent.as_deref(),
+        bytes,
+    ) {
+        error!("Failed to log access to share {}: {:?}", se.share_id.0, e);
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct ShareAccessCount {
+    kind: String,
+    count: u64,
+    bytes: u64,
+    #[serde(rename = "lastAccess")]
+    last_access: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ShareAccessEvent {
+    #[serde(rename = "accessedAt")]
+    accessed_at: String,
+    kind: String,
+    path: String,
+    ip: Option<String>,
+    #[serde(rename = "userAgent")]
+    user_agent: Option<String>,
+    bytes: Option<u64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ShareStats {
+    counts: Vec<ShareAccessCount>,
+    /// newest first
+    recent: Vec<ShareAccessEvent>,
+}
+
+/// Access counters and the last `limit` (default 50) accesses of the share, only for the owner
+#[get("/shared/stats?<shared_id>&<limit>")]
+pub fn get_share_stats(
+    shared_id: &str,
+    limit: Option<u32>,
+    user_id: UserID,
+    db: &State<SharedDatabase>,
+) -> Result<Json<ShareStats>, status::Custom<()>> {
+    let se = match db.get_shared_entry(shared_id) {
+        Some(se) if se.user == user_id => se,
+        _ => return Err(status::Custom(Status::NotFound, ())),
+    };
+    let db_error = |e: rusqlite::Error| {
+        error!("Failed to load access log of share {}: {:?}", shared_id, e);
+        status::Custom(Status::InternalServerError, ())
+    };
+
+    let counts = db.get_share_access_counts(&se.share_id).map_err(db_error)?;
+    let recent = db
+        .get_share_access_log(&se.share_id, limit.unwrap_or(50).min(1000))
+        .map_err(db_error)?;
+    Ok(Json(ShareStats {
+        counts: counts
+            .into_iter()
+            .map(|c| ShareAccessCount {
+                kind: c.kind,
+                count: c.count,
+                bytes: c.bytes,
+                last_access: c.last_access,
+            })
+            .collect(),
+        recent: recent
+            .into_iter()
+            .map(|e| ShareAccessEvent {
+                accessed_at: e.accessed_at,
+                kind: e.kind,
+                path: e.path,
+                ip: e.ip,
+                user_agent: e.user_agent,
+                bytes: e.bytes,
+            })
+            .collect(),
+    }))
+}
+
+/// Starts a thread that deletes shares which expired more than `EXPIRED_SHARE_GRACE_DAYS` ago
+pub fn start_cleanup_job() {
+    let spawned = std::thread::Builder::new()
+        .name("Share cleanup".into())
+        .spawn(|| {
+            // own connection, the managed one is owned by rocket
+            let db = SharedDatabase::new(crate::config::db_path());
+            loop {
+                match db.delete_expired_shares(EXPIRED_SHARE_GRACE_DAYS) {
+                    Ok(0) => {}
+                    Ok(n) => info!("Deleted {} expired shares", n),
+                    Err(e) => error!("Failed to delete expired shares: {:?}", e),
+                }
+                if let Err(e) = db.delete_old_share_access(ACCESS_LOG_RETENTION_DAYS) {
+                    error!("Failed to delete old share access log entries: {:?}", e);
+                }
+                std::thread::sleep(CLEANUP_INTERVAL);
+            }
+        });
+    if let Err(e) = spawned {
+        error!("Failed to start share cleanup thread: {:?}", e);
+    }
+}
+
+/// Token from `unlock_share`, sent as `X-Share-Token` header or `share_token` query parameter
+/// (downloads and previews can't set headers). Always succeeds, checked by `SharedEntry::check_access`
+pub struct ShareUnlockToken(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> rocket::request::FromRequest<'r> for ShareUnlockToken {
+    type Error = ();
+
+    async fn from_request(request: &'r rocket::Request<'_>) -> rocket::request::Outcome<Self, Self::Error> {
+        let token = request
+            .headers()
+            .get_one("X-Share-Token")
+            .or_else(|| request.query_value::<&str>("share_token").and_then(Result::ok));
+        rocket::request::Outcome::Success(ShareUnlockToken(token.map(String::from)))
+    }
+}
+
+fn password_tag(password_hash: &str) -> String {
+    crate::auth::hash_str_to_hex(password_hash)[..16].to_string()
+}
+
+#[derive(Deserialize)]
+pub struct SharePassword {
+    /// None removes the password
+    #[serde(rename = "passwordBase64")]
+    password_base64: Option<String>,
+}
+
+/// Sets or removes the password of the share, all tokens of the old password get invalid
+#[put("/folder/shared/password?<shared_id>", data = "<password>")]
+pub fn set_share_password(
+    shared_id: &str,
+    password: Json<SharePassword>,
+    writable: WritableUser,
+    db: &State<SharedDatabase>,
+) -> Result<status::NoContent, status::Custom<()>> {
+    let user_id = writable.0;
+    let se = match db.get_shared_entry(shared_id) {
+        Some(se) if se.user == user_id => se,
+        _ => return Err(status::Custom(Status::NotFound, ())),
+    };
+
+    let hash = match password.password_base64.as_deref() {
+        Some("") => return Err(status::Custom(Status::BadRequest, ())),
+        Some(pw) => Some(crate::auth::hash_password(pw).map_err(|e| {
+            error!("Failed to hash share password: {:?}", e);
+            status::Custom(Status::InternalServerError, ())
+        })?),
+        None => None,
+    };
+    db.set_share_password(&se.share_id, hash.as_deref()).map_err(|e| {
+        error!("Failed to set share password: {:?}", e);
+        status::Custom(Status::InternalServerError, ())
+    })?;
+    info!("{} {} the password of share {}", user_id, if hash.is_some() { "set" } else { "removed" }, shared_id);
+    Ok(status::NoContent)
+}
+
+#[derive(Deserialize)]
+pub struct ShareUnlock {
+    #[serde(rename = "passwordBase64")]
+    password_base64: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct UnlockedShare {
+    token: String,
+    #[serde(rename = "expiresAt")]
+    expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Responder, Debug)]
+pub enum UnlockShareResponse {
+    #[response(status = 200)]
+    Unlocked(Json<UnlockedShare>),
+    #[response(status = 401)]
+    WrongPassword(&'static str),
+    #[response(status = 404)]
+    NotFound(()),
+    #[response(status = 410)]
+    Gone(&'static str),
+    #[response(status = 500)]
+    ServerError(()),
+}
+
+/// Exchanges the password of the share for a short lived token, see `ShareUnlockToken`
+#[post("/shared/unlock?<shared_id>", data = "<unlock>")]
+pub fn unlock_share(shared_id: &str, unlock: Json<ShareUnlock>, db: &State<SharedDatabase>) -> UnlockShareResponse {
+    let se = match db.get_shared_entry(shared_id) {
+        Some(se) => se,
+        None => return UnlockShareResponse::NotFound(()),
+    };
+    if se.expired {
+        return UnlockShareResponse::Gone("Share expired");
+    }
+    let password_hash = match &se.password_hash {
+        Some(hash) => hash,
+        None => return UnlockShareResponse::NotFound(()),
+    };
+    if crate::auth::verify_password(password_hash, &unlock.password_base64) != crate::auth::PasswordCheck::Valid {
+        info!("Wrong password for share {}", shared_id);
+        return UnlockShareResponse::WrongPassword("Wrong password");
+    }
+
+    let expires_at = crate::auth::jwt::ShareToken::expires_at();
+    let token = crate::auth::jwt::to_share_token(
+        crate::auth::jwt::ShareToken {
+            shared_id: se.share_id.0.clone(),
+            password_tag: password_tag(password_hash),
+        },
+        expires_at,
+    );
+    match token {
+        Ok(token) => UnlockShareResponse::Unlocked(Json(UnlockedShare { token, expires_at })),
+        Err(e) => {
+            error!("Failed to create share token: {}", e);
+            UnlockShareResponse::ServerError(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_permissions() {
+        assert_eq!(
+            SharePermissions::parse("list, preview"),
+            Some(SharePermissions(SharePermissions::LIST.0 | SharePermissions::PREVIEW.0))
+        );
+        assert_eq!(SharePermissions::parse("upload"), Some(SharePermissions::UPLOAD));
+        assert_eq!(SharePermissions::parse(""), Some(SharePermissions(0)));
+        assert_eq!(SharePermissions::parse("list,everything"), None);
+
+        let drop_box = SharePermissions::parse("upload").unwrap();
+        assert!(drop_box.contains(SharePermissions::UPLOAD));
+        assert!(!drop_box.contains(SharePermissions::LIST));
+        assert_eq!(
+            serde_json::to_string(&SharePermissions::default()).unwrap(),
+            r#"["list","download","preview"]"#
+        );
+    }
+
+    #[test]
+    fn test_count_download() {
+        let mut path = std::env::temp_dir();
+        path.push(format!("what-cloud-downloads-test-{}.sqlite", std::process::id()));
+        let db = SharedDatabase::new(&path);
+        let user = UserID("user0001".into());
+        let id = db
+            .update_share(&user, Path::new("a"), true, None, None, Some(2), SharePermissions::default())
+            .unwrap();
+        let count = |path, ip, continued| db.count_share_download(&id, path, ip, continued, DOWNLOAD_CONTINUE_MINUTES).unwrap();
+
+        assert!(count("x.txt", Some("1.2.3.4"), false));
+        // continues the counted download
+        assert!(count("x.txt", Some("1.2.3.4"), true));
+        assert_eq!(db.get_shared_entry(id.as_ref()).unwrap().downloads, 1);
+        // other clients and paths can't continue it
+        assert!(count("x.txt", Some("5.6.7.8"), true));
+        assert!(!count("y.txt", Some("1.2.3.4"), true));
+        assert!(!count("x.txt", None, false));
+        assert_eq!(db.get_shared_entry(id.as_ref()).unwrap().downloads, 2);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_continue_last_download() {
+        let mut path = std::env::temp_dir();
+        path.push(format!("what-cloud-last-download-test-{}.sqlite", std::process::id()));
+        let db = SharedDatabase::new(&path);
+        let user = UserID("user0001".into());
+        let id = db
+            .update_share(&user, Path::new("a"), true, None, None, Some(1), SharePermissions::default())
+            .unwrap();
+        let token = ShareUnlockToken(None);
+        // range request as the download client of a large file sends it
+        let request = |ip, continued| {
+            let se = db.get_shared_entry(id.as_ref()).unwrap();
+            se.check_download(&db, &token, "x.bin", ip, continued)?;
+            match db.count_share_download(&id, "x.bin", ip, continued, DOWNLOAD_CONTINUE_MINUTES).unwrap() {
+                true => Ok(()),
+                false => Err(ShareDenied::Expired),
+            }
+        };
+
+        assert_eq!(request(Some("1.2.3.4"), false), Ok(()));
+        assert!(db.get_shared_entry(id.as_ref()).unwrap().expired);
+        assert_eq!(request(Some("1.2.3.4"), true), Ok(()));
+        assert_eq!(request(Some("1.2.3.4"), true), Ok(()));
+        // but the share is used up for new downloads and other clients
+        assert_eq!(request(Some("1.2.3.4"), false), Err(ShareDenied::Expired));
+        assert_eq!(request(Some("5.6.7.8"), true), Err(ShareDenied::Expired));
+        assert_eq!(db.get_shared_entry(id.as_ref()).unwrap().downloads, 1);
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,252 @@
+//! Recursive sizes of the folders in the users roots, cached in the database.
+//! The routes that change files update the cached sizes of all folders containing the changed node,
+//! folders that are not cached yet get calculated when they are requested.
+//! A background job rescans all roots, so changes from outside of the server get picked up as well.
+//! The same scan rebuilds the search index (see `search`)
+
+use super::netfilepath::NetFilePath;
+use super::{search, to_abs_data_path};
+use crate::auth::UserID;
+use crate::database::{DBIndexEntry, SharedDatabase};
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// How often the background job rescans the roots
+const RECONCILE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(6 * 60 * 60);
+
+/// Size of the file, or of all files below the folder. Symlinks are not followed
+pub fn node_size(path: &Path) -> u64 {
+    let meta = match std::fs::symlink_metadata(path) {
+        Ok(meta) => meta,
+        Err(_) => return 0,
+    };
+    if meta.is_dir() {
+        match std::fs::read_dir(path) {
+            Ok(entries) => entries.filter_map(Result::ok).map(|entry| node_size(&entry.path())).sum(),
+            Err(e) => {
+                warn!("Failed to read {:?} while calculating the size: {:?}", path, e);
+                0
+            }
+        }
+    } else if meta.is_file() {
+        meta.len()
+    } else {
+        0
+    }
+}
+
+/// Path relative to the users root as stored in the database, None if `abs_path` is outside of it
+pub(super) fn key(user_id: &UserID, abs_path: &Path) -> Option<String> {
+    let rel = abs_path.strip_prefix(to_abs_data_path(user_id, "")).ok()?;
+    Some(NetFilePath::from_path(rel).as_str().trim_matches('/').to_owned())
+}
+
+/// Keys of all folders containing `key`, up to the root
+pub(super) fn ancestors(key: &str) -> Vec<String> {
+    let mut folders = vec![String::new()];
+    let mut end = 0;
+    while let Some(i) = key[end..].find('/') {
+        end += i;
+        folders.push(key[..end].to_owned());
+        end += 1;
+    }
+    folders
+}
+
+/// Recursive size of the folder at `abs_path`, from the cache if possible
+pub fn folder_size(db: &SharedDatabase, user_id: &UserID, abs_path: &Path) -> u64 {
+    let key = match key(user_id, abs_path) {
+        Some(key) => key,
+        None => return node_size(abs_path),
+    };
+    match db.get_folder_size(user_id, &key) {
+        Ok(Some(size)) => return size,
+        Ok(None) => {}
+        Err(e) => error!("Failed to load size of {:?}: {:?}", abs_path, e),
+    }
+    let size = node_size(abs_path);
+    if let Err(e) = db.set_folder_size(user_id, &key, size) {
+        error!("Failed to store size of {:?}: {:?}", abs_path, e);
+    }
+    size
+}
+
+/// Cached sizes of the folders directly inside of `abs_folder` by name, missing ones need `folder_size`
+pub fn cached_child_sizes(db: &SharedDatabase, user_id: &UserID, abs_folder: &Path) -> HashMap<String, u64> {
+    let key = match key(user_id, abs_folder) {
+        Some(key) => key,
+        None => return HashMap::new(),
+    };
+    match db.get_child_folder_sizes(user_id, &key) {
+        Ok(sizes) => sizes.into_iter().collect(),
+        Err(e) => {
+            error!("Failed to load sizes below {:?}: {:?}", abs_folder, e);
+            HashMap::new()
+        }
+    }
+}
+
+/// Size of the file, or the cached size of the folder
+pub fn size_of(db: &SharedDatabase, user_id: &UserID, abs_path: &Path) -> u64 {
+    match std::fs::symlink_metadata(abs_path) {
+        Ok(meta) if meta.is_dir() => folder_size(db, user_id, abs_path),
+        Ok(meta) if meta.is_file() => meta.len(),
+        _ => 0,
+    }
+}
+
+/// The node at `abs_path` grew by `delta` bytes (shrunk if negative), updates the folders containing it
+pub(super) fn changed(db: &SharedDatabase, user_id: &UserID, abs_path: &Path, delta: i64) {
+    if delta == 0 {
+        return;
+    }
+    if let Some(key) = key(user_id, abs_path) {
+        if let Err(e) = db.change_folder_sizes(user_id, &ancestors(&key), delta) {
+            error!("Failed to update folder sizes for {:?}: {:?}", abs_path, e);
+        }
+    }
+}
+
+/// The node at `abs_path` with `size` bytes got moved away or deleted
+pub(super) fn removed(db: &SharedDatabase, user_id: &UserID, abs_path: &Path, size: u64) {
+    if let Some(key) = key(user_id, abs_path) {
+        if let Err(e) = db.delete_folder_sizes_below(user_id, &key) {
+            error!("Failed to forget folder sizes below {:?}: {:?}", abs_path, e);
+        }
+    }
+    changed(db, user_id, abs_path, -(size as i64));
+}
+
+/// Collects the sizes of `path` and all folders below it as (key, size) and the index entries of all nodes below it,
+/// returns the size of `path`
+fn scan(path: &Path, key: String, sizes: &mut Vec<(String, u64)>, entries: &mut Vec<DBIndexEntry>) -> u64 {
+    let mut size = 0;
+    match std::fs::read_dir(path) {
+        Ok(children) => {
+            for child in children.filter_map(Result::ok) {
+                // like symlink_metadata, links are not followed
+                let meta = match child.metadata() {
+                    Ok(meta) => meta,
+                    Err(_) => continue,
+                };
+                let name = child.file_name();
+                let child_key = if key.is_empty() {
+                    name.to_string_lossy().into_owned()
+                } else {
+                    format!("{}/{}", key, name.to_string_lossy())
+                };
+                if meta.is_dir() {
+                    size += scan(&child.path(), child_key.clone(), sizes, entries);
+                } else if meta.is_file() {
+                    size += meta.len();
+                }
+                entries.extend(search::entry(child_key, &meta));
+            }
+        }
+        Err(e) => warn!("Failed to read {:?} while scanning: {:?}", path, e),
+    }
+    sizes.push((key, size));
+    size
+}
+
+/// Recalculates the sizes of all folders of the user and rebuilds the search index from the files,
+/// returns the number of indexed nodes
+pub fn reconcile(db: &SharedDatabase, user_id: &UserID) -> rusqlite::Result<usize> {
+    let root = to_abs_data_path(user_id, "");
+    let (mut sizes, mut entries) = (Vec::new(), Vec::new());
+    if root.is_dir() {
+        scan(&root, String::new(), &mut sizes, &mut entries);
+    }
+    db.replace_folder_sizes(user_id, &sizes)?;
+    db.replace_index(user_id, &entries)?;
+    Ok(entries.len())
+}
+
+/// Starts a thread that reconciles the sizes and the search index of all users now and every `RECONCILE_INTERVAL`
+pub fn start_reconcile_job() {
+    let spawned = std::thread::Builder::new()
+        .name("Rescan".into())
+        .spawn(|| {
+            // own connection, the managed one is owned by rocket
+            let db = SharedDatabase::new(crate::config::db_path());
+            loop {
+                match db.get_all_users() {
+                    Ok(users) => {
+                        let mut indexed = 0;
+                        for user in &users {
+                            match reconcile(&db, &user.id) {
+                                Ok(n) => indexed += n,
+                                Err(e) => error!("Failed to reconcile folder sizes and index of {}: {:?}", user.id, e),
+                            }
+                        }
+                        info!("Reconciled folder sizes and indexed {} nodes of {} users", indexed, users.len());
+                    }
+                    Err(e) => error!("Failed to load users for the rescan: {:?}", e),
+                }
+                std::thread::sleep(RECONCILE_INTERVAL);
+            }
+        });
+    if let Err(e) = spawned {
+        error!("Failed to start rescan thread: {:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_node_size() {
+        let mut dir = std::env::temp_dir();
+        dir.push(format!("what-cloud-size-test-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("a.txt"), b"abc").unwrap();
+        std::fs::write(dir.join("sub").join("b.txt"), b"defgh").unwrap();
+
+        assert_eq!(node_size(&dir), 8);
+        assert_eq!(node_size(&dir.join("a.txt")), 3);
+        assert_eq!(node_size(&dir.join("missing")), 0);
+
+        let (mut sizes, mut entries) = (Vec::new(), Vec::new());
+        assert_eq!(scan(&dir, String::new(), &mut sizes, &mut entries), 8);
+        assert_eq!(sizes, vec![("sub".to_string(), 5), (String::new(), 8)]);
+        let mut indexed: Vec<(String, bool, u64)> = entries.into_iter().map(|e| (e.path, e.is_folder, e.size)).collect();
+        indexed.sort();
+        assert_eq!(
+            indexed,
+            vec![("a.txt".into(), false, 3), ("sub".into(), true, 0), ("sub/b.txt".into(), false, 5)]
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_cached_sizes() {
+        let mut path = std::env::temp_dir();
+        path.push(format!("what-cloud-sizes-test-{}.sqlite", std::process::id()));
+        let db = SharedDatabase::new(&path);
+        let user = UserID("user0001".into());
+        let sizes: Vec<(String, u64)> = ["", "a", "a/b", "ab"].iter().map(|p| (p.to_string(), 10)).collect();
+        db.replace_folder_sizes(&user, &sizes).unwrap();
+
+        db.change_folder_sizes(&user, &ancestors("a/b/c.txt"), 5).unwrap();
+        assert_eq!(db.get_folder_size(&user, "a/b").unwrap(), Some(15));
+        assert_eq!(db.get_folder_size(&user, "ab").unwrap(), Some(10));
+        assert_eq!(db.delete_folder_sizes_below(&user, "a").unwrap(), 2);
+        assert_eq!(db.get_folder_size(&user, "a/b").unwrap(), None);
+        assert_eq!(db.get_folder_size(&user, "ab").unwrap(), Some(10));
+        assert_eq!(db.get_child_folder_sizes(&user, "").unwrap(), vec![("ab".to_string(), 10)]);
+        db.change_folder_sizes(&user, &ancestors("a/b"), -20).unwrap();
+        assert_eq!(db.get_folder_size(&user, "").unwrap(), Some(0));
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_ancestors() {
+        assert_eq!(ancestors(""), vec![""]);
+        assert_eq!(ancestors("a"), vec![""]);
+        assert_eq!(ancestors("a/b/c.txt"), vec!["", "a", "a/b"]);
+    }
+}
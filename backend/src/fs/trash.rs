@@ -0,0 +1,283 @@
+use super::node_ops::ChangedNode;
+use super::upload::{free_file_name, ConflictPolicy};
+use super::{previews, quota, search, sizes, to_abs_data_path, user_shares, NetFilePath};
+use crate::auth::{UserID, WritableUser};
+use crate::database::{DBTrashItem, SharedDatabase};
+use log::{error, info, warn};
+use rocket::response::status;
+use rocket::serde::json::Json;
+use rocket::State;
+use std::borrow::Borrow;
+use std::path::{Path, PathBuf};
+
+/// How often the retention job looks for expired items
+const RETENTION_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+/// Deleted nodes of the user, inside the data path (so deleting is a rename) but outside of the users root
+pub(super) fn trash_dir(user_id: &UserID) -> PathBuf {
+    let mut dir = PathBuf::from(crate::config::data_path());
+    dir.push(".trash");
+    dir.push(&user_id.0);
+    dir
+}
+
+fn trash_item_path(user_id: &UserID, id: &str) -> PathBuf {
+    let mut path = trash_dir(user_id);
+    path.push(id);
+    path
+}
+
+/// Moves the node to the trash of the user, returns the id of the trash item.
+/// `path` is relative to the users root
+pub fn move_to_trash(db: &SharedDatabase, user_id: &UserID, path: &Path) -> std::io::Result<String> {
+    let abs_path = to_abs_data_path(user_id, path);
+    let id: String = crate::utils::get_rand_token::<16>()
+        .iter()
+        .map(|e| *e as char)
+        .collect();
+
+    std::fs::create_dir_all(trash_dir(user_id))?;
+    previews::invalidate_cache(&abs_path);
+    let is_folder = abs_path.is_dir();
+    let size = sizes::size_of(db, user_id, &abs_path);
+    std::fs::rename(&abs_path, trash_item_path(user_id, &id))?;
+
+    if let Err(e) = db.add_trash_item(&id, user_id, path, is_folder) {
+        error!("Failed to store trash item, restoring {:?}: {:?}", abs_path, e);
+        std::fs::rename(trash_item_path(user_id, &id), &abs_path)?;
+        return Err(std::io::Error::new(std::io::ErrorKind::Other, "failed to store trash item"));
+    }
+    sizes::removed(db, user_id, &abs_path, size);
+    search::removed(db, user_id, &abs_path);
+
+    // a share would point to nothing (or to a new node with the same name), so it is disabled until restored
+    match db.trash_shares_below(user_id, path, &id) {
+        Ok(0) => {}
+        Ok(n) => info!("Disabled {} shares of deleted {:?}", n, path),
+        Err(e) => error!("Failed to disable shares of deleted {:?}: {:?}", path, e),
+    }
+    Ok(id)
+}
+
+fn purge(item: &DBTrashItem, db: &SharedDatabase) -> std::io::Result<()> {
+    let path = trash_item_path(&item.user, &item.id);
+    let size = sizes::node_size(&path);
+    let res = if item.is_folder {
+        std::fs::remove_dir_all(&path)
+    } else {
+        std::fs::remove_file(&path)
+    };
+    match res {
+        Ok(()) => {}
+        // nothing left to delete, so just forget the item
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => warn!("Trash item {:?} is missing", path),
+        Err(e) => return Err(e),
+    }
+    quota::change(db, &item.user, -(size as i64));
+    // the versions are only needed if the node gets restored
+    if !to_abs_data_path(&item.user, &item.original_path).exists() {
+        super::versions::delete_versions_below(db, &item.user, &item.original_path)?;
+    }
+    if let Err(e) = db.delete_trashed_shares(&item.id) {
+        error!("Failed to remove shares of purged {}: {:?}", item.id, e);
+    }
+    db.delete_trash_item(&item.id)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("{:?}", e)))
+}
+
+#[derive(Serialize, Debug)]
+pub struct TrashItem {
+    id: String,
+    #[serde(rename = "originalPath")]
+    original_path: String,
+    #[serde(rename = "type")]
+    node_type: &'static str,
+    #[serde(rename = "deletedAt")]
+    deleted_at: String,
+}
+
+#[get("/trash")]
+pub fn get_trash(user_id: UserID, db: &State<SharedDatabase>) -> Result<Json<Vec<TrashItem>>, status::Custom<()>> {
+    let items = db.get_trash_items(&user_id).map_err(|e| {
+        error!("Failed to load trash of {}: {:?}", user_id, e);
+        status::Custom(rocket::http::Status::InternalServerError, ())
+    })?;
+
+    Ok(Json(
+        items
+            .into_iter()
+            .map(|item| TrashItem {
+                original_path: NetFilePath::from_path(&item.original_path).into(),
+                node_type: if item.is_folder { "folder" } else { "file" },
+                deleted_at: item.deleted_at,
+                id: item.id,
+            })
+            .collect(),
+    ))
+}
+
+#[derive(Responder, Debug)]
+pub enum RestoreResponse {
+    #[response(status = 202)]
+    Accepted(Json<ChangedNode>),
+    #[response(status = 404)]
+    NotFound(()),
+    #[response(status = 409)]
+    Conflict(&'static str),
+    #[response(status = 500)]
+    ServerError(()),
+}
+
+/// Moves the item back to its original path (missing parent folders get created).
+/// `conflict` defaults to fail, returns the path the node was restored to
+#[post("/trash/restore?<id>&<conflict>")]
+pub fn restore_trash_item(
+    id: &str,
+    conflict: Option<ConflictPolicy>,
+    writable: WritableUser,
+    db: &State<SharedDatabase>,
+) -> RestoreResponse {
+    let user_id = writable.0;
+    let item = match db.get_trash_item(id, &user_id) {
+        Some(item) => item,
+        None => return RestoreResponse::NotFound(()),
+    };
+
+    let mut target = to_abs_data_path(&user_id, &item.original_path);
+    if user_shares::is_reserved(&user_id, &target) {
+        // a real folder with that name would be hidden by the virtual one
+        match free_file_name(&target) {
+            Some(free) => target = free,
+            None => return RestoreResponse::Conflict("No free name found"),
+        }
+    }
+    if target.exists() {
+        match conflict.unwrap_or(ConflictPolicy::Fail) {
+            ConflictPolicy::Rename => match free_file_name(&target) {
+                Some(free) => target = free,
+                None => return RestoreResponse::Conflict("No free name found"),
+            },
+            _ => return RestoreResponse::Conflict("Original path exists"),
+        }
+    }
+
+    if let Some(parent) = target.parent() {
+        if let Err(e) = std::fs::create_dir_all(parent) {
+            error!("Failed to create parent of restored {:?}: {:?}", target, e);
+            return RestoreResponse::ServerError(());
+        }
+    }
+    if let Err(e) = std::fs::rename(trash_item_path(&user_id, &item.id), &target) {
+        error!("Failed to restore trash item {}: {:?}", item.id, e);
+        return RestoreResponse::ServerError(());
+    }
+    if let Err(e) = db.delete_trash_item(&item.id) {
+        error!("Failed to delete restored trash item {}: {:?}", item.id, e);
+    }
+    sizes::changed(db, &user_id, &target, sizes::node_size(&target) as i64);
+    search::added(db, &user_id, &target);
+
+    let mut user_root = PathBuf::from(crate::config::data_path());
+    user_root.push(&user_id.0);
+    let restored = NetFilePath::from_path(target.strip_prefix(&user_root).unwrap_or(&item.original_path));
+    match db.restore_trashed_shares(&item.id, &item.original_path, Borrow::<Path>::borrow(&restored)) {
+        Ok(0) => {}
+        Ok(n) => info!("Enabled {} shares of restored {:?}", n, restored),
+        Err(e) => error!("Failed to enable shares of restored {:?}: {:?}", restored, e),
+    }
+    info!("{} restored {:?}", user_id, restored);
+    RestoreResponse::Accepted(Json(ChangedNode { path: restored.into() }))
+}
+
+/// Deletes the item with `id` permanently, or the whole trash if no id is set
+#[delete("/trash?<id>")]
+pub async fn purge_trash(
+    id: Option<&str>,
+    writable: WritableUser,
+    db: &State<SharedDatabase>,
+) -> Result<status::Accepted<()>, status::NotFound<()>> {
+    let user_id = writable.0;
+    let items = match id {
+        Some(id) => db.get_trash_item(id, &user_id).into_iter().collect(),
+        None => db.get_trash_items(&user_id).unwrap_or_default(),
+    };
+    if id.is_some() && items.is_empty() {
+        return Err(status::NotFound(()));
+    }
+
+    for item in items {
+        if let Err(e) = purge(&item, db) {
+            error!("Failed to purge trash item {}: {:?}", item.id, e);
+        }
+    }
+    info!("{} purged trash", user_id);
+    Ok(status::Accepted(None))
+}
+
+/// Starts a thread that permanently deletes trash items older than `config::trash_retention_days()`
+pub fn start_retention_job() {
+    let spawned = std::thread::Builder::new()
+        .name("Trash retention".into())
+        .spawn(|| {
+            // own connection, the managed one is owned by rocket
+            let db = SharedDatabase::new(crate::config::db_path());
+            loop {
+                let retention_days = crate::config::trash_retention_days();
+                match db.get_expired_trash_items(retention_days) {
+                    Ok(items) => {
+                        if !items.is_empty() {
+                            info!("Purging {} trash items older than {} days", items.len(), retention_days);
+                        }
+                        for item in items {
+                            if let Err(e) = purge(&item, &db) {
+                                error!("Failed to purge trash item {}: {:?}", item.id, e);
+                            }
+                        }
+                    }
+                    Err(e) => error!("Failed to load expired trash items: {:?}", e),
+                }
+                std::thread::sleep(RETENTION_INTERVAL);
+            }
+        });
+    if let Err(e) = spawned {
+        error!("Failed to start trash retention thread: {:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::UserRoll;
+    use crate::fs::shared::SharePermissions;
+
+    #[test]
+    fn test_trashed_shares() {
+        let mut path = std::env::temp_dir();
+        path.push(format!("what-cloud-trash-test-{}.sqlite", std::process::id()));
+        let db = SharedDatabase::new(&path);
+        let (owner, other) = (UserID("owner001".into()), UserID("other001".into()));
+        db.create_user(&owner, "owner", "hash", UserRoll::User).unwrap();
+        db.create_user(&other, "other", "hash", UserRoll::User).unwrap();
+        let link = db
+            .update_share(&owner, Path::new("docs/a"), true, None, None, None, SharePermissions::default())
+            .unwrap();
+        db.upsert_user_share(&owner, Path::new("docs"), &other, false).unwrap();
+
+        assert_eq!(db.trash_shares_below(&owner, Path::new("docs"), "trash1").unwrap(), 2);
+        assert!(db.get_shared_entry(link.as_ref()).is_none());
+        assert!(db.get_incoming_user_shares(&other).unwrap().is_empty());
+        // a new node with the same path can be shared
+        db.upsert_user_share(&owner, Path::new("docs"), &other, true).unwrap();
+
+        assert_eq!(db.restore_trashed_shares("trash1", Path::new("docs"), Path::new("docs (1)")).unwrap(), 2);
+        assert_eq!(db.get_shared_entry(link.as_ref()).unwrap().path, PathBuf::from("docs (1)/a"));
+        assert_eq!(db.get_incoming_user_shares(&other).unwrap().len(), 2);
+
+        db.trash_shares_below(&owner, Path::new("docs (1)"), "trash2").unwrap();
+        assert_eq!(db.delete_trashed_shares("trash2").unwrap(), 2);
+        assert_eq!(db.restore_trashed_shares("trash2", Path::new("docs (1)"), Path::new("docs (1)")).unwrap(), 0);
+        assert_eq!(db.get_incoming_user_shares(&other).unwrap().len(), 1);
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
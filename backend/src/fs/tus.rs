@@ -0,0 +1,473 @@
+//! Resumable uploads with the tus protocol (https://tus.io/protocols/resumable-upload.html)
+//! Supports core 1.0.0 with the creation and termination extensions.
+//! The target path is set via the `path` key of Upload-Metadata, the data gets written to a
+//! staging file and moved to the target when all bytes arrived.
+//! The optional `conflict` key (overwrite / rename / fail, default overwrite) decides what happens
+//! if the target exists at that point.
+//! Uploads larger than the space left in the users quota get rejected on creation and when finishing.
+
+use super::upload::{place_upload, staging_dir, ConflictPolicy, UploadError, MAX_UPLOAD_SIZE};
+use super::NetFilePath;
+use crate::auth::{UserID, WritableUser};
+use crate::database::SharedDatabase;
+use lazy_static::lazy_static;
+use log::{error, info, warn};
+use rocket::data::ToByteUnit;
+use rocket::form::{FromFormField, ValueField};
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::response::{self, Responder};
+use rocket::{Data, Request, Response, State};
+use std::borrow::Borrow;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tokio::io::AsyncWriteExt;
+
+const TUS_VERSION: &str = "1.0.0";
+/// uploads not continued for this long get removed
+const STALE_UPLOAD_DAYS: u32 = 7;
+
+lazy_static! {
+    /// Ids of uploads currently receiving a PATCH, to reject parallel writes to the same file
+    static ref ACTIVE_PATCHES: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
+
+/// Entry in `ACTIVE_PATCHES`, removed on drop, so a failed or cancelled PATCH doesn't lock the upload
+struct ActivePatch(String);
+
+impl ActivePatch {
+    /// None if the upload is already receiving a PATCH
+    fn start(upload_id: &str) -> Option<Self> {
+        if ACTIVE_PATCHES.lock().unwrap().insert(upload_id.to_string()) {
+            Some(ActivePatch(upload_id.to_string()))
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for ActivePatch {
+    fn drop(&mut self) {
+        if let Ok(mut active) = ACTIVE_PATCHES.lock() {
+            active.remove(&self.0);
+        }
+    }
+}
+
+/// Tus specific request headers
+pub struct TusHeaders {
+    resumable: Option<String>,
+    upload_length: Option<u64>,
+    upload_offset: Option<u64>,
+    upload_metadata: Option<String>,
+    content_type: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for TusHeaders {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let headers = req.headers();
+        Outcome::Success(TusHeaders {
+            resumable: headers.get_one("Tus-Resumable").map(String::from),
+            upload_length: headers.get_one("Upload-Length").and_then(|l| l.parse().ok()),
+            upload_offset: headers.get_one("Upload-Offset").and_then(|o| o.parse().ok()),
+            upload_metadata: headers.get_one("Upload-Metadata").map(String::from),
+            content_type: headers.get_one("Content-Type").map(String::from),
+        })
+    }
+}
+
+impl TusHeaders {
+    fn supported_version(&self) -> bool {
+        self.resumable.as_deref() == Some(TUS_VERSION)
+    }
+}
+
+/// Every tus response has the Tus-Resumable header, the other headers depend on the request
+pub struct TusResponse {
+    status: Status,
+    headers: Vec<(&'static str, String)>,
+}
+
+impl TusResponse {
+    fn new(status: Status) -> Self {
+        TusResponse {
+            status,
+            headers: Vec::new(),
+        }
+    }
+
+    fn header(mut self, name: &'static str, value: impl ToString) -> Self {
+        self.headers.push((name, value.to_string()));
+        self
+    }
+}
+
+impl<'r> Responder<'r, 'static> for TusResponse {
+    fn respond_to(self, _request: &'r Request<'_>) -> response::Result<'static> {
+        let mut res = Response::build();
+        res.status(self.status)
+            .raw_header("Tus-Resumable", TUS_VERSION)
+            .raw_header("Cache-Control", "no-store");
+        for (name, value) in self.headers {
+            res.raw_header(name, value);
+        }
+        res.ok()
+    }
+}
+
+/// Parses `key base64value,key2 base64value2`, keys without value map to an empty string
+fn parse_metadata(raw: &str) -> Option<Vec<(String, String)>> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let mut split = pair.splitn(2, ' ');
+            let key = split.next()?.to_string();
+            let value = match split.next() {
+                Some(v) => String::from_utf8(base64::decode(v).ok()?).ok()?,
+                None => String::new(),
+            };
+            Some((key, value))
+        })
+        .collect()
+}
+
+fn conflict_policy(metadata: &[(String, String)]) -> Option<ConflictPolicy> {
+    match metadata.iter().find(|(k, _)| k == "conflict") {
+        Some((_, policy)) => ConflictPolicy::from_name(policy),
+        None => Some(ConflictPolicy::Overwrite),
+    }
+}
+
+pub(super) fn staging_file(upload_id: &str) -> PathBuf {
+    let mut path = staging_dir();
+    path.push(format!("tus_{}", upload_id));
+    path
+}
+
+fn remove_stale_uploads(db: &SharedDatabase) {
+    match db.delete_stale_uploads(STALE_UPLOAD_DAYS) {
+        Ok(ids) => {
+            for id in ids {
+                info!("Removing stale upload {}", id);
+                if let Err(e) = std::fs::remove_file(staging_file(&id)) {
+                    warn!("Failed to remove staging file of upload {}: {:?}", id, e);
+                }
+            }
+        }
+        Err(e) => error!("Failed to remove stale uploads: {:?}", e),
+    }
+}
+
+#[options("/tus")]
+pub fn tus_options() -> TusResponse {
+    TusResponse::new(Status::NoContent)
+        .header("Tus-Version", TUS_VERSION)
+        .header("Tus-Extension", "creation,termination")
+        .header("Tus-Max-Size", MAX_UPLOAD_SIZE)
+}
+
+/// creation extension, needs Upload-Length and the target `path` in Upload-Metadata
+#[post("/tus")]
+pub fn tus_create(headers: TusHeaders, writable: WritableUser, db: &State<SharedDatabase>) -> TusResponse {
+    let user_id = writable.0;
+    if !headers.supported_version() {
+        return TusResponse::new(Status::PreconditionFailed).header("Tus-Version", TUS_VERSION);
+    }
+    let length = match headers.upload_length {
+        Some(l) => l,
+        None => return TusResponse::new(Status::BadRequest),
+    };
+    if length > MAX_UPLOAD_SIZE {
+        return TusResponse::new(Status::PayloadTooLarge);
+    }
+    // checked again when the upload is finished, other uploads may have used the space in between
+    match super::quota::available_bytes(db, &user_id) {
+        Ok(Some(available)) if length > available => return TusResponse::new(Status::PayloadTooLarge),
+        Ok(_) => {}
+        Err(e) => {
+            error!("Failed to load quota of {}: {:?}", user_id, e);
+            return TusResponse::new(Status::InternalServerError);
+        }
+    }
+
+    let metadata = match headers.upload_metadata.as_deref().map(parse_metadata) {
+        Some(Some(md)) => md,
+        _ => return TusResponse::new(Status::BadRequest),
+    };
+    // same validation as paths from query parameters
+    let target = match metadata
+        .iter()
+        .find(|(k, _)| k == "path")
+        .and_then(|(_, path)| NetFilePath::from_value(ValueField::from_value(path)).ok())
+    {
+        Some(t) => t,
+        None => return TusResponse::new(Status::BadRequest),
+    };
+    if super::user_shares::is_virtual(&target) {
+        // the upload would belong to the owner of the folder, use POST /upload instead
+        return TusResponse::new(Status::Forbidden);
+    }
+    let conflict = match conflict_policy(&metadata) {
+        Some(c) => c,
+        None => return TusResponse::new(Status::BadRequest),
+    };
+    let abs_target = match super::contained_data_path(&user_id, Borrow::<Path>::borrow(&target)) {
+        Some(p) => p,
+        None => return TusResponse::new(Status::Forbidden),
+    };
+    if abs_target.is_dir() || !abs_target.parent().map(Path::is_dir).unwrap_or(false) {
+        warn!("Tus upload target {:?} is a folder or has no parent folder", abs_target);
+        return TusResponse::new(Status::Conflict);
+    }
+    if conflict == ConflictPolicy::Fail && abs_target.exists() {
+        return TusResponse::new(Status::Conflict);
+    }
+
+    remove_stale_uploads(db);
+
+    let upload_id = match db.create_upload(
+        &user_id,
+        Borrow::<Path>::borrow(&target),
+        length,
+        headers.upload_metadata.as_deref(),
+    ) {
+        Ok(id) => id,
+        Err(e) => {
+            error!("Failed to create upload: {:?}", e);
+            return TusResponse::new(Status::InternalServerError);
+        }
+    };
+
+    if let Err(e) = std::fs::create_dir_all(staging_dir())
+        .and_then(|_| std::fs::File::create(staging_file(&upload_id)))
+    {
+        error!("Failed to create staging file: {:?}", e);
+        db.delete_upload(&upload_id).ok();
+        return TusResponse::new(Status::InternalServerError);
+    }
+
+    info!("{} created upload {} to {:?} ({} bytes)", user_id, upload_id, target, length);
+    TusResponse::new(Status::Created).header("Location", format!("/api/tus/{}", upload_id))
+}
+
+#[head("/tus/<upload_id>")]
+pub fn tus_head(upload_id: &str, user_id: UserID, db: &State<SharedDatabase>) -> TusResponse {
+    match db.get_upload(upload_id, &user_id) {
+        Some(upload) => {
+            let res = TusResponse::new(Status::Ok)
+                .header("Upload-Offset", upload.offset)
+                .header("Upload-Length", upload.length);
+            match upload.metadata {
+                Some(md) => res.header("Upload-Metadata", md),
+                None => res,
+            }
+        }
+        None => TusResponse::new(Status::NotFound),
+    }
+}
+
+/// Appends the body at Upload-Offset. If the connection drops, all bytes that reached the
+/// staging file are kept and the client continues from the offset returned by HEAD
+#[patch("/tus/<upload_id>", data = "<data>")]
+pub async fn tus_patch(
+    upload_id: &str,
+    headers: TusHeaders,
+    writable: WritableUser,
+    db: &State<SharedDatabase>,
+    data: Data<'_>,
+) -> TusResponse {
+    let user_id = writable.0;
+    if !headers.supported_version() {
+        return TusResponse::new(Status::PreconditionFailed).header("Tus-Version", TUS_VERSION);
+    }
+    if headers.content_type.as_deref() != Some("application/offset+octet-stream") {
+        return TusResponse::new(Status::UnsupportedMediaType);
+    }
+    let (_active, upload) = match start_patch(db, upload_id, &user_id, headers.upload_offset) {
+        Ok(started) => started,
+        Err(res) => return res,
+    };
+    append_chunk(&upload, db, data).await
+}
+
+/// Locks the upload for a PATCH at `offset`. The upload is read while holding the lock,
+/// so a PATCH that waited for another one can't write at the offset from before it
+fn start_patch(
+    db: &SharedDatabase,
+    upload_id: &str,
+    user_id: &UserID,
+    offset: Option<u64>,
+) -> Result<(ActivePatch, crate::database::DBUpload), TusResponse> {
+    let active = match ActivePatch::start(upload_id) {
+        Some(active) => active,
+        None => {
+            warn!("Parallel PATCH to upload {}", upload_id);
+            return Err(TusResponse::new(Status::Conflict));
+        }
+    };
+    let upload = match db.get_upload(upload_id, user_id) {
+        Some(u) => u,
+        None => return Err(TusResponse::new(Status::NotFound)),
+    };
+    if offset != Some(upload.offset) {
+        return Err(TusResponse::new(Status::Conflict).header("Upload-Offset", upload.offset));
+    }
+    Ok((active, upload))
+}
+
+async fn append_chunk(
+    upload: &crate::database::DBUpload,
+    db: &SharedDatabase,
+    data: Data<'_>,
+) -> TusResponse {
+    let staging = staging_file(&upload.id);
+    let mut file = match tokio::fs::OpenOptions::new().write(true).open(&staging).await {
+        Ok(f) => f,
+        Err(e) => {
+            error!("Failed to open staging file {:?}: {:?}", staging, e);
+            return TusResponse::new(Status::InternalServerError);
+        }
+    };
+    // bytes behind the stored offset are from an interrupted write that wasn't recorded
+    if let Err(e) = file.set_len(upload.offset).await {
+        error!("Failed to truncate staging file {:?}: {:?}", staging, e);
+        return TusResponse::new(Status::InternalServerError);
+    }
+    if let Err(e) = tokio::io::AsyncSeekExt::seek(&mut file, std::io::SeekFrom::Start(upload.offset)).await {
+        error!("Failed to seek staging file {:?}: {:?}", staging, e);
+        return TusResponse::new(Status::InternalServerError);
+    }
+
+    let remaining = upload.length - upload.offset;
+    let streamed = data.open(remaining.bytes()).stream_to(&mut file).await;
+    if let Err(e) = &streamed {
+        // keep what we got, the client resumes from the new offset
+        warn!("Upload {} interrupted: {}", upload.id, e);
+    }
+    let new_offset = match file.flush().await.and(file.sync_data().await) {
+        Ok(()) => file.metadata().await.map(|md| md.len()).unwrap_or(upload.offset),
+        Err(e) => {
+            error!("Failed to flush staging file {:?}: {:?}", staging, e);
+            upload.offset
+        }
+    };
+    drop(file);
+
+    if let Err(e) = db.set_upload_offset(&upload.id, new_offset) {
+        error!("Failed to store offset of upload {}: {:?}", upload.id, e);
+        return TusResponse::new(Status::InternalServerError);
+    }
+    if let Ok(n) = streamed {
+        if !n.complete {
+            // client sent more than Upload-Length
+            return TusResponse::new(Status::PayloadTooLarge).header("Upload-Offset", new_offset);
+        }
+    }
+
+    if new_offset == upload.length {
+        let target = super::to_abs_data_path(&upload.user, &upload.target_path);
+        let conflict = upload
+            .metadata
+            .as_deref()
+            .and_then(parse_metadata)
+            .and_then(|md| conflict_policy(&md))
+            .unwrap_or(ConflictPolicy::Fail);
+        let stored_at = match place_upload(db, &upload.user, &staging, &target, conflict) {
+            Ok(path) => path,
+            // keep the upload, so the client can delete it
+            Err(UploadError::Conflict) => return TusResponse::new(Status::Conflict).header("Upload-Offset", new_offset),
+            Err(UploadError::QuotaExceeded) => {
+                return TusResponse::new(Status::PayloadTooLarge).header("Upload-Offset", new_offset)
+            }
+            Err(UploadError::Failed) => return TusResponse::new(Status::InternalServerError),
+        };
+        if let Err(e) = db.delete_upload(&upload.id) {
+            error!("Failed to delete finished upload {}: {:?}", upload.id, e);
+        }
+        info!("Finished upload {} to {:?}", upload.id, stored_at);
+    }
+
+    TusResponse::new(Status::NoContent).header("Upload-Offset", new_offset)
+}
+
+/// termination extension
+#[delete("/tus/<upload_id>")]
+pub fn tus_delete(upload_id: &str, headers: TusHeaders, writable: WritableUser, db: &State<SharedDatabase>) -> TusResponse {
+    let user_id = writable.0;
+    if !headers.supported_version() {
+        return TusResponse::new(Status::PreconditionFailed).header("Tus-Version", TUS_VERSION);
+    }
+    let upload = match db.get_upload(upload_id, &user_id) {
+        Some(u) => u,
+        None => return TusResponse::new(Status::NotFound),
+    };
+    if ACTIVE_PATCHES.lock().unwrap().contains(&upload.id) {
+        return TusResponse::new(Status::Conflict);
+    }
+
+    if let Err(e) = db.delete_upload(&upload.id) {
+        error!("Failed to delete upload {}: {:?}", upload.id, e);
+        return TusResponse::new(Status::InternalServerError);
+    }
+    if let Err(e) = std::fs::remove_file(staging_file(&upload.id)) {
+        warn!("Failed to remove staging file of upload {}: {:?}", upload.id, e);
+    }
+    info!("{} terminated upload {}", user_id, upload.id);
+    TusResponse::new(Status::NoContent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_metadata() {
+        let md = parse_metadata("path Zm9sZGVyL2ZpbGUudHh0,is_confidential").unwrap();
+        assert_eq!(
+            md,
+            vec![
+                ("path".to_string(), "folder/file.txt".to_string()),
+                ("is_confidential".to_string(), String::new())
+            ]
+        );
+        assert_eq!(parse_metadata("").unwrap(), vec![]);
+        // invalid base64
+        assert!(parse_metadata("path ###").is_none());
+    }
+
+    #[test]
+    fn test_patch_same_offset() {
+        let mut path = std::env::temp_dir();
+        path.push(format!("what-cloud-tus-test-{}.sqlite", std::process::id()));
+        let db = SharedDatabase::new(&path);
+        let user = UserID("user0001".into());
+        let id = db.create_upload(&user, Path::new("a.txt"), 10, None).unwrap();
+
+        let (first, upload) = start_patch(&db, &id, &user, Some(0)).ok().unwrap();
+        assert_eq!(upload.offset, 0);
+        // the second PATCH with the same offset is rejected while the first one runs
+        assert_eq!(start_patch(&db, &id, &user, Some(0)).err().unwrap().status, Status::Conflict);
+        db.set_upload_offset(&id, 4).unwrap();
+        drop(first);
+        // and after it, because the offset moved on
+        let res = start_patch(&db, &id, &user, Some(0)).err().unwrap();
+        assert_eq!(res.status, Status::Conflict);
+        assert_eq!(res.headers, vec![("Upload-Offset", "4".to_string())]);
+        assert!(start_patch(&db, &id, &user, Some(4)).is_ok());
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_active_patch() {
+        let active = ActivePatch::start("test-upload").unwrap();
+        assert!(ActivePatch::start("test-upload").is_none());
+        drop(active);
+        assert!(ActivePatch::start("test-upload").is_some());
+    }
+}
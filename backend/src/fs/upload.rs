@@ -1,44 +1,262 @@
-use super::NetFilePath;
-use crate::auth::UserID;
+use super::shared::{self, ShareAccessKind, ShareClient, ShareDenied, SharePermissions, ShareUnlockToken};
+use super::user_shares::{self, Access, ResolvedPath};
+use super::{quota, search, sizes, NetFilePath};
+use crate::auth::{UserID, WritableUser};
 use crate::database::SharedDatabase;
-use log::{info, warn};
+use log::{error, info, warn};
 use rocket::Data;
+use rocket::Request;
+use rocket::request::{FromRequest, Outcome};
+use rocket::serde::json::Json;
 use std::borrow::Borrow;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use rocket::response::status;
 use rocket::State;
 
-type UploadResponse = Result<status::Accepted<()>, status::Forbidden<()>>;
+type CreateFolderResponse = Result<status::Accepted<()>, status::Forbidden<()>>;
 
-#[allow(unreachable_code, unused_variables)]
-#[post("/upload?<file_path>&<shared_id>", data = "<data>", rank = 1)]
+/// 10 GiB
+pub const MAX_UPLOAD_SIZE: u64 = 10 * 1024 * 1024 * 1024;
+
+/// Unfinished uploads are stored here, inside the data path so they can be renamed into place
+pub fn staging_dir() -> PathBuf {
+    let mut dir = PathBuf::from(crate::config::data_path());
+    dir.push(".uploads");
+    dir
+}
+
+/// Value of the Content-Length header, forwards if not set
+pub struct ContentLength(pub u64);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ContentLength {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match req.headers().get_one("Content-Length").and_then(|l| l.parse().ok()) {
+            Some(len) => Outcome::Success(ContentLength(len)),
+            None => Outcome::Forward(()),
+        }
+    }
+}
+
+/// What to do if the uploaded file exists already
+#[derive(FromFormField, Debug, Clone, Copy, PartialEq)]
+pub enum ConflictPolicy {
+    #[field(value = "overwrite")]
+    Overwrite,
+    /// store as `name (1).ext`, `name (2).ext`, ...
+    #[field(value = "rename")]
+    Rename,
+    #[field(value = "fail")]
+    Fail,
+}
+
+impl ConflictPolicy {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "overwrite" => Some(ConflictPolicy::Overwrite),
+            "rename" => Some(ConflictPolicy::Rename),
+            "fail" => Some(ConflictPolicy::Fail),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum UploadError {
+    /// target exists and ConflictPolicy::Fail
+    Conflict,
+    /// the owner of the target has not enough space left
+    QuotaExceeded,
+    Failed,
+}
+
+/// Where the upload got stored, differs from the requested path with ConflictPolicy::Rename
+#[derive(Serialize, Debug)]
+pub struct UploadedFile {
+    path: String,
+    size: u64,
+}
+
+#[derive(Responder, Debug)]
+pub enum FileUploadResponse {
+    #[response(status = 202)]
+    Accepted(Json<UploadedFile>),
+    #[response(status = 403)]
+    Forbidden(()),
+    #[response(status = 409)]
+    Conflict(&'static str),
+    #[response(status = 413)]
+    QuotaExceeded(&'static str),
+}
+
+/// Upload quota of a share after the upload
+#[derive(Serialize, Debug)]
+pub struct SharedUploadQuota {
+    #[serde(rename = "uploadedBytes")]
+    uploaded_bytes: u64,
+    #[serde(rename = "remainingBytes")]
+    remaining_bytes: u64,
+    /// path inside the share the file got stored at
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+}
+
+#[derive(Responder, Debug)]
+pub enum SharedUploadResponse {
+    #[response(status = 202)]
+    Accepted(Json<SharedUploadQuota>),
+    #[response(status = 401)]
+    Locked(&'static str),
+    #[response(status = 403)]
+    Forbidden(&'static str),
+    #[response(status = 409)]
+    Conflict(&'static str),
+    #[response(status = 410)]
+    Gone(&'static str),
+    #[response(status = 411)]
+    LengthRequired(&'static str),
+    #[response(status = 413)]
+    LimitExceeded(Json<SharedUploadQuota>),
+    /// the owner of the share has no space left
+    #[response(status = 507)]
+    InsufficientStorage(&'static str),
+    #[response(status = 500)]
+    ServerError(()),
+}
+
+/// Uploads into shared folders need the upload permission and an upload_limit.
+/// All uploads to a share count against the limit, so the size must be known before streaming.
+/// Existing files can only be overwritten with the overwrite permission, so `conflict` defaults to fail
+#[post("/upload?<file_path>&<shared_id>&<conflict>", data = "<data>", rank = 1)]
 pub async fn post_upload_shared(
     file_path: NetFilePath,
     db: &State<SharedDatabase>,
     shared_id: String,
+    share_token: ShareUnlockToken,
+    client: ShareClient,
+    conflict: Option<ConflictPolicy>,
+    content_length: Option<ContentLength>,
     data: Data<'_>,
-) -> UploadResponse {
-    warn!("Upload for shared not implemented");
-    return Err(status::Forbidden(None));
+) -> SharedUploadResponse {
     // check if shared id is allowed
-    if let Some(se) = db.get_shared_entry(&shared_id) {
-        file_path.add_prefix(&se.path);
+    let se = match db.get_shared_entry(&shared_id) {
+        Some(se) => se,
+        None => return SharedUploadResponse::Forbidden("Shared ID doesn't exist"),
+    };
+    match se.check_access(&share_token, SharePermissions::UPLOAD) {
+        Ok(()) => {}
+        Err(ShareDenied::Expired) => return SharedUploadResponse::Gone("Share expired"),
+        Err(ShareDenied::Locked) => return SharedUploadResponse::Locked("Share is password protected"),
+        Err(ShareDenied::NotPermitted) => return SharedUploadResponse::Forbidden("Upload not permitted"),
+    }
+    let remaining = match se.remaining_upload_bytes() {
+        Some(r) => r,
+        None => return SharedUploadResponse::Forbidden("Upload not enabled for this share"),
+    };
+    let size = match content_length {
+        Some(ContentLength(size)) => size,
+        None => return SharedUploadResponse::LengthRequired("Content-Length required"),
+    };
+    let conflict = conflict.unwrap_or(ConflictPolicy::Fail);
+    if conflict == ConflictPolicy::Overwrite && !se.permissions.contains(SharePermissions::OVERWRITE) {
+        return SharedUploadResponse::Forbidden("Overwrite not permitted");
+    }
 
-        return handle_upload(file_path, se.user, data).await;
+    let requested = file_path.as_str().to_owned();
+    let file_path = match se.node_path(file_path) {
+        Some(p) => p,
+        None => return SharedUploadResponse::Forbidden("Can't upload into a shared file"),
+    };
+
+    match db.reserve_share_upload(&se.share_id, size) {
+        Ok(true) => {}
+        Ok(false) => {
+            return SharedUploadResponse::LimitExceeded(Json(SharedUploadQuota {
+                uploaded_bytes: 0,
+                remaining_bytes: remaining,
+                path: None,
+            }))
+        }
+        Err(e) => {
+            error!("Failed to reserve upload quota: {:?}", e);
+            return SharedUploadResponse::ServerError(());
+        }
+    }
+
+    let stored = store_upload(db, &file_path, &se.user, data, size, Some(size), conflict).await;
+    let written = stored.as_ref().map(|(written, _)| *written).unwrap_or(0);
+    if written < size {
+        if let Err(e) = db.release_share_upload(&se.share_id, size - written) {
+            error!("Failed to release upload quota: {:?}", e);
+        }
     }
 
-    // TODO add error details
-    Err(status::Forbidden(None))
+    match stored {
+        Ok((written, path)) => {
+            shared::log_access(db, &se, &client, ShareAccessKind::Upload, &requested, Some(written));
+            SharedUploadResponse::Accepted(Json(SharedUploadQuota {
+                uploaded_bytes: written,
+                remaining_bytes: remaining.saturating_sub(written),
+                path: Path::new(&path)
+                    .strip_prefix(&se.path)
+                    .ok()
+                    .map(|p| NetFilePath::from_path(p).into()),
+            }))
+        }
+        Err(UploadError::Conflict) => SharedUploadResponse::Conflict("File exists already"),
+        Err(UploadError::QuotaExceeded) => SharedUploadResponse::InsufficientStorage("Owner of the share has no space left"),
+        Err(UploadError::Failed) => SharedUploadResponse::Forbidden("Upload failed"),
+    }
 }
 
-#[post("/upload?<file_path>", data = "<data>", rank = 2)]
-pub async fn post_upload(file_path: NetFilePath, user_id: UserID, data: Data<'_>) -> UploadResponse {
-    handle_upload(file_path, user_id, data).await
+/// `conflict` defaults to overwrite, folders shared with the user need write access.
+/// Uploads count against the quota of the owner, with Content-Length they get rejected before streaming
+#[post("/upload?<file_path>&<conflict>", data = "<data>", rank = 2)]
+pub async fn post_upload(
+    file_path: NetFilePath,
+    conflict: Option<ConflictPolicy>,
+    writable: WritableUser,
+    db: &State<SharedDatabase>,
+    content_length: Option<ContentLength>,
+    data: Data<'_>,
+) -> FileUploadResponse {
+    let user_id = writable.0;
+    let (owner, file_path, share) = match user_shares::resolve(db, &user_id, file_path, Access::Write) {
+        Ok(ResolvedPath::Node { owner, path, share }) => (owner, path, share),
+        Ok(ResolvedPath::SharedRoot) | Err(_) => return FileUploadResponse::Forbidden(()),
+    };
+    let conflict = conflict.unwrap_or(ConflictPolicy::Overwrite);
+    let expected_size = content_length.map(|ContentLength(len)| len);
+    match store_upload(db, &file_path, &owner, data, MAX_UPLOAD_SIZE, expected_size, conflict).await {
+        Ok((size, path)) => {
+            // path as seen by the user
+            let path = match share {
+                Some(share) => share.to_virtual(Path::new(&path)).into(),
+                None => path,
+            };
+            FileUploadResponse::Accepted(Json(UploadedFile { path, size }))
+        }
+        Err(UploadError::Conflict) => FileUploadResponse::Conflict("File exists already"),
+        Err(UploadError::QuotaExceeded) => FileUploadResponse::QuotaExceeded("Not enough space left"),
+        Err(UploadError::Failed) => FileUploadResponse::Forbidden(()),
+    }
 }
 
+/// Folders shared with the user need write access
 #[post("/create_folder?<folder_path>")]
-pub async fn post_create_folder(folder_path: NetFilePath, user_id: UserID) -> UploadResponse {
+pub async fn post_create_folder(
+    folder_path: NetFilePath,
+    writable: WritableUser,
+    db: &State<SharedDatabase>,
+) -> CreateFolderResponse {
+    let user_id = writable.0;
+    let (user_id, folder_path) = match user_shares::resolve(db, &user_id, folder_path, Access::Write) {
+        Ok(ResolvedPath::Node { owner, path, .. }) => (owner, path),
+        Ok(ResolvedPath::SharedRoot) | Err(_) => return Err(status::Forbidden(None)),
+    };
     let mut root: PathBuf = PathBuf::from(crate::config::data_path());
     root.push(&user_id.0);
     if !root.exists() {
@@ -50,7 +268,10 @@ pub async fn post_create_folder(folder_path: NetFilePath, user_id: UserID) -> Up
             }
         }
     }
-    root.push(Borrow::<str>::borrow(&folder_path));
+    let root = match super::contained_path(&root, Borrow::<Path>::borrow(&folder_path)) {
+        Some(path) => path,
+        None => return Err(status::Forbidden(None)),
+    };
     if root.exists() {
         // check if user has allready folder or needs to get created
         warn!("User tried to create existin folder");
@@ -61,12 +282,127 @@ pub async fn post_create_folder(folder_path: NetFilePath, user_id: UserID) -> Up
         warn!("Error while creating folder: {:?}", e);
         return Err(status::Forbidden(None));
     }
+    search::added(db, &user_id, &root);
     Ok(status::Accepted(None))
 }
 
 use rocket::data::ToByteUnit;
 
-async fn handle_upload(folder_path: NetFilePath, user_id: UserID, upload: Data<'_>) -> UploadResponse {
+/// Picks the first free `name (n).ext` next to target
+pub fn free_file_name(target: &Path) -> Option<PathBuf> {
+    let stem = target.file_stem()?.to_string_lossy();
+    let ext = target.extension().map(|e| e.to_string_lossy());
+    (1..1000)
+        .map(|n| {
+            let name = match &ext {
+                Some(ext) => format!("{} ({}).{}", stem, n, ext),
+                None => format!("{} ({})", stem, n),
+            };
+            target.with_file_name(name)
+        })
+        .find(|candidate| !candidate.exists())
+}
+
+/// Moves a completely received staging file to target, returns the path it was stored at.
+/// Staging files are in the data path, so renaming / linking them is atomic and target never contains a partial upload.
+/// Overwritten files are kept as versions, the upload gets added to the usage of `user_id`
+pub fn place_upload(
+    db: &SharedDatabase,
+    user_id: &UserID,
+    staging: &Path,
+    target: &Path,
+    conflict: ConflictPolicy,
+) -> Result<PathBuf, UploadError> {
+    let overwrite = target.exists() && conflict == ConflictPolicy::Overwrite;
+    if target.exists() && conflict == ConflictPolicy::Fail {
+        return Err(UploadError::Conflict);
+    }
+
+    let size = std::fs::metadata(staging).map(|md| md.len()).map_err(|e| {
+        error!("Failed to read size of {:?}: {:?}", staging, e);
+        UploadError::Failed
+    })?;
+    // the overwritten file stays as version (counted by `archive_version`), so it only moves out of the users root
+    let replaced = if overwrite {
+        std::fs::metadata(target).map(|md| md.len()).unwrap_or(0)
+    } else {
+        0
+    };
+    // only the growth of the root needs to fit into the quota
+    let reserved = size.saturating_sub(replaced);
+    if !quota::reserve(db, user_id, reserved) {
+        info!("Upload to {:?} exceeds the quota of {}", target, user_id);
+        return Err(UploadError::QuotaExceeded);
+    }
+    if overwrite {
+        info!("Overwriting existing file {:?}", target);
+        if let Err(e) = super::versions::archive_version(db, user_id, target) {
+            error!("Failed to keep version of {:?}: {:?}", target, e);
+            quota::change(db, user_id, -(reserved as i64));
+            return Err(UploadError::Failed);
+        }
+        super::previews::invalidate_cache(target);
+    }
+
+    let placed = if conflict == ConflictPolicy::Overwrite {
+        std::fs::rename(staging, target)
+            .map(|()| target.to_path_buf())
+            .map_err(|e| {
+                error!("Failed to move upload to {:?}: {:?}", target, e);
+                UploadError::Failed
+            })
+    } else {
+        place_new(staging, target, conflict)
+    };
+    let target = match placed {
+        Ok(target) => target,
+        Err(e) => {
+            quota::change(db, user_id, -(reserved as i64));
+            return Err(e);
+        }
+    };
+    quota::change(db, user_id, size as i64 - replaced as i64 - reserved as i64);
+    sizes::changed(db, user_id, &target, size as i64 - replaced as i64);
+    search::added(db, user_id, &target);
+    Ok(target)
+}
+
+/// Places the staging file at target without replacing a node created since the conflict check:
+/// the hard link fails if target exists, with `ConflictPolicy::Rename` the next free name is tried
+fn place_new(staging: &Path, target: &Path, conflict: ConflictPolicy) -> Result<PathBuf, UploadError> {
+    let mut candidate = target.to_path_buf();
+    loop {
+        match std::fs::hard_link(staging, &candidate) {
+            Ok(()) => {
+                if let Err(e) = std::fs::remove_file(staging) {
+                    warn!("Failed to remove staging file {:?}: {:?}", staging, e);
+                }
+                return Ok(candidate);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists && conflict == ConflictPolicy::Rename => {
+                candidate = free_file_name(target).ok_or(UploadError::Conflict)?;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => return Err(UploadError::Conflict),
+            Err(e) => {
+                error!("Failed to move upload to {:?}: {:?}", candidate, e);
+                return Err(UploadError::Failed);
+            }
+        }
+    }
+}
+
+/// Streams the upload into a staging file and moves it to the target if it was received completely.
+/// Uploads larger than the space left for `user_id` get rejected, before streaming if `expected_size` is known.
+/// Returns the written bytes and the path (from the users root) the file was stored at
+async fn store_upload(
+    db: &SharedDatabase,
+    folder_path: &NetFilePath,
+    user_id: &UserID,
+    upload: Data<'_>,
+    max_size: u64,
+    expected_size: Option<u64>,
+    conflict: ConflictPolicy,
+) -> Result<(u64, String), UploadError> {
     let mut root: PathBuf = PathBuf::from(crate::config::data_path());
     root.push(&user_id.0);
     if !root.exists() {
@@ -74,32 +410,151 @@ async fn handle_upload(folder_path: NetFilePath, user_id: UserID, upload: Data<'
             Ok(()) => info!("Created base dir of user {}", user_id.0),
             Err(e) => {
                 warn!("Failed to create base dir of user {}: {:?}", user_id.0, e);
-                return Err(status::Forbidden(None));
+                return Err(UploadError::Failed);
             }
         }
     }
-    root.push(Borrow::<str>::borrow(&folder_path));
-    
-    if root.exists() {
-        // check if user has allready folder or needs to get created
-        info!("User overwriting existing file");
-    } 
+    let user_root = root.clone();
+    let root = super::contained_path(&user_root, Borrow::<Path>::borrow(folder_path)).ok_or(UploadError::Failed)?;
+
+    if root.is_dir() || !root.parent().map(Path::is_dir).unwrap_or(false) {
+        warn!("Upload target {:?} is a folder or has no parent folder", root);
+        return Err(UploadError::Failed);
+    }
+    // don't stream the whole file if it gets rejected anyways
+    if conflict == ConflictPolicy::Fail && root.exists() {
+        return Err(UploadError::Conflict);
+    }
+    let available = quota::available_bytes(db, user_id).map_err(|e| {
+        error!("Failed to load quota of {}: {:?}", user_id, e);
+        UploadError::Failed
+    })?;
+    if let (Some(available), Some(expected)) = (available, expected_size) {
+        if expected > available {
+            info!("Upload of {} bytes to {:?} exceeds the quota of {}", expected, root, user_id);
+            return Err(UploadError::QuotaExceeded);
+        }
+    }
+    let limited_by_quota = available.map(|a| a < max_size).unwrap_or(false);
+    let max_size = available.map(|a| a.min(max_size)).unwrap_or(max_size);
+
+    let mut staging = staging_dir();
+    if let Err(e) = tokio::fs::create_dir_all(&staging).await {
+        error!("Failed to create staging dir {:?}: {:?}", staging, e);
+        return Err(UploadError::Failed);
+    }
+    staging.push(format!(
+        "upload_{}",
+        crate::utils::get_rand_token::<16>().iter().map(|e| *e as char).collect::<String>()
+    ));
 
-    let target_file =  match tokio::fs::OpenOptions::new().write(true).create(true).open(&root).await {
-        Ok(f)=> f,
-        Err(_) => return Err(status::Forbidden(None))
+    let staging_file = match tokio::fs::OpenOptions::new().write(true).create_new(true).open(&staging).await {
+        Ok(f) => f,
+        Err(e) => {
+            error!("Failed to create staging file {:?}: {:?}", staging, e);
+            return Err(UploadError::Failed);
+        }
     };
 
-    info!("Streaing to file {:?}", root);
-    // stream file to root
-    match upload.open(10u64.gibibytes()).stream_to(target_file).await {
-        Ok(size) => {
-            info!("Uploaded {} bytes to {:?}", size, root);
-            Ok(status::Accepted(None))
+    info!("Streaing to file {:?}", staging);
+    // stream file to staging
+    let written = match upload.open(max_size.bytes()).stream_to(staging_file).await {
+        Ok(n) if n.complete => Ok(n.written),
+        Ok(_) => {
+            warn!("Upload to {:?} exceeded {} bytes", root, max_size);
+            Err(if limited_by_quota { UploadError::QuotaExceeded } else { UploadError::Failed })
         }
         Err(e) => {
             warn!("Upload failed: {}", e);
-            Err(status::Forbidden(None))
+            Err(UploadError::Failed)
         }
+    };
+
+    let placed = written.and_then(|written| Ok((written, place_upload(db, user_id, &staging, &root, conflict)?)));
+    let (written, stored_at) = match placed {
+        Ok(placed) => placed,
+        Err(e) => {
+            if let Err(e) = tokio::fs::remove_file(&staging).await {
+                error!("Failed to remove staging file {:?}: {:?}", staging, e);
+            }
+            return Err(e);
+        }
+    };
+
+    info!("Uploaded {} bytes to {:?}", written, stored_at);
+    let rel_path = stored_at
+        .strip_prefix(&user_root)
+        .map(|p| NetFilePath::from_path(p).into())
+        .unwrap_or_default();
+    Ok((written, rel_path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_free_file_name() {
+        let mut dir = std::env::temp_dir();
+        dir.push(format!("what-cloud-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let target = dir.join("file.txt");
+        std::fs::write(&target, b"1").unwrap();
+        assert_eq!(free_file_name(&target), Some(dir.join("file (1).txt")));
+        std::fs::write(dir.join("file (1).txt"), b"2").unwrap();
+        assert_eq!(free_file_name(&target), Some(dir.join("file (2).txt")));
+        assert_eq!(free_file_name(&dir.join("noext")), Some(dir.join("noext (1)")));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_overwrite_at_quota() {
+        let dir = crate::config::init_for_tests();
+        let db = SharedDatabase::new(&dir.join("quota-upload-test.sqlite"));
+        let user = UserID("quota001".into());
+        db.create_user(&user, "quota", "hash", crate::database::UserRoll::User).unwrap();
+        let root = super::super::to_abs_data_path(&user, "");
+        std::fs::create_dir_all(&root).unwrap();
+        let target = root.join("a.txt");
+        std::fs::write(&target, b"0123456789").unwrap();
+        db.change_usage(&user, 10).unwrap();
+        db.set_user_quota(&user, Some(10)).unwrap();
+
+        // same size fits, although the usage is at the quota
+        let staging = root.join("staging");
+        std::fs::write(&staging, b"abcdefghij").unwrap();
+        assert_eq!(place_upload(&db, &user, &staging, &target, ConflictPolicy::Overwrite).ok(), Some(target.clone()));
+        assert_eq!(std::fs::read(&target).unwrap(), b"abcdefghij");
+        // a larger file doesn't
+        std::fs::write(&staging, b"abcdefghijk").unwrap();
+        assert!(matches!(
+            place_upload(&db, &user, &staging, &target, ConflictPolicy::Overwrite),
+            Err(UploadError::QuotaExceeded)
+        ));
+
+        super::super::remove_user_files(&user, &[]).unwrap();
+        let _ = std::fs::remove_file(dir.join("quota-upload-test.sqlite"));
+    }
+
+    #[test]
+    fn test_place_new() {
+        let mut dir = std::env::temp_dir();
+        dir.push(format!("what-cloud-test-place-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let staging = dir.join("staging");
+        let target = dir.join("file.txt");
+        std::fs::write(&target, b"existing").unwrap();
+
+        std::fs::write(&staging, b"new").unwrap();
+        assert!(matches!(place_new(&staging, &target, ConflictPolicy::Fail), Err(UploadError::Conflict)));
+        assert_eq!(std::fs::read(&target).unwrap(), b"existing");
+
+        assert_eq!(place_new(&staging, &target, ConflictPolicy::Rename).ok(), Some(dir.join("file (1).txt")));
+        assert_eq!(std::fs::read(dir.join("file (1).txt")).unwrap(), b"new");
+        assert!(!staging.exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -0,0 +1,383 @@
+use super::{contained_data_path, contained_path, to_abs_data_path, NetFilePath};
+use crate::auth::{UserID, WritableUser};
+use crate::database::{DBUserShare, GetUserQuery, SharedDatabase};
+use log::{error, info};
+use rocket::http::Status;
+use rocket::response::status;
+use rocket::serde::json::Json;
+use rocket::State;
+use std::borrow::Borrow;
+use std::path::{Component, Path, PathBuf};
+
+/// Virtual folder in the root of every user, containing the folders other users shared with them
+pub const SHARED_WITH_ME: &str = "@shared";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// Folder of another user, reached through `SHARED_WITH_ME`
+#[derive(Debug)]
+pub struct SharedBase {
+    /// path of the shared folder from the owners root
+    pub owner_path: PathBuf,
+    /// `SHARED_WITH_ME` and the name of the shared folder
+    pub virtual_prefix: Vec<String>,
+}
+
+impl SharedBase {
+    /// Path inside the owners tree as seen by the user the folder is shared with
+    pub fn to_virtual(&self, owner_rel: &Path) -> NetFilePath {
+        let mut path: PathBuf = self.virtual_prefix.iter().collect();
+        match owner_rel.strip_prefix(&self.owner_path) {
+            Ok(rest) if !rest.as_os_str().is_empty() => path.push(rest),
+            _ => {}
+        }
+        NetFilePath::from_path(path)
+    }
+}
+
+#[derive(Debug)]
+pub enum ResolvedPath {
+    /// `path` is relative to the root of `owner`, `share` is set if it was reached through `SHARED_WITH_ME`
+    Node {
+        owner: UserID,
+        path: NetFilePath,
+        share: Option<SharedBase>,
+    },
+    /// `SHARED_WITH_ME` itself
+    SharedRoot,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ResolveError {
+    NotFound,
+    /// only shared for reading
+    Forbidden,
+}
+
+pub fn is_virtual(path: &NetFilePath) -> bool {
+    first_component(Borrow::<Path>::borrow(path)).as_deref() == Some(SHARED_WITH_ME)
+}
+
+/// True for the absolute path a real `SHARED_WITH_ME` of the user would have, it would be unreachable
+pub fn is_reserved(user_id: &UserID, abs_path: &Path) -> bool {
+    abs_path == to_abs_data_path(user_id, SHARED_WITH_ME)
+}
+
+/// Renames real nodes named `SHARED_WITH_ME` in the roots of the users (created before user shares existed)
+/// to free names. Runs before the size and index jobs, which rebuild their rows of the renamed nodes
+pub fn rename_reserved_nodes(db: &SharedDatabase) {
+    let users = match db.get_all_users() {
+        Ok(users) => users,
+        Err(e) => {
+            error!("Failed to load users: {:?}", e);
+            return;
+        }
+    };
+    for user in users {
+        let reserved = to_abs_data_path(&user.id, SHARED_WITH_ME);
+        if std::fs::symlink_metadata(&reserved).is_err() {
+            continue;
+        }
+        let free = match super::upload::free_file_name(&reserved) {
+            Some(free) => free,
+            None => {
+                error!("No free name for {:?} of {}", reserved, user.id);
+                continue;
+            }
+        };
+        if let Err(e) = std::fs::rename(&reserved, &free) {
+            error!("Failed to rename {:?}: {:?}", reserved, e);
+            continue;
+        }
+        let new_name = Path::new(free.file_name().unwrap_or_default());
+        if let Err(e) = db.move_shares(&user.id, Path::new(SHARED_WITH_ME), new_name) {
+            error!("Failed to update shares after rename: {:?}", e);
+        }
+        if let Err(e) = db.move_versions(&user.id, Path::new(SHARED_WITH_ME), new_name) {
+            error!("Failed to update versions after rename: {:?}", e);
+        }
+        if let Err(e) = db.move_user_shares(&user.id, Path::new(SHARED_WITH_ME), new_name) {
+            error!("Failed to update user shares after rename: {:?}", e);
+        }
+        info!("Renamed {:?} of {} to {:?}, the name is reserved", SHARED_WITH_ME, user.id, new_name);
+    }
+}
+
+fn first_component(path: &Path) -> Option<String> {
+    path.components()
+        .find(|c| matches!(c, Component::Normal(_)))
+        .map(|c| c.as_os_str().to_string_lossy().to_string())
+}
+
+/// Resolves a path from the users perspective to the owner of the node and the path in the owners tree,
+/// checking that shares of other users allow `access`
+pub fn resolve(
+    db: &SharedDatabase,
+    user_id: &UserID,
+    path: NetFilePath,
+    access: Access,
+) -> Result<ResolvedPath, ResolveError> {
+    if !is_virtual(&path) {
+        return Ok(ResolvedPath::Node {
+            owner: user_id.clone(),
+            path,
+            share: None,
+        });
+    }
+
+    let mut components = Borrow::<Path>::borrow(&path)
+        .components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .skip(1);
+    let name = match components.next() {
+        Some(name) => name.as_os_str().to_string_lossy().to_string(),
+        None if access == Access::Read => return Ok(ResolvedPath::SharedRoot),
+        None => return Err(ResolveError::Forbidden),
+    };
+    let rest: PathBuf = components.collect();
+
+    let share = incoming_shares(db, user_id)
+        .into_iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, share)| share)
+        .ok_or(ResolveError::NotFound)?;
+    if access == Access::Write && !share.writable {
+        return Err(ResolveError::Forbidden);
+    }
+    // the path must stay inside the shared folder, not only inside the owners root
+    if !inside_share(&to_abs_data_path(&share.owner, ""), &share.path, &rest) {
+        return Err(ResolveError::Forbidden);
+    }
+
+    Ok(ResolvedPath::Node {
+        path: NetFilePath::from_path(share.path.join(rest)),
+        owner: share.owner,
+        share: Some(SharedBase {
+            owner_path: share.path,
+            virtual_prefix: vec![SHARED_WITH_ME.into(), name],
+        }),
+    })
+}
+
+/// True if `rest` stays inside the shared folder at `share_path` below `owner_root`, also after resolving symlinks
+fn inside_share(owner_root: &Path, share_path: &Path, rest: &Path) -> bool {
+    contained_path(owner_root, share_path)
+        .and_then(|share_root| contained_path(&share_root, rest))
+        .is_some()
+}
+
+/// Shares with the user and the names they have in `SHARED_WITH_ME`.
+/// Names are the folder names (the owners name for a shared root), duplicates get numbered by age
+pub fn incoming_shares(db: &SharedDatabase, user_id: &UserID) -> Vec<(String, DBUserShare)> {
+    let shares = match db.get_incoming_user_shares(user_id) {
+        Ok(shares) => shares,
+        Err(e) => {
+            error!("Failed to load shares with {}: {:?}", user_id, e);
+            return Vec::new();
+        }
+    };
+
+    let mut named: Vec<(String, DBUserShare)> = Vec::with_capacity(shares.len());
+    for share in shares {
+        let base = share
+            .path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_else(|| share.owner_name.clone());
+        let mut name = base.clone();
+        let mut n = 2;
+        while named.iter().any(|(other, _)| *other == name) {
+            name = format!("{} ({})", base, n);
+            n += 1;
+        }
+        named.push((name, share));
+    }
+    named
+}
+
+/// Can be shared read-only or writable
+#[derive(FromFormField, Debug, Clone, Copy, PartialEq)]
+pub enum ShareAccess {
+    #[field(value = "read")]
+    Read,
+    #[field(value = "write")]
+    Write,
+}
+
+fn access_name(writable: bool) -> &'static str {
+    if writable {
+        "write"
+    } else {
+        "read"
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct CreatedUserShare {
+    id: String,
+}
+
+/// Shares the folder with the user named `user`, changes the access if it is shared already
+#[post("/user_shares?<path>&<user>&<access>")]
+pub fn create_user_share(
+    path: NetFilePath,
+    user: &str,
+    access: ShareAccess,
+    writable: WritableUser,
+    db: &State<SharedDatabase>,
+) -> Result<Json<CreatedUserShare>, status::Custom<&'static str>> {
+    let user_id = writable.0;
+    if is_virtual(&path) {
+        return Err(status::Custom(Status::Forbidden, "Can't share folders of other users"));
+    }
+    if !contained_data_path(&user_id, Borrow::<Path>::borrow(&path)).map(|p| p.is_dir()).unwrap_or(false) {
+        return Err(status::Custom(Status::NotFound, "Folder doesn't exist"));
+    }
+    let target = match db.get_user(GetUserQuery::ByName(user)) {
+        Ok(target) if target.id != user_id => target,
+        Ok(_) => return Err(status::Custom(Status::BadRequest, "Can't share with yourself")),
+        Err(_) => return Err(status::Custom(Status::NotFound, "User doesn't exist")),
+    };
+
+    match db.upsert_user_share(&user_id, Borrow::<Path>::borrow(&path), &target.id, access == ShareAccess::Write) {
+        Ok(id) => {
+            info!("{} shared {:?} with {} ({:?})", user_id, path, target.id, access);
+            Ok(Json(CreatedUserShare { id }))
+        }
+        Err(e) => {
+            error!("Failed to create user share: {:?}", e);
+            Err(status::Custom(Status::InternalServerError, "Failed to share"))
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct OutgoingUserShare {
+    id: String,
+    path: String,
+    /// name of the user it is shared with
+    user: String,
+    access: &'static str,
+    #[serde(rename = "createdAt")]
+    created_at: String,
+}
+
+/// Folders the user shared with others
+#[get("/user_shares")]
+pub fn get_outgoing_user_shares(
+    user_id: UserID,
+    db: &State<SharedDatabase>,
+) -> Result<Json<Vec<OutgoingUserShare>>, status::Custom<()>> {
+    let shares = db.get_outgoing_user_shares(&user_id).map_err(|e| {
+        error!("Failed to load shares of {}: {:?}", user_id, e);
+        status::Custom(Status::InternalServerError, ())
+    })?;
+    Ok(Json(
+        shares
+            .into_iter()
+            .map(|s| OutgoingUserShare {
+                path: NetFilePath::from_path(&s.path).into(),
+                access: access_name(s.writable),
+                user: s.target_name,
+                created_at: s.created_at,
+                id: s.id,
+            })
+            .collect(),
+    ))
+}
+
+#[derive(Serialize, Debug)]
+pub struct IncomingUserShare {
+    id: String,
+    /// name in `SHARED_WITH_ME`
+    name: String,
+    /// `SHARED_WITH_ME/<name>`, usable as path for all node routes
+    path: String,
+    /// name of the owner
+    owner: String,
+    access: &'static str,
+}
+
+/// Folders other users shared with the user
+#[get("/user_shares/incoming")]
+pub fn get_incoming_user_shares(user_id: UserID, db: &State<SharedDatabase>) -> Json<Vec<IncomingUserShare>> {
+    Json(
+        incoming_shares(db, &user_id)
+            .into_iter()
+            .map(|(name, s)| IncomingUserShare {
+                path: format!("{}/{}", SHARED_WITH_ME, name),
+                name,
+                owner: s.owner_name,
+                access: access_name(s.writable),
+                id: s.id,
+            })
+            .collect(),
+    )
+}
+
+/// Can be removed by the owner and by the user it is shared with
+#[delete("/user_shares?<id>")]
+pub fn delete_user_share(
+    id: &str,
+    writable: WritableUser,
+    db: &State<SharedDatabase>,
+) -> Result<status::NoContent, status::Custom<()>> {
+    let user_id = writable.0;
+    let share = match db.get_user_share(id) {
+        Some(share) if share.owner == user_id || share.target == user_id => share,
+        _ => return Err(status::Custom(Status::NotFound, ())),
+    };
+    if let Err(e) = db.delete_user_share(&share.id) {
+        error!("Failed to delete user share {}: {:?}", share.id, e);
+        return Err(status::Custom(Status::InternalServerError, ()));
+    }
+    info!("{} removed the share of {:?} with {}", user_id, share.path, share.target);
+    Ok(status::NoContent)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_virtual_paths() {
+        assert!(is_virtual(&NetFilePath::from_path("@shared")));
+        assert!(is_virtual(&NetFilePath::from_path("/@shared/docs/a.txt")));
+        assert!(!is_virtual(&NetFilePath::from_path("docs/@shared")));
+        assert!(!is_virtual(&NetFilePath::from_path("")));
+
+        let base = SharedBase {
+            owner_path: PathBuf::from("work/docs"),
+            virtual_prefix: vec![SHARED_WITH_ME.into(), "docs (2)".into()],
+        };
+        assert_eq!(base.to_virtual(Path::new("work/docs/a/b.txt")).as_str(), "@shared/docs (2)/a/b.txt");
+        assert_eq!(base.to_virtual(Path::new("work/docs")).as_str(), "@shared/docs (2)");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_out_of_share() {
+        let mut root = std::env::temp_dir();
+        root.push(format!("what-cloud-user-share-test-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("shared")).unwrap();
+        std::fs::create_dir_all(root.join("private")).unwrap();
+        std::fs::write(root.join("private").join("secret.txt"), b"x").unwrap();
+        std::os::unix::fs::symlink(root.join("private"), root.join("shared").join("link")).unwrap();
+
+        let shared = Path::new("shared");
+        assert!(inside_share(&root, shared, Path::new("")));
+        assert!(inside_share(&root, shared, Path::new("new.txt")));
+        assert!(!inside_share(&root, shared, Path::new("link")));
+        assert!(!inside_share(&root, shared, Path::new("link/secret.txt")));
+        assert!(!inside_share(&root, shared, Path::new("link/new.txt")));
+        // the link is fine for the owner, it stays in their root
+        assert!(contained_path(&root, Path::new("shared/link/secret.txt")).is_some());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,238 @@
+use super::download::{serve_file, FileDownloadResponse, RequestedRange};
+use super::node_ops::{ChangedNode, NodeChangeResponse};
+use super::{previews, quota, search, sizes, to_abs_data_path, NetFilePath};
+use crate::auth::{UserID, WritableUser};
+use crate::database::{DBVersion, SharedDatabase};
+use log::{error, info, warn};
+use rocket::response::status;
+use rocket::serde::json::Json;
+use rocket::State;
+use std::borrow::Borrow;
+use std::path::{Path, PathBuf};
+
+/// Previous contents of the users files, inside the data path but outside of the users root
+pub(super) fn versions_dir(user_id: &UserID) -> PathBuf {
+    let mut dir = PathBuf::from(crate::config::data_path());
+    dir.push(".versions");
+    dir.push(&user_id.0);
+    dir
+}
+
+fn version_path(user_id: &UserID, id: &str) -> PathBuf {
+    let mut path = versions_dir(user_id);
+    path.push(id);
+    path
+}
+
+fn db_error(e: rusqlite::Error) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Other, format!("{:?}", e))
+}
+
+/// Keeps the current content of the file at `abs_path` as a version, call before it gets replaced.
+/// The version is a hard link, so the file can be replaced atomically by renaming over it afterwards
+pub fn archive_version(db: &SharedDatabase, user_id: &UserID, abs_path: &Path) -> std::io::Result<()> {
+    if let Some(rel_path) = keep_version(db, user_id, abs_path)? {
+        prune_versions(db, user_id, &rel_path, Some(crate::config::versions_max_count()), None)?;
+    }
+    Ok(())
+}
+
+/// `archive_version` without pruning the old versions, returns the path from the users root if a version was kept
+fn keep_version(db: &SharedDatabase, user_id: &UserID, abs_path: &Path) -> std::io::Result<Option<PathBuf>> {
+    if !abs_path.is_file() {
+        return Ok(None);
+    }
+    let rel_path = abs_path
+        .strip_prefix(to_abs_data_path(user_id, ""))
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "path is outside of the users root"))?;
+
+    let id: String = crate::utils::get_rand_token::<16>()
+        .iter()
+        .map(|e| *e as char)
+        .collect();
+    std::fs::create_dir_all(versions_dir(user_id))?;
+    let stored = version_path(user_id, &id);
+    if let Err(e) = std::fs::hard_link(abs_path, &stored) {
+        warn!("Failed to link version of {:?}, copying instead: {:?}", abs_path, e);
+        std::fs::copy(abs_path, &stored)?;
+    }
+
+    let size = std::fs::metadata(&stored)?.len();
+    if let Err(e) = db.add_version(&id, user_id, rel_path, size) {
+        let _ = std::fs::remove_file(&stored);
+        return Err(db_error(e));
+    }
+    // versions count against the quota, but must not fail because of it
+    quota::change(db, user_id, size as i64);
+    Ok(Some(rel_path.to_path_buf()))
+}
+
+fn delete_version(db: &SharedDatabase, version: &DBVersion) -> std::io::Result<()> {
+    let path = version_path(&version.user, &version.id);
+    match std::fs::remove_file(&path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => warn!("Version {:?} is missing", path),
+        Err(e) => return Err(e),
+    }
+    quota::change(db, &version.user, -(version.size as i64));
+    db.delete_version(&version.id).map_err(db_error)
+}
+
+/// Deletes all but the newest `keep` versions and all versions older than `max_age_days`.
+/// Returns how many got deleted
+pub fn prune_versions(
+    db: &SharedDatabase,
+    user_id: &UserID,
+    rel_path: &Path,
+    keep: Option<usize>,
+    max_age_days: Option<u32>,
+) -> std::io::Result<usize> {
+    let mut pruned: Vec<DBVersion> = match keep {
+        Some(keep) => db
+            .get_versions(user_id, rel_path)
+            .map_err(db_error)?
+            .into_iter()
+            .skip(keep)
+            .collect(),
+        None => Vec::new(),
+    };
+    if let Some(days) = max_age_days {
+        for version in db.get_expired_versions(user_id, rel_path, days).map_err(db_error)? {
+            if !pruned.iter().any(|v| v.id == version.id) {
+                pruned.push(version);
+            }
+        }
+    }
+
+    for version in &pruned {
+        delete_version(db, version)?;
+    }
+    Ok(pruned.len())
+}
+
+/// Removes the versions of files at or below `rel_path`, e.g. because they got deleted permanently
+pub fn delete_versions_below(db: &SharedDatabase, user_id: &UserID, rel_path: &Path) -> std::io::Result<usize> {
+    let versions = db.get_versions_below(user_id, rel_path).map_err(db_error)?;
+    for version in &versions {
+        delete_version(db, version)?;
+    }
+    Ok(versions.len())
+}
+
+#[derive(Serialize, Debug)]
+pub struct FileVersion {
+    id: String,
+    size: u64,
+    #[serde(rename = "createdAt")]
+    created_at: String,
+}
+
+/// Versions of the file, newest first
+#[get("/versions?<path>")]
+pub fn get_versions(
+    path: NetFilePath,
+    user_id: UserID,
+    db: &State<SharedDatabase>,
+) -> Result<Json<Vec<FileVersion>>, status::Custom<()>> {
+    let versions = db.get_versions(&user_id, Borrow::<Path>::borrow(&path)).map_err(|e| {
+        error!("Failed to load versions of {:?}: {:?}", path, e);
+        status::Custom(rocket::http::Status::InternalServerError, ())
+    })?;
+
+    Ok(Json(
+        versions
+            .into_iter()
+            .map(|v| FileVersion {
+                id: v.id,
+                size: v.size,
+                created_at: v.created_at,
+            })
+            .collect(),
+    ))
+}
+
+/// token gets read by the `UserID` guard from the `token` query parameter, like `download::download_file`
+#[get("/versions/download?<id>")]
+pub async fn download_version(
+    id: &str,
+    token: UserID,
+    db: &State<SharedDatabase>,
+    range: Option<RequestedRange>,
+) -> FileDownloadResponse {
+    match db.get_version(id, &token) {
+        Some(version) => serve_file(&version_path(&token, &version.id), range).await,
+        None => FileDownloadResponse::NotFound(()),
+    }
+}
+
+/// Replaces the file with the version, the replaced content is kept as a new version
+#[post("/versions/restore?<id>")]
+pub fn restore_version(id: &str, writable: WritableUser, db: &State<SharedDatabase>) -> NodeChangeResponse {
+    let user_id = writable.0;
+    let version = match db.get_version(id, &user_id) {
+        Some(v) => v,
+        None => return NodeChangeResponse::NotFound(()),
+    };
+    let target = to_abs_data_path(&user_id, &version.path);
+    if target.is_dir() {
+        return NodeChangeResponse::Conflict("A folder exists at the path of the file");
+    }
+    if !target.parent().map(Path::is_dir).unwrap_or(false) {
+        return NodeChangeResponse::NotFound(());
+    }
+
+    let current = std::fs::metadata(&target).map(|md| md.len()).unwrap_or(0);
+    // pruned after the restore, the restored version could be the oldest one
+    if let Err(e) = keep_version(db, &user_id, &target) {
+        error!("Failed to keep current version of {:?}: {:?}", target, e);
+        return NodeChangeResponse::ServerError(());
+    }
+    previews::invalidate_cache(&target);
+    if let Err(e) = std::fs::rename(version_path(&user_id, &version.id), &target) {
+        error!("Failed to restore version {}: {:?}", version.id, e);
+        return NodeChangeResponse::ServerError(());
+    }
+    // the restored version moved into the users root and the current content is a version now
+    quota::change(db, &user_id, -(current as i64));
+    sizes::changed(db, &user_id, &target, version.size as i64 - current as i64);
+    search::added(db, &user_id, &target);
+    if let Err(e) = db.delete_version(&version.id) {
+        error!("Failed to delete restored version {}: {:?}", version.id, e);
+    }
+    if let Err(e) = prune_versions(db, &user_id, &version.path, Some(crate::config::versions_max_count()), None) {
+        error!("Failed to prune versions of {:?}: {:?}", version.path, e);
+    }
+
+    info!("{} restored version {} of {:?}", user_id, version.id, version.path);
+    NodeChangeResponse::Accepted(Json(ChangedNode {
+        path: NetFilePath::from_path(&version.path).into(),
+    }))
+}
+
+#[derive(Serialize, Debug)]
+pub struct PrunedVersions {
+    deleted: usize,
+}
+
+/// Deletes all but the newest `keep` versions and all older than `max_age_days`, without both all versions
+#[delete("/versions?<path>&<keep>&<max_age_days>")]
+pub fn prune_file_versions(
+    path: NetFilePath,
+    keep: Option<usize>,
+    max_age_days: Option<u32>,
+    writable: WritableUser,
+    db: &State<SharedDatabase>,
+) -> Result<Json<PrunedVersions>, status::Custom<()>> {
+    let user_id = writable.0;
+    let keep = if keep.is_none() && max_age_days.is_none() { Some(0) } else { keep };
+    match prune_versions(db, &user_id, Borrow::<Path>::borrow(&path), keep, max_age_days) {
+        Ok(deleted) => {
+            info!("{} pruned {} versions of {:?}", user_id, deleted, path);
+            Ok(Json(PrunedVersions { deleted }))
+        }
+        Err(e) => {
+            error!("Failed to prune versions of {:?}: {:?}", path, e);
+            Err(status::Custom(rocket::http::Status::InternalServerError, ()))
+        }
+    }
+}
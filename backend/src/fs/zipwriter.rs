@@ -1,7 +1,7 @@
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Condvar, Mutex};
 
-// use super::async_buf::{split_blocking_async, AsyncConsumer};
+use super::async_buf::{split_blocking_async, AsyncConsumer};
 use lazy_static::lazy_static;
 use log::{error, info};
 use ringbuf::RingBuffer;
@@ -57,6 +57,13 @@ pub fn new_zip_writer(path: std::path::PathBuf) -> Result<AsyncConsumer, &'stati
             );
             ZIP_WRITER_FINISHED.notify_one();
         })
-        .map_err(|_| "Failed to start ZIP worker thread")?;
+        .map_err(|_| {
+            // worker never started, so free its slot again
+            if let Ok(mut thread_count) = ZIP_WRITER_THREAD_COUNT.lock() {
+                *thread_count -= 1;
+            }
+            ZIP_WRITER_FINISHED.notify_one();
+            "Failed to start ZIP worker thread"
+        })?;
     Ok(cons)
 }
@@ -15,6 +15,7 @@ mod config;
 mod database;
 mod fs;
 mod icons;
+mod users;
 mod utils;
 
 #[get("/")]
@@ -52,6 +53,23 @@ fn rocket() -> _ {
 
     let db = database::SharedDatabase::new(config::db_path());
 
+    // e.g. `backend users list`, runs instead of the server
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        match users::run_command(&db, &args) {
+            Ok(()) => std::process::exit(0),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    fs::quota::init_usage(&db);
+    fs::user_shares::rename_reserved_nodes(&db);
+    fs::trash::start_retention_job();
+    fs::sizes::start_reconcile_job();
+    fs::shared::start_cleanup_job();
+
     info!("Cache path: {:?}", crate::fs::previews::cache_path());
 
     let mut api_routes = api_mount::mount_api();
@@ -0,0 +1,292 @@
+use crate::auth::{hash_password, UserID};
+use crate::database::{DBUser, GetUserQuery, SharedDatabase, UserRoll};
+use log::{error, info, warn};
+use std::convert::TryFrom;
+use std::io::{BufRead, Write};
+
+/// Errors of the account management, used by the admin routes and the `users` command line subcommand
+#[derive(Debug)]
+pub enum UserError {
+    NotFound,
+    NameTaken,
+    InvalidName,
+    Database(rusqlite::Error),
+    Hash(argon2::password_hash::Error),
+}
+
+impl From<rusqlite::Error> for UserError {
+    fn from(e: rusqlite::Error) -> Self {
+        match e {
+            rusqlite::Error::QueryReturnedNoRows => UserError::NotFound,
+            e => UserError::Database(e),
+        }
+    }
+}
+
+impl std::fmt::Display for UserError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UserError::NotFound => write!(f, "User doesn't exist"),
+            UserError::NameTaken => write!(f, "Name is already taken"),
+            UserError::InvalidName => write!(f, "Names need 1 to 64 characters and no control characters"),
+            UserError::Database(e) => write!(f, "Database error: {:?}", e),
+            UserError::Hash(e) => write!(f, "Failed to hash password: {:?}", e),
+        }
+    }
+}
+
+fn check_name(db: &SharedDatabase, name: &str) -> Result<(), UserError> {
+    if name.trim() != name || name.is_empty() || name.chars().count() > 64 || name.chars().any(char::is_control) {
+        return Err(UserError::InvalidName);
+    }
+    match db.get_user(GetUserQuery::ByName(name)) {
+        Ok(_) => Err(UserError::NameTaken),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(()),
+        Err(e) => Err(UserError::Database(e)),
+    }
+}
+
+fn revoke_sessions(db: &SharedDatabase, user_id: &UserID) {
+    match db.delete_user_sessions(user_id) {
+        Ok(n) => info!("Revoked {} sessions of {}", n, user_id),
+        Err(e) => error!("Failed to revoke sessions of {}: {:?}", user_id, e),
+    }
+}
+
+/// `password_base64` like sent by the login form
+pub fn create_user(db: &SharedDatabase, name: &str, password_base64: &str, roll: UserRoll) -> Result<UserID, UserError> {
+    check_name(db, name)?;
+    let hash = hash_password(password_base64).map_err(UserError::Hash)?;
+    // ids are 8 random chars, retry in the unlikely case of a collision
+    let user_id = loop {
+        let id = UserID(crate::utils::get_rand_token::<8>().iter().map(|e| *e as char).collect());
+        match db.get_user(GetUserQuery::ByID(&id)) {
+            Err(rusqlite::Error::QueryReturnedNoRows) => break id,
+            Err(e) => return Err(UserError::Database(e)),
+            Ok(_) => {}
+        }
+    };
+    db.create_user(&user_id, name, &hash, roll)?;
+    info!("Created user {} ({}) as {:?}", user_id, name, roll);
+    Ok(user_id)
+}
+
+pub fn rename_user(db: &SharedDatabase, user_id: &UserID, name: &str) -> Result<(), UserError> {
+    let user = db.get_user(GetUserQuery::ByID(user_id))?;
+    if user.name == name {
+        return Ok(());
+    }
+    check_name(db, name)?;
+    db.rename_user(user_id, name)?;
+    info!("Renamed {} from {} to {}", user_id, user.name, name);
+    Ok(())
+}
+
+/// All sessions get revoked, so the user needs to log in with the new password
+pub fn reset_password(db: &SharedDatabase, user_id: &UserID, password_base64: &str) -> Result<(), UserError> {
+    db.get_user(GetUserQuery::ByID(user_id))?;
+    let hash = hash_password(password_base64).map_err(UserError::Hash)?;
+    db.update_password_hash(user_id, &hash)?;
+    revoke_sessions(db, user_id);
+    info!("Reset password of {}", user_id);
+    Ok(())
+}
+
+/// Tokens carry the roll, so all sessions get revoked
+pub fn set_roll(db: &SharedDatabase, user_id: &UserID, roll: UserRoll) -> Result<(), UserError> {
+    let user = db.get_user(GetUserQuery::ByID(user_id))?;
+    if user.roll == roll {
+        return Ok(());
+    }
+    db.set_user_roll(user_id, roll)?;
+    revoke_sessions(db, user_id);
+    info!("Changed roll of {} from {:?} to {:?}", user_id, user.roll, roll);
+    Ok(())
+}
+
+pub fn set_disabled(db: &SharedDatabase, user_id: &UserID, disabled: bool) -> Result<(), UserError> {
+    db.get_user(GetUserQuery::ByID(user_id))?;
+    db.set_user_disabled(user_id, disabled)?;
+    if disabled {
+        revoke_sessions(db, user_id);
+    }
+    info!("{} {}", if disabled { "Disabled" } else { "Enabled" }, user_id);
+    Ok(())
+}
+
+/// `None` removes the quota. A quota below the usage only stops new files, nothing gets deleted
+pub fn set_quota(db: &SharedDatabase, user_id: &UserID, quota_bytes: Option<u64>) -> Result<(), UserError> {
+    db.get_user(GetUserQuery::ByID(user_id))?;
+    db.set_user_quota(user_id, quota_bytes)?;
+    info!("Set quota of {} to {:?} bytes", user_id, quota_bytes);
+    Ok(())
+}
+
+/// Deletes the user with all files, trash, versions, shares and the avatar
+pub fn delete_user(db: &SharedDatabase, user_id: &UserID) -> Result<(), UserError> {
+    db.get_user(GetUserQuery::ByID(user_id))?;
+    let uploads = db.delete_user(user_id)?;
+    if let Err(e) = crate::fs::remove_user_files(user_id, &uploads) {
+        warn!("Failed to remove all files of deleted user {}: {:?}", user_id, e);
+    }
+    if let Err(e) = crate::auth::account::remove_avatar(user_id) {
+        warn!("Failed to remove avatar of deleted user {}: {:?}", user_id, e);
+    }
+    info!("Deleted user {}", user_id);
+    Ok(())
+}
+
+const USAGE: &str = "usage: backend users <command>
+    list
+    create <name> [guest|user|admin]    password is read from stdin
+    rename <name> <new name>
+    password <name>                     password is read from stdin
+    roll <name> <guest|user|admin>
+    disable <name>
+    enable <name>
+    delete <name>
+    quota <name> <bytes|none>           bytes can have a k, m, g or t suffix
+    recalc                              recalculates the storage usage of all users from their files";
+
+fn read_password(name: &str) -> Result<String, String> {
+    eprint!("Password for {}: ", name);
+    let _ = std::io::stderr().flush();
+    let mut password = String::new();
+    std::io::stdin()
+        .lock()
+        .read_line(&mut password)
+        .map_err(|e| format!("Failed to read password: {}", e))?;
+    let password = password.trim_end_matches(&['\r', '\n'][..]);
+    if password.is_empty() {
+        return Err("Password can't be empty".into());
+    }
+    // the login form sends btoa(password), so it gets hashed like that. btoa only takes latin1 characters
+    let latin1: Option<Vec<u8>> = password.chars().map(|c| u8::try_from(c as u32).ok()).collect();
+    match latin1 {
+        Some(bytes) => Ok(base64::encode(bytes)),
+        None => Err("Passwords can only contain latin1 characters".into()),
+    }
+}
+
+/// `none` or bytes with an optional binary suffix, e.g. `500m`
+fn parse_quota(quota: &str) -> Result<Option<u64>, String> {
+    if quota.eq_ignore_ascii_case("none") {
+        return Ok(None);
+    }
+    let lower = quota.to_ascii_lowercase();
+    let (number, factor) = match lower.char_indices().last() {
+        Some((i, 'k')) => (&lower[..i], 1u64 << 10),
+        Some((i, 'm')) => (&lower[..i], 1 << 20),
+        Some((i, 'g')) => (&lower[..i], 1 << 30),
+        Some((i, 't')) => (&lower[..i], 1 << 40),
+        _ => (&lower[..], 1),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(factor))
+        .map(Some)
+        .ok_or_else(|| format!("Invalid quota {}, use none or bytes like 500m", quota))
+}
+
+fn parse_roll(roll: &str) -> Result<UserRoll, String> {
+    roll.parse().map_err(|_| format!("Unknown roll {}, use guest, user or admin", roll))
+}
+
+/// Runs the `users` subcommand, `args` without the binary name
+pub fn run_command(db: &SharedDatabase, args: &[String]) -> Result<(), String> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let by_name = |name: &str| -> Result<DBUser, String> {
+        db.get_user(GetUserQuery::ByName(name))
+            .map_err(|e| UserError::from(e).to_string())
+    };
+
+    let res = match args.as_slice() {
+        ["users", "list"] => {
+            let users = db.get_all_users().map_err(|e| UserError::from(e).to_string())?;
+            for user in users {
+                let quota = user.quota_bytes.map(|q| q.to_string()).unwrap_or_else(|| "unlimited".into());
+                println!(
+                    "{}  {:<24} {:?}{}  {} / {} bytes",
+                    user.id.0,
+                    user.name,
+                    user.roll,
+                    if user.disabled { " (disabled)" } else { "" },
+                    user.used_bytes.unwrap_or(0),
+                    quota
+                );
+            }
+            Ok(())
+        }
+        ["users", "create", name] | ["users", "create", name, _] => {
+            let roll = match args.get(3) {
+                Some(roll) => parse_roll(roll)?,
+                None => UserRoll::User,
+            };
+            let password = read_password(name)?;
+            create_user(db, name, &password, roll).map(|id| println!("{}", id.0))
+        }
+        ["users", "rename", name, new_name] => rename_user(db, &by_name(name)?.id, new_name),
+        ["users", "password", name] => {
+            let user = by_name(name)?;
+            reset_password(db, &user.id, &read_password(name)?)
+        }
+        ["users", "roll", name, roll] => set_roll(db, &by_name(name)?.id, parse_roll(roll)?),
+        ["users", "disable", name] => set_disabled(db, &by_name(name)?.id, true),
+        ["users", "enable", name] => set_disabled(db, &by_name(name)?.id, false),
+        ["users", "delete", name] => delete_user(db, &by_name(name)?.id),
+        ["users", "quota", name, quota] => set_quota(db, &by_name(name)?.id, parse_quota(quota)?),
+        ["users", "recalc"] => {
+            let users = db.get_all_users().map_err(|e| UserError::from(e).to_string())?;
+            for user in users {
+                let used = crate::fs::quota::recalculate_usage(db, &user.id).map_err(|e| UserError::from(e).to_string())?;
+                println!("{}  {:<24} {} bytes", user.id.0, user.name, used);
+            }
+            Ok(())
+        }
+        _ => return Err(USAGE.into()),
+    };
+    res.map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manage_users() {
+        let mut path = std::env::temp_dir();
+        path.push(format!("what-cloud-users-test-{}.sqlite", std::process::id()));
+        let db = SharedDatabase::new(&path);
+
+        let id = create_user(&db, "alice", "cGFzc3dvcmQ=", UserRoll::User).unwrap();
+        assert_eq!(id.0.len(), 8);
+        assert!(matches!(create_user(&db, "alice", "cGFzc3dvcmQ=", UserRoll::User), Err(UserError::NameTaken)));
+        assert!(matches!(create_user(&db, " bob", "cGFzc3dvcmQ=", UserRoll::User), Err(UserError::InvalidName)));
+        assert!(matches!(create_user(&db, "", "cGFzc3dvcmQ=", UserRoll::User), Err(UserError::InvalidName)));
+
+        rename_user(&db, &id, "alice2").unwrap();
+        set_roll(&db, &id, UserRoll::Admin).unwrap();
+        set_disabled(&db, &id, true).unwrap();
+        let user = db.get_user(GetUserQuery::ByID(&id)).unwrap();
+        assert_eq!(user.name, "alice2");
+        assert_eq!(user.roll, UserRoll::Admin);
+        assert!(user.disabled);
+        assert!(db.get_user(GetUserQuery::ByName("alice")).is_err());
+
+        set_quota(&db, &id, Some(10)).unwrap();
+        assert!(db.reserve_usage(&id, 6).unwrap());
+        assert!(!db.reserve_usage(&id, 6).unwrap());
+        db.change_usage(&id, -10).unwrap();
+        let user = db.get_user(GetUserQuery::ByID(&id)).unwrap();
+        assert_eq!((user.quota_bytes, user.used_bytes), (Some(10), Some(0)));
+        assert_eq!(parse_quota("2k"), Ok(Some(2048)));
+        assert_eq!(parse_quota("None"), Ok(None));
+        assert!(parse_quota("12x").is_err());
+
+        db.delete_user(&id).unwrap();
+        assert!(matches!(rename_user(&db, &id, "carol"), Err(UserError::NotFound)));
+
+        let _ = std::fs::remove_file(&path);
+    }
+}